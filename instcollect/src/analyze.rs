@@ -0,0 +1,301 @@
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::spans::{Event, Span, SpanKind, Spans, ThreadKey};
+
+/// Totals of an instrumented function or method
+#[derive(Debug, Default, PartialEq)]
+pub struct FunctionStats {
+    pub calls: u64,
+    /// Time between begin and end, summed over all calls
+    pub inclusive: u64,
+    /// Inclusive time without the time of nested spans
+    pub exclusive: u64,
+    /// Longest call
+    pub max: u64,
+}
+
+/// Probe cost per process, measured by the calibration at global init
+#[derive(Debug, Default)]
+pub struct Overheads {
+    /// Mean time of a probe in nanoseconds by machine, pid and run id of the process
+    pub probe_ns: HashMap<(String, u32, Option<String>), f64>,
+}
+
+impl Overheads {
+    /// Probe cost of the process of the event, 0 if it was not calibrated
+    pub fn of(&self, event: &Event) -> f64 {
+        self.probe_ns
+            .get(&(event.machine_id.clone(), event.pid, event.run_id.clone()))
+            .cloned()
+            .unwrap_or(0.0)
+    }
+}
+
+/// Loads the probe costs of the processes of a run
+pub fn load_overheads(
+    conn: &Connection,
+    table_name: &str,
+    run_name: &str,
+) -> Result<Overheads, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        format!(
+            "SELECT machine_id, pid, probes, total_ns, run_id FROM {}_calibrations \
+             WHERE run_name = ?1 AND probes > 0",
+            table_name
+        )
+        .as_str(),
+    )?;
+    let probe_ns = stmt
+        .query_map(&[run_name], |row| {
+            let probes = row.get::<_, i64>(2)? as f64;
+            let total_ns = row.get::<_, i64>(3)? as f64;
+            Ok(((row.get(0)?, row.get(1)?, row.get(4)?), total_ns / probes))
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(Overheads { probe_ns })
+}
+
+/// Inclusive and exclusive time of the span without the cost of its probes. The begin probe
+/// takes its time stamp first, so the span contains about one probe of its own and both
+/// probes of every nested span
+fn without_overhead(span: &Span, probe_ns: f64) -> (u64, u64) {
+    let inclusive = probe_ns * (1 + 2 * span.nested) as f64;
+    let exclusive = probe_ns * (1 + span.children) as f64;
+    (
+        span.duration().saturating_sub(inclusive as u64),
+        span.exclusive().saturating_sub(exclusive as u64),
+    )
+}
+
+/// Totals per absolute path and span kind, sorted by exclusive time.
+/// Recursive calls count towards the inclusive time once per call.
+/// The cost of the probes is subtracted for calibrated processes
+pub fn function_stats(
+    spans: &Spans,
+    overheads: &Overheads,
+) -> Vec<(String, SpanKind, FunctionStats)> {
+    let mut stats: HashMap<(&str, SpanKind), FunctionStats> = HashMap::new();
+    for span in &spans.spans {
+        let (inclusive, exclusive) = without_overhead(span, overheads.of(&span.begin));
+        let entry = stats
+            .entry((span.begin.absolute_path.as_str(), span.kind))
+            .or_default();
+        entry.calls += 1;
+        entry.inclusive += inclusive;
+        entry.exclusive += exclusive;
+        entry.max = entry.max.max(inclusive);
+    }
+    let mut stats = stats
+        .into_iter()
+        .map(|((path, kind), stats)| (String::from(path), kind, stats))
+        .collect::<Vec<_>>();
+    stats.sort_by(|a, b| b.2.exclusive.cmp(&a.2.exclusive).then(a.0.cmp(&b.0)));
+    stats
+}
+
+/// Writes the call statistics of a run, the probe overhead per thread
+/// and the events without counterpart
+pub fn report<W: Write>(spans: &Spans, overheads: &Overheads, mut writer: W) -> io::Result<()> {
+    writeln!(
+        writer,
+        "{:<50} {:>6} {:>10} {:>14} {:>14} {:>14}",
+        "function", "kind", "calls", "inclusive", "exclusive", "max"
+    )?;
+    for (path, kind, stats) in function_stats(spans, overheads) {
+        writeln!(
+            writer,
+            "{:<50} {:>6} {:>10} {:>14} {:>14} {:>14}",
+            path,
+            kind.as_str(),
+            stats.calls,
+            millis(stats.inclusive),
+            millis(stats.exclusive),
+            millis(stats.max)
+        )?;
+    }
+    if !overheads.probe_ns.is_empty() {
+        thread_overheads(spans, overheads, &mut writer)?;
+    }
+
+    if !spans.unmatched_begins.is_empty() {
+        writeln!(
+            writer,
+            "\n{} begin events without end, their thread panicked or ended early:",
+            spans.unmatched_begins.len()
+        )?;
+        for event in &spans.unmatched_begins {
+            writeln!(
+                writer,
+                "  {} of pid {} on {}: {} {} at {}:{}",
+                event.thread_label(),
+                event.pid,
+                event.machine_id,
+                event.description,
+                event.absolute_path,
+                event.source_file,
+                event.line
+            )?;
+        }
+    }
+    if !spans.unmatched_ends.is_empty() {
+        writeln!(
+            writer,
+            "\n{} end events without begin",
+            spans.unmatched_ends.len()
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes the number of probes and their estimated cost per thread
+fn thread_overheads<W: Write>(
+    spans: &Spans,
+    overheads: &Overheads,
+    writer: &mut W,
+) -> io::Result<()> {
+    // A span was measured by two probes, an unmatched event by one
+    let probes = spans
+        .spans
+        .iter()
+        .map(|span| (&span.begin, 2))
+        .chain(spans.unmatched_begins.iter().map(|event| (event, 1)))
+        .chain(spans.unmatched_ends.iter().map(|event| (event, 1)));
+    let mut threads: HashMap<ThreadKey, (&Event, u64)> = HashMap::new();
+    for (event, count) in probes {
+        threads
+            .entry((
+                &event.machine_id,
+                event.pid,
+                event.run_id.as_deref(),
+                event.thread_id,
+            ))
+            .or_insert((event, 0))
+            .1 += count;
+    }
+    let mut threads = threads.into_iter().collect::<Vec<_>>();
+    threads.sort_by(|a, b| a.0.cmp(&b.0));
+
+    writeln!(
+        writer,
+        "\n{:<50} {:>10} {:>14}",
+        "probe overhead", "probes", "overhead"
+    )?;
+    for (_, (event, probes)) in threads {
+        let probe_ns = overheads.of(event);
+        writeln!(
+            writer,
+            "{:<50} {:>10} {:>14}",
+            format!("{} of {}", event.thread_label(), event.process_label()),
+            probes,
+            if probe_ns > 0.0 {
+                millis((probe_ns * probes as f64) as u64)
+            } else {
+                String::from("unknown")
+            }
+        )?;
+    }
+    Ok(())
+}
+
+/// Formats nanoseconds as milliseconds
+pub fn millis(nanos: u64) -> String {
+    format!("{:.3} ms", nanos as f64 / 1_000_000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spans::pair;
+    use crate::spans::tests::event;
+
+    #[test]
+    fn sums_inclusive_and_exclusive_time() {
+        let spans = pair(vec![
+            event("main", "GLOBAL_BEGIN", 0),
+            event("send", "BEGIN", 10),
+            event("send", "END", 30),
+            event("send", "BEGIN", 40),
+            event("send", "END", 50),
+            event("main", "GLOBAL_END", 100),
+        ]);
+        let stats = function_stats(&spans, &Overheads::default());
+
+        assert_eq!(stats[0].0, "main");
+        assert_eq!(
+            stats[0].2,
+            FunctionStats {
+                calls: 1,
+                inclusive: 100,
+                exclusive: 70,
+                max: 100,
+            }
+        );
+        assert_eq!(stats[1].0, "send");
+        assert_eq!(
+            stats[1].2,
+            FunctionStats {
+                calls: 2,
+                inclusive: 30,
+                exclusive: 30,
+                max: 20,
+            }
+        );
+    }
+
+    #[test]
+    fn subtracts_probe_overhead() {
+        let spans = pair(vec![
+            event("main", "GLOBAL_BEGIN", 0),
+            event("send", "BEGIN", 10),
+            event("send", "END", 30),
+            event("send", "BEGIN", 40),
+            event("send", "END", 50),
+            event("main", "GLOBAL_END", 100),
+        ]);
+        let mut overheads = Overheads::default();
+        overheads.probe_ns.insert(
+            (String::from("127.0.0.1"), 1, Some(String::from("run"))),
+            2.0,
+        );
+        let stats = function_stats(&spans, &overheads);
+
+        // main holds one probe of its own and both probes of each send
+        assert_eq!(
+            stats[0].2,
+            FunctionStats {
+                calls: 1,
+                inclusive: 90,
+                exclusive: 64,
+                max: 90,
+            }
+        );
+        assert_eq!(
+            stats[1].2,
+            FunctionStats {
+                calls: 2,
+                inclusive: 26,
+                exclusive: 26,
+                max: 18,
+            }
+        );
+    }
+
+    #[test]
+    fn tells_runs_with_the_same_pid_apart() {
+        let mut other_run = event("main", "GLOBAL_BEGIN", 200);
+        other_run.run_id = Some(String::from("other"));
+        let spans = pair(vec![
+            event("main", "GLOBAL_BEGIN", 0),
+            event("main", "GLOBAL_END", 100),
+            other_run.clone(),
+        ]);
+        let mut output = Vec::new();
+        thread_overheads(&spans, &Overheads::default(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.contains("thread 1 of pid 1 on 127.0.0.1 (run run)"));
+        assert!(output.contains("thread 1 of pid 1 on 127.0.0.1 (run other)"));
+    }
+}
@@ -0,0 +1,277 @@
+use instdata::ChannelOp;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::analyze::millis;
+use crate::spans::{Event, Span};
+
+/// Channel of a run. Channel ids are only unique within their process
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ChannelKey {
+    pub machine_id: String,
+    pub pid: u32,
+    pub run_id: Option<String>,
+    pub id: u64,
+}
+
+impl ChannelKey {
+    /// Channel of the event, if it is a channel operation
+    fn of(event: &Event) -> Option<(ChannelKey, ChannelOp, u64)> {
+        event.channel.map(|channel| {
+            (
+                ChannelKey {
+                    machine_id: event.machine_id.clone(),
+                    pid: event.pid,
+                    run_id: event.run_id.clone(),
+                    id: channel.id,
+                },
+                channel.op,
+                channel.seq,
+            )
+        })
+    }
+}
+
+/// Message matched by the sequence numbers of its send and recv
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub channel: ChannelKey,
+    pub seq: u64,
+    /// Send, time stamped with the end of its call
+    pub sent: Event,
+    /// Recv, time stamped with the end of its call
+    pub received: Event,
+}
+
+impl Message {
+    /// Time between the end of the send and the end of the recv in nanoseconds
+    pub fn latency(&self) -> u64 {
+        self.received
+            .time_stamp
+            .saturating_sub(self.sent.time_stamp)
+    }
+}
+
+/// Messages of a run and the channel operations without counterpart
+#[derive(Debug, Default)]
+pub struct Messages {
+    pub messages: Vec<Message>,
+    /// Sends whose message was still queued at the end or received without probe
+    pub unreceived: Vec<Event>,
+    /// Receives whose send was lost or sent without probe
+    pub unsent: Vec<Event>,
+}
+
+/// Begin event of a channel operation, time stamped with the end of its call.
+/// Operations are numbered before the call, their message is sent or received at its end
+fn operation(span: &Span) -> Option<Event> {
+    span.begin.channel.map(|_| Event {
+        time_stamp: span.end_time,
+        ..span.begin.clone()
+    })
+}
+
+/// Matches the n-th send with the n-th recv of every channel. Operations whose call did not
+/// end are left out. Messages are ordered by channel and sequence number
+pub fn match_messages(spans: &[Span]) -> Messages {
+    let operations = spans.iter().filter_map(operation).collect::<Vec<_>>();
+    let mut sends = HashMap::new();
+    let mut recvs = Vec::new();
+    for event in &operations {
+        match ChannelKey::of(event) {
+            Some((channel, ChannelOp::Send, seq)) => {
+                sends.insert((channel, seq), event);
+            }
+            Some((channel, ChannelOp::Recv, seq)) => recvs.push((channel, seq, event)),
+            None => (),
+        }
+    }
+
+    let mut result = Messages::default();
+    for (channel, seq, received) in recvs {
+        match sends.remove(&(channel.clone(), seq)) {
+            Some(sent) => result.messages.push(Message {
+                channel,
+                seq,
+                sent: sent.clone(),
+                received: received.clone(),
+            }),
+            None => result.unsent.push(received.clone()),
+        }
+    }
+    result.unreceived = sends.values().map(|&sent| sent.clone()).collect();
+    result
+        .messages
+        .sort_by(|a, b| a.channel.cmp(&b.channel).then(a.seq.cmp(&b.seq)));
+    result.unreceived.sort_by_key(|sent| sent.time_stamp);
+    result.unsent.sort_by_key(|received| received.time_stamp);
+    result
+}
+
+/// Latency and queue depth of a channel
+#[derive(Debug, Default, PartialEq)]
+pub struct ChannelStats {
+    pub messages: u64,
+    pub mean_latency: u64,
+    pub max_latency: u64,
+    /// Most messages sent and not yet received at the same time
+    pub max_depth: u64,
+    /// Queued messages averaged over the time from the first send to the last operation
+    pub mean_depth: f64,
+    /// Messages still queued at the end of the run
+    pub unreceived: u64,
+}
+
+/// Statistics per channel, ordered by channel
+pub fn channel_stats(messages: &Messages) -> Vec<(ChannelKey, ChannelStats)> {
+    let unreceived = messages
+        .unreceived
+        .iter()
+        .filter_map(|sent| ChannelKey::of(sent).map(|(channel, _, _)| (channel, sent.time_stamp)))
+        .collect::<Vec<_>>();
+    // Changes of the queue depth per channel: +1 at the end of a send, -1 at the end of a recv
+    let mut changes: HashMap<&ChannelKey, Vec<(u64, i64)>> = HashMap::new();
+    let mut stats: HashMap<&ChannelKey, ChannelStats> = HashMap::new();
+    for message in &messages.messages {
+        let entry = stats.entry(&message.channel).or_default();
+        entry.messages += 1;
+        entry.mean_latency += message.latency();
+        entry.max_latency = entry.max_latency.max(message.latency());
+        let channel_changes = changes.entry(&message.channel).or_default();
+        channel_changes.push((message.sent.time_stamp, 1));
+        channel_changes.push((message.received.time_stamp, -1));
+    }
+    for (channel, time_stamp) in &unreceived {
+        stats.entry(channel).or_default().unreceived += 1;
+        changes.entry(channel).or_default().push((*time_stamp, 1));
+    }
+
+    let mut result = stats
+        .into_iter()
+        .map(|(channel, mut channel_stats)| {
+            channel_stats.mean_latency = channel_stats
+                .mean_latency
+                .checked_div(channel_stats.messages)
+                .unwrap_or(0);
+            let mut channel_changes = changes.remove(channel).unwrap_or_default();
+            // Sends first, the depth never drops below zero
+            channel_changes.sort_by_key(|&(time_stamp, change)| (time_stamp, -change));
+            let mut depth: i64 = 0;
+            let mut area: u128 = 0;
+            let mut last = channel_changes.first().map_or(0, |change| change.0);
+            for (time_stamp, change) in &channel_changes {
+                area += (depth.max(0) as u128) * u128::from(time_stamp - last);
+                depth += change;
+                last = *time_stamp;
+                channel_stats.max_depth = channel_stats.max_depth.max(depth.max(0) as u64);
+            }
+            let duration = last - channel_changes.first().map_or(0, |change| change.0);
+            if duration > 0 {
+                channel_stats.mean_depth = area as f64 / duration as f64;
+            }
+            (channel.clone(), channel_stats)
+        })
+        .collect::<Vec<_>>();
+    result.sort_by(|a, b| a.0.cmp(&b.0));
+    result
+}
+
+/// Writes latency and queue depth of every channel and the operations without counterpart
+pub fn report<W: Write>(messages: &Messages, mut writer: W) -> io::Result<()> {
+    let stats = channel_stats(messages);
+    if stats.is_empty() && messages.unsent.is_empty() {
+        return Ok(());
+    }
+    writeln!(
+        writer,
+        "\n{:<50} {:>10} {:>14} {:>14} {:>10} {:>10}",
+        "channel", "messages", "mean latency", "max latency", "max depth", "mean depth"
+    )?;
+    for (channel, stats) in stats {
+        writeln!(
+            writer,
+            "{:<50} {:>10} {:>14} {:>14} {:>10} {:>10.2}",
+            format!(
+                "{:#x} of pid {} on {}",
+                channel.id, channel.pid, channel.machine_id
+            ),
+            stats.messages,
+            millis(stats.mean_latency),
+            millis(stats.max_latency),
+            stats.max_depth,
+            stats.mean_depth
+        )?;
+    }
+    if !messages.unreceived.is_empty() {
+        writeln!(
+            writer,
+            "\n{} messages sent and not received, still queued or received without probe",
+            messages.unreceived.len()
+        )?;
+    }
+    if !messages.unsent.is_empty() {
+        writeln!(
+            writer,
+            "\n{} messages received without send, their send was lost or has no probe",
+            messages.unsent.len()
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spans::tests::event;
+    use crate::spans::{pair, Spans};
+    use instdata::Channel;
+
+    /// Call of the seq-th operation on channel 42 ending at the time stamp
+    fn message(thread_id: u64, op: ChannelOp, seq: u64, time_stamp: u64) -> Vec<Event> {
+        vec![
+            event(op.as_str(), "BEGIN", time_stamp - 5)
+                .on_thread(thread_id)
+                .with_channel(Channel { id: 42, op, seq }),
+            event(op.as_str(), "END", time_stamp).on_thread(thread_id),
+        ]
+    }
+
+    #[test]
+    fn matches_messages_and_measures_queue() {
+        let Spans { spans, .. } = pair(
+            [
+                message(1, ChannelOp::Send, 1, 10),
+                message(1, ChannelOp::Send, 2, 20),
+                message(1, ChannelOp::Send, 3, 30),
+                message(2, ChannelOp::Recv, 1, 40),
+                message(2, ChannelOp::Recv, 2, 50),
+                message(2, ChannelOp::Recv, 4, 60),
+            ]
+            .concat(),
+        );
+        let messages = match_messages(&spans);
+        let matched = messages
+            .messages
+            .iter()
+            .map(|message| (message.seq, message.latency()))
+            .collect::<Vec<_>>();
+        assert_eq!(matched, vec![(1, 30), (2, 30)]);
+        assert_eq!(messages.unreceived.len(), 1);
+        assert_eq!(messages.unsent.len(), 1);
+
+        let stats = channel_stats(&messages);
+        assert_eq!(stats.len(), 1);
+        assert_eq!(
+            stats[0].1,
+            ChannelStats {
+                messages: 2,
+                mean_latency: 30,
+                max_latency: 30,
+                max_depth: 3,
+                // Depth 1, 2, 3 and 2 for 10 ns each
+                mean_depth: 2.0,
+                unreceived: 1,
+            }
+        );
+    }
+}
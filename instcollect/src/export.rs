@@ -0,0 +1,157 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+
+use crate::channels::Messages;
+use crate::spans::{Event, Spans};
+
+/// Output format of the export subcommand
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    /// Chrome Trace Event JSON, opens in chrome://tracing and Perfetto
+    ChromeTrace,
+    /// OTF2 archive for Vampir and the Score-P tools, needs the `otf2` feature
+    Otf2,
+}
+
+impl ExportFormat {
+    /// Parses the name given to `--format`
+    pub fn parse(name: &str) -> Result<ExportFormat, &'static str> {
+        match name {
+            "chrome-trace" => Ok(ExportFormat::ChromeTrace),
+            "otf2" => Ok(ExportFormat::Otf2),
+            _ => Err("unknown export format"),
+        }
+    }
+
+    /// Output used if none is given: a file for Chrome traces,
+    /// the directory of the archive for OTF2
+    pub fn default_out(self, run_name: &str) -> String {
+        match self {
+            ExportFormat::ChromeTrace => format!("{}.json", run_name),
+            ExportFormat::Otf2 => String::from(run_name),
+        }
+    }
+}
+
+/// Assigns small numeric ids to processes and threads, Chrome expects integers.
+/// Processes are told apart by machine, pid and run id
+#[derive(Default)]
+struct Ids {
+    processes: HashMap<(String, u32, Option<String>), u32>,
+    threads: HashMap<(u32, u64), u32>,
+    metadata: Vec<Value>,
+}
+
+impl Ids {
+    /// Process and thread id of the event. Announces names of new ones
+    fn of(&mut self, event: &Event) -> (u32, u32) {
+        let metadata = &mut self.metadata;
+        let next_pid = self.processes.len() as u32 + 1;
+        let pid = *self
+            .processes
+            .entry((event.machine_id.clone(), event.pid, event.run_id.clone()))
+            .or_insert_with(|| {
+                metadata.push(json!({
+                    "name": "process_name",
+                    "ph": "M",
+                    "pid": next_pid,
+                    "args": { "name": event.process_label() },
+                }));
+                next_pid
+            });
+        let next_tid = self.threads.len() as u32 + 1;
+        let tid = *self
+            .threads
+            .entry((pid, event.thread_id))
+            .or_insert_with(|| {
+                metadata.push(json!({
+                    "name": "thread_name",
+                    "ph": "M",
+                    "pid": pid,
+                    "tid": next_tid,
+                    "args": { "name": event.thread_label() },
+                }));
+                next_tid
+            });
+        (pid, tid)
+    }
+}
+
+/// Writes spans as complete events of the Chrome Trace Event format.
+/// Unmatched begins become begin events without end, messages become flow arrows
+/// from the end of the send to the end of the recv
+pub fn chrome_trace<W: Write>(
+    spans: &Spans,
+    messages: &Messages,
+    writer: W,
+) -> Result<(), Box<dyn Error>> {
+    let origins = spans.origins();
+    let micros =
+        |event: &Event| (event.time_stamp - origins[event.machine_id.as_str()]) as f64 / 1000.0;
+
+    let mut ids = Ids::default();
+    let mut events = Vec::with_capacity(spans.spans.len() + spans.unmatched_begins.len());
+    for span in &spans.spans {
+        let (pid, tid) = ids.of(&span.begin);
+        events.push(json!({
+            "name": span.begin.absolute_path,
+            "cat": span.kind.as_str(),
+            "ph": "X",
+            "ts": micros(&span.begin),
+            "dur": span.duration() as f64 / 1000.0,
+            "pid": pid,
+            "tid": tid,
+            "args": {
+                "source_file": span.begin.source_file,
+                "line": span.begin.line,
+            },
+        }));
+    }
+    for event in &spans.unmatched_begins {
+        let (pid, tid) = ids.of(event);
+        events.push(json!({
+            "name": event.absolute_path,
+            "cat": "unmatched",
+            "ph": "B",
+            "ts": micros(event),
+            "pid": pid,
+            "tid": tid,
+            "args": {
+                "source_file": event.source_file,
+                "line": event.line,
+                "description": event.description,
+            },
+        }));
+    }
+    for (flow_id, message) in messages.messages.iter().enumerate() {
+        for (event, phase) in &[(&message.sent, "s"), (&message.received, "f")] {
+            let (pid, tid) = ids.of(event);
+            events.push(json!({
+                "name": "message",
+                "cat": "channel",
+                "ph": phase,
+                "id": flow_id,
+                "ts": micros(event),
+                "pid": pid,
+                "tid": tid,
+                "bp": "e",
+                "args": {
+                    "channel": format!("{:#x}", message.channel.id),
+                    "seq": message.seq,
+                },
+            }));
+        }
+    }
+    events.extend(ids.metadata);
+
+    serde_json::to_writer(
+        writer,
+        &json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ns",
+        }),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,245 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::analyze::millis;
+use crate::spans::{Span, SpanKind, Spans, ThreadKey};
+
+/// Fork/join tree of a run. Nodes are the runs of main and of spawned closures,
+/// a closure hangs below the closure or main its parent thread was running when it spawned it
+#[derive(Debug)]
+pub struct ForkTree<'a> {
+    pub nodes: Vec<&'a Span>,
+    pub children: Vec<Vec<usize>>,
+    /// Main and closures whose parent is unknown
+    pub roots: Vec<usize>,
+}
+
+impl<'a> ForkTree<'a> {
+    /// Number of levels below the node
+    pub fn depth(&self, node: usize) -> usize {
+        self.children[node]
+            .iter()
+            .map(|&child| self.depth(child) + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Rebuilds the fork/join tree from the thread and main spans
+pub fn fork_tree(spans: &Spans) -> ForkTree<'_> {
+    let mut nodes = spans
+        .spans
+        .iter()
+        .filter(|span| span.kind != SpanKind::Call)
+        .collect::<Vec<_>>();
+    nodes.sort_by_key(|span| (span.begin.time_stamp, span.begin_index));
+
+    let mut tree = ForkTree {
+        children: vec![Vec::new(); nodes.len()],
+        roots: Vec::new(),
+        nodes: Vec::new(),
+    };
+    let threads = ThreadSpans::index(&nodes);
+    for (index, span) in nodes.iter().enumerate() {
+        match threads.parent(&nodes, span) {
+            Some(parent) => tree.children[parent].push(index),
+            None => tree.roots.push(index),
+        }
+    }
+    tree.nodes = nodes;
+    tree
+}
+
+/// Nodes of every thread ordered by begin, each with the innermost node of the same thread
+/// enclosing it
+struct ThreadSpans<'a> {
+    threads: HashMap<ThreadKey<'a>, Vec<(usize, Option<usize>)>>,
+}
+
+impl<'a> ThreadSpans<'a> {
+    /// Indexes the nodes, which have to be ordered by begin
+    fn index(nodes: &[&'a Span]) -> ThreadSpans<'a> {
+        let mut threads: HashMap<ThreadKey, Vec<(usize, Option<usize>)>> = HashMap::new();
+        let mut open: HashMap<ThreadKey, Vec<usize>> = HashMap::new();
+        for (index, span) in nodes.iter().enumerate() {
+            let key = (
+                span.begin.machine_id.as_str(),
+                span.begin.pid,
+                span.begin.run_id.as_deref(),
+                span.begin.thread_id,
+            );
+            let thread = threads.entry(key).or_default();
+            let stack = open.entry(key).or_default();
+            // Spans of one thread nest, the ones ended before this one began are closed
+            while let Some(&top) = stack.last() {
+                if nodes[thread[top].0].end_index > span.begin_index {
+                    break;
+                }
+                stack.pop();
+            }
+            thread.push((index, stack.last().cloned()));
+            stack.push(thread.len() - 1);
+        }
+        ThreadSpans { threads }
+    }
+
+    /// Innermost span of the parent thread which encloses the start of the spawned closure.
+    /// It has to begin strictly before the closure, so the tree has no cycles
+    fn parent(&self, nodes: &[&Span], span: &Span) -> Option<usize> {
+        let spawn = span.begin.spawn.as_ref()?;
+        let started = span.begin.time_stamp;
+        let key = (
+            span.begin.machine_id.as_str(),
+            span.begin.pid,
+            span.begin.run_id.as_deref(),
+            spawn.parent_thread_id,
+        );
+        let thread = self.threads.get(&key)?;
+        let same_stream = spawn.parent_thread_id == span.begin.thread_id;
+        let began_before = |candidate: &Span| {
+            if same_stream {
+                candidate.begin_index < span.begin_index
+            } else {
+                candidate.begin.time_stamp < started
+            }
+        };
+        // Last span which began before, followed by the spans enclosing it
+        let mut position = thread
+            .binary_search_by(|&(index, _)| {
+                if began_before(nodes[index]) {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                }
+            })
+            .unwrap_err()
+            .checked_sub(1);
+        while let Some(current) = position {
+            let (index, enclosing) = thread[current];
+            if nodes[index].end_time >= started {
+                return Some(index);
+            }
+            position = enclosing;
+        }
+        None
+    }
+}
+
+/// Writes the fork/join tree, one line per run of main or a spawned closure
+pub fn report<W: Write>(tree: &ForkTree, mut writer: W) -> io::Result<()> {
+    fn write_node<W: Write>(
+        tree: &ForkTree,
+        node: usize,
+        level: usize,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        let span = tree.nodes[node];
+        writeln!(
+            writer,
+            "{:indent$}{} at {}:{}  {}  {} of pid {} on {}{}",
+            "",
+            span.begin.absolute_path,
+            span.begin.source_file,
+            span.begin.line,
+            millis(span.duration()),
+            span.begin.thread_label(),
+            span.begin.pid,
+            span.begin.machine_id,
+            if span.kind == SpanKind::Thread && span.begin.spawn.is_none() {
+                "  (parent unknown)"
+            } else {
+                ""
+            },
+            indent = level * 2
+        )?;
+        for &child in &tree.children[node] {
+            write_node(tree, child, level + 1, writer)?;
+        }
+        Ok(())
+    }
+
+    for &root in &tree.roots {
+        write_node(tree, root, 0, &mut writer)?;
+    }
+    let spawned = tree
+        .nodes
+        .iter()
+        .filter(|span| span.kind == SpanKind::Thread)
+        .count();
+    let unknown = tree
+        .roots
+        .iter()
+        .filter(|&&root| tree.nodes[root].kind == SpanKind::Thread)
+        .count();
+    let depth = tree
+        .roots
+        .iter()
+        .map(|&root| tree.depth(root))
+        .max()
+        .unwrap_or(0);
+    writeln!(
+        writer,
+        "\n{} spawned closures, {} levels deep, {} without known parent",
+        spawned, depth, unknown
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spans::tests::event;
+    use crate::spans::{pair, Event};
+    use instdata::Spawn;
+
+    /// Event of main or of a closure spawned at site 7
+    fn spawn(thread_id: u64, description: &str, time_stamp: u64, parent: Option<u64>) -> Event {
+        let absolute_path = if description.starts_with("GLOBAL") {
+            "main"
+        } else {
+            "std::thread::spawn"
+        };
+        let spawned = event(absolute_path, description, time_stamp).on_thread(thread_id);
+        match parent {
+            Some(parent) => spawned.with_spawn(Spawn {
+                parent_thread_id: parent,
+                site_id: 7,
+            }),
+            None => spawned,
+        }
+    }
+
+    #[test]
+    fn rebuilds_nested_spawns() {
+        let spans = pair(vec![
+            spawn(1, "GLOBAL_BEGIN", 0, None),
+            spawn(1, "GLOBAL_END", 100, None),
+            spawn(2, "LOCAL_BEGIN", 10, Some(1)),
+            // Closure run inline on the spawning thread
+            spawn(2, "LOCAL_BEGIN", 20, Some(2)),
+            spawn(2, "LOCAL_END", 30, None),
+            spawn(2, "LOCAL_END", 50, None),
+            spawn(3, "LOCAL_BEGIN", 40, Some(2)),
+            spawn(3, "LOCAL_END", 60, None),
+            spawn(4, "LOCAL_BEGIN", 70, None),
+            spawn(4, "LOCAL_END", 80, None),
+        ]);
+        let tree = fork_tree(&spans);
+
+        let children = |node: usize| {
+            tree.children[node]
+                .iter()
+                .map(|&child| tree.nodes[child].begin.time_stamp)
+                .collect::<Vec<_>>()
+        };
+        let roots = tree
+            .roots
+            .iter()
+            .map(|&root| tree.nodes[root].begin.time_stamp)
+            .collect::<Vec<_>>();
+        assert_eq!(roots, vec![0, 70]);
+        assert_eq!(children(0), vec![10]);
+        assert_eq!(children(1), vec![20, 40]);
+        assert_eq!(tree.depth(0), 2);
+    }
+}
@@ -1,65 +1,217 @@
-use instdata::{to_structs, DynData, StaticData};
+use export::ExportFormat;
+use instdata::{
+    from_batch, Calibration, DynData, Origin, ProcessInfo, Record, StaticData, ThreadInfo,
+};
+use losses::{Loss, LossKind, LossTracker};
 use r2d2::{self, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{OpenFlags, NO_PARAMS, types::ToSql};
+use rusqlite::{OpenFlags, NO_PARAMS, types::ToSql, Connection};
+use std::collections::HashMap;
+use std::env;
 use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::codec::{FramedRead, LengthDelimitedCodec};
+use tokio::net::tcp::TcpListener;
 use tokio::net::udp::UdpSocket;
 use tokio::prelude::future::lazy;
 use tokio::prelude::*;
+use tokio::timer::Interval;
 
-const SOCKET_BINDING: &str = "0.0.0.0:8080";
+/// Default port the collector listens on for UDP and TCP
+const COLLECTOR_PORT: u16 = 8080;
 const DB_FILE_NAME: &str = "instrumentation.db";
 const DB_TABLE_NAME: &str = "instrumentation";
+/// Maximal payload of a UDP datagram over IPv4
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+/// Default width of the ASCII timeline chart
+const TIMELINE_WIDTH: usize = 100;
+/// Time the collector waits for late batches of a stream which sent its summary
+const LATE_BATCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time after which a stream without summary and without new events is reported
+const IDLE_STREAM_TIMEOUT: Duration = Duration::from_secs(600);
+/// Interval in which the collector reports expired streams
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+mod analyze;
+mod channels;
+mod export;
+mod forks;
+mod losses;
+#[cfg_attr(not(feature = "otf2"), allow(dead_code))]
+mod otf2;
+mod spans;
+mod timeline;
+
+/// Mode the collector application runs in
+#[derive(Clone)]
+pub enum Mode {
+    /// Receives instrumentation data from the network
+    Collect,
+    /// Loads trace files written by the file transport
+    Import(Vec<String>),
+    /// Writes a stored run in another trace format
+    Export(Export),
+    /// Prints call statistics of a stored run
+    Analyze,
+    /// Prints the parallelism of a stored run
+    Timeline(Timeline),
+    /// Prints the fork/join tree of a stored run
+    Tree,
+}
+
+/// Options of the export subcommand
+#[derive(Clone, Default)]
+pub struct Export {
+    format: Option<ExportFormat>,
+    /// Output file, `<run_name>.<extension>` if not given
+    out: Option<String>,
+}
+
+/// Options of the timeline subcommand
+#[derive(Clone)]
+pub struct Timeline {
+    /// Columns of the ASCII chart
+    width: usize,
+    /// File for the HTML chart
+    html: Option<String>,
+}
 
 /// Configuration struct
 #[derive(Clone)]
 pub struct Config {
+    mode: Mode,
     db_name: String,
     table_name: String,
     run_name: String,
+    /// Port the collector listens on
+    port: u16,
+    /// Command line the collector was started with, stored with the run
+    command: String,
 }
 
 impl Config {
     /// Constructs new config struct
     pub fn new(args: &[String]) -> Result<Config, &'static str> {
-        if args.len() > 6 {
-            return Err("too many arguments");
-        } else if args.len() < 2 {
+        if args.len() < 2 {
             return Err("not enough arguments");
-        } else if [3, 5].contains(&args.len()) {
-            return Err("either 'database_name' or 'table_name' was not provided");
         }
-        let run_name = format!("run_{}", args[1].clone());
-        let db_name = if args.len() >= 4 && args[2] == "--db" {
-            args[3].clone()
-        } else if args.len() >= 6 && args[4] == "--db" {
-            args[5].clone()
-        } else {
-            // Default name
-            String::from(DB_FILE_NAME)
+        let (mut mode, rest) = match args[1].as_str() {
+            "import" => (Mode::Import(Vec::new()), &args[2..]),
+            "export" => (Mode::Export(Export::default()), &args[2..]),
+            "analyze" => (Mode::Analyze, &args[2..]),
+            "timeline" => (
+                Mode::Timeline(Timeline {
+                    width: TIMELINE_WIDTH,
+                    html: None,
+                }),
+                &args[2..],
+            ),
+            "tree" => (Mode::Tree, &args[2..]),
+            _ => (Mode::Collect, &args[1..]),
         };
-        let table_name = if args.len() >= 4 && args[2] == "--table" {
-            args[3].clone()
-        } else if args.len() >= 6 && args[4] == "--table" {
-            args[5].clone()
-        } else {
-            // Default name
-            String::from(DB_TABLE_NAME)
+        // Default names
+        let mut db_name = String::from(DB_FILE_NAME);
+        let mut table_name = String::from(DB_TABLE_NAME);
+        let mut run_name = None;
+        let mut port = COLLECTOR_PORT;
+
+        let mut options = rest.iter();
+        while let Some(arg) = options.next() {
+            match (arg.as_str(), &mut mode) {
+                ("--db", _) => {
+                    db_name = options
+                        .next()
+                        .ok_or("'database_name' was not provided")?
+                        .clone()
+                }
+                ("--table", _) => {
+                    table_name = options
+                        .next()
+                        .ok_or("'table_name' was not provided")?
+                        .clone()
+                }
+                ("--run", Mode::Export(_))
+                | ("--run", Mode::Analyze)
+                | ("--run", Mode::Timeline(_))
+                | ("--run", Mode::Tree) => {
+                    run_name = Some(options.next().ok_or("'run_name' was not provided")?)
+                }
+                ("--format", Mode::Export(export)) => {
+                    let format = options.next().ok_or("'format' was not provided")?;
+                    export.format = Some(ExportFormat::parse(format)?)
+                }
+                ("--out", Mode::Export(export)) => {
+                    export.out = Some(options.next().ok_or("'out' was not provided")?.clone())
+                }
+                (_, Mode::Export(_)) => return Err("unknown export option"),
+                ("--width", Mode::Timeline(timeline)) => {
+                    timeline.width = options
+                        .next()
+                        .ok_or("'width' was not provided")?
+                        .parse()
+                        .ok()
+                        .filter(|width| *width > 0)
+                        .ok_or("'width' has to be a positive number")?
+                }
+                ("--html", Mode::Timeline(timeline)) => {
+                    timeline.html = Some(options.next().ok_or("'html' was not provided")?.clone())
+                }
+                (_, Mode::Analyze) => return Err("unknown analyze option"),
+                (_, Mode::Timeline(_)) => return Err("unknown timeline option"),
+                (_, Mode::Tree) => return Err("unknown tree option"),
+                ("--port", Mode::Collect) => {
+                    port = options
+                        .next()
+                        .ok_or("'port' was not provided")?
+                        .parse()
+                        .map_err(|_| "'port' has to be a port number")?
+                }
+                (_, _) if run_name.is_none() => run_name = Some(arg),
+                (_, Mode::Import(files)) => files.push(arg.clone()),
+                (_, Mode::Collect) => return Err("too many arguments"),
+            }
+        }
+        let run_name = match run_name {
+            Some(run_name) => format!("run_{}", run_name),
+            None => return Err("'run_name' was not provided"),
         };
+        match &mode {
+            Mode::Import(files) if files.is_empty() => return Err("no trace file was provided"),
+            Mode::Export(export) if export.format.is_none() => {
+                return Err("'format' was not provided")
+            }
+            _ => (),
+        }
 
         Ok(Config {
+            mode,
             db_name,
             table_name,
             run_name,
+            port,
+            command: args.join(" "),
         })
     }
 }
 
-/// Runs the collector
+/// Runs the collector application in the configured mode
 pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    println!("\n---- Instrumentation Collector ----");
-    println!("-----------------------------------\n");
+    match config.mode.clone() {
+        Mode::Collect => collect(config),
+        Mode::Import(files) => import(config, &files),
+        Mode::Export(export) => export_run(config, &export),
+        Mode::Analyze => analyze_run(config),
+        Mode::Timeline(timeline) => timeline_run(config, &timeline),
+        Mode::Tree => tree_run(config),
+    }
+}
+
+/// Opens the database and creates the tables if needed
+fn open_db(config: &Config) -> Result<r2d2::Pool<SqliteConnectionManager>, Box<dyn Error>> {
     println!("Connecting to database '{}'...", config.db_name);
 
     let manager = SqliteConnectionManager::file(&config.db_name).with_flags(
@@ -69,53 +221,349 @@ pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
     );
     let pool = r2d2::Pool::new(manager)?;
 
-    create_if_not_exist(pool.get().unwrap(), &config)?;
+    create_if_not_exist(pool.get()?, config)?;
+    Ok(pool)
+}
+
+/// Imports trace files into the db
+fn import(config: Config, files: &[String]) -> Result<(), Box<dyn Error>> {
+    println!("\n---- Instrumentation Import ----");
+    println!("--------------------------------\n");
+    let pool = open_db(&config)?;
+
+    println!("Inserting in table '{}'...", config.table_name);
+    println!("Name of the instrumentation run: {}\n", config.run_name);
+
+    let conn = pool.get()?;
+    let losses = Mutex::new(LossTracker::new());
+    for file in files {
+        let content = fs::read(file)?;
+        conn.execute_batch("BEGIN")?;
+        let mut batches = 0;
+        let mut rest = content.as_slice();
+        // Every batch is prefixed by its length as u32 (big endian)
+        while rest.len() >= 4 {
+            let mut len_bytes = [0; 4];
+            len_bytes.copy_from_slice(&rest[..4]);
+            let len = u32::from_be_bytes(len_bytes) as usize;
+            if rest.len() < 4 + len {
+                break;
+            }
+            match from_batch(&rest[4..4 + len]) {
+                Ok(records) => insert_batch(&conn, &config, &losses, records),
+                Err(err) => {
+                    eprintln!("Unable to unpack batch {} of '{}': {}", batches, file, err);
+                    insert_loss(
+                        &conn,
+                        &config,
+                        &Loss::unattributed(
+                            LossKind::Undecodable,
+                            file.clone(),
+                            format!("batch {} of {} bytes: {}", batches, len, err),
+                        ),
+                    )
+                }
+            }
+            batches += 1;
+            rest = &rest[4 + len..];
+        }
+        if !rest.is_empty() {
+            eprintln!("'{}' ends with a truncated batch", file);
+            insert_loss(
+                &conn,
+                &config,
+                &Loss::unattributed(
+                    LossKind::Truncated,
+                    file.clone(),
+                    format!("{} trailing bytes", rest.len()),
+                ),
+            );
+        }
+        conn.execute_batch("COMMIT")?;
+        println!("Imported {} batches from '{}'", batches, file);
+    }
+    // Trace files are complete, so remaining streams ended without summary
+    for loss in losses.lock().unwrap().finish_all() {
+        insert_loss(&conn, &config, &loss);
+    }
+    end_run(&conn, &config);
+    Ok(())
+}
+
+/// Loads the events of a stored run and pairs them to spans
+fn load_spans(config: &Config) -> Result<spans::Spans, Box<dyn Error>> {
+    Ok(spans::pair(load_events(config)?))
+}
+
+/// Loads the events of a stored run ordered by stream and sequence number
+fn load_events(config: &Config) -> Result<Vec<spans::Event>, Box<dyn Error>> {
+    let conn = Connection::open_with_flags(&config.db_name, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    if !spans::run_exists(&conn, &config.table_name, &config.run_name)? {
+        return Err(format!(
+            "run '{}' not found in table '{}'",
+            config.run_name, config.table_name
+        )
+        .into());
+    }
+    Ok(spans::load_events(
+        &conn,
+        &config.table_name,
+        &config.run_name,
+    )?)
+}
+
+/// Prints call and channel statistics of a stored run
+fn analyze_run(config: Config) -> Result<(), Box<dyn Error>> {
+    let events = load_events(&config)?;
+    let conn = Connection::open_with_flags(&config.db_name, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let overheads = analyze::load_overheads(&conn, &config.table_name, &config.run_name)?;
+    let spans = spans::pair(events);
+    let messages = channels::match_messages(&spans.spans);
+    let stdout = io::stdout();
+    analyze::report(&spans, &overheads, stdout.lock())?;
+    channels::report(&messages, stdout.lock())?;
+    Ok(())
+}
+
+/// Prints the parallelism of a stored run and writes the HTML chart if requested
+fn timeline_run(config: Config, options: &Timeline) -> Result<(), Box<dyn Error>> {
+    let spans = load_spans(&config)?;
+    let timelines = timeline::timelines(&spans);
+    if timelines.is_empty() {
+        return Err("run has no thread or main spans".into());
+    }
+    let stdout = io::stdout();
+    timeline::ascii(&timelines, options.width, stdout.lock())?;
+    if let Some(html) = &options.html {
+        let writer = BufWriter::new(File::create(html)?);
+        timeline::html(&timelines, &config.run_name, writer)?;
+        println!("Wrote HTML chart to '{}'", html);
+    }
+    Ok(())
+}
+
+/// Prints the fork/join tree of a stored run
+fn tree_run(config: Config) -> Result<(), Box<dyn Error>> {
+    let spans = load_spans(&config)?;
+    let tree = forks::fork_tree(&spans);
+    if tree.nodes.is_empty() {
+        return Err("run has no thread or main spans".into());
+    }
+    let stdout = io::stdout();
+    forks::report(&tree, stdout.lock())?;
+    Ok(())
+}
+
+/// Exports a stored run to a file
+fn export_run(config: Config, export: &Export) -> Result<(), Box<dyn Error>> {
+    let events = load_events(&config)?;
+    let spans = spans::pair(events);
+    let messages = channels::match_messages(&spans.spans);
+    if !spans.unmatched_begins.is_empty() || !spans.unmatched_ends.is_empty() {
+        eprintln!(
+            "{} begin and {} end events without counterpart",
+            spans.unmatched_begins.len(),
+            spans.unmatched_ends.len()
+        );
+    }
+
+    let format = export.format.ok_or("'format' was not provided")?;
+    let out = export
+        .out
+        .clone()
+        .unwrap_or_else(|| format.default_out(&config.run_name));
+    match format {
+        ExportFormat::ChromeTrace => {
+            export::chrome_trace(&spans, &messages, BufWriter::new(File::create(&out)?))?
+        }
+        ExportFormat::Otf2 => export_otf2(&spans, &out)?,
+    }
+    println!(
+        "Exported {} spans of '{}' to '{}'",
+        spans.spans.len(),
+        config.run_name,
+        out
+    );
+    Ok(())
+}
+
+/// Writes the spans as OTF2 archive into the directory
+#[cfg(feature = "otf2")]
+fn export_otf2(spans: &spans::Spans, out: &str) -> Result<(), Box<dyn Error>> {
+    otf2::write(&otf2::Trace::from_spans(spans), out)
+}
+
+/// OTF2 archives are written by libotf2, which is only linked with the `otf2` feature
+#[cfg(not(feature = "otf2"))]
+fn export_otf2(_spans: &spans::Spans, _out: &str) -> Result<(), Box<dyn Error>> {
+    Err("instcollect was built without the 'otf2' feature".into())
+}
+
+/// Runs the collector
+fn collect(config: Config) -> Result<(), Box<dyn Error>> {
+    println!("\n---- Instrumentation Collector ----");
+    println!("-----------------------------------\n");
+    let pool = open_db(&config)?;
 
     println!("Inserting in table '{}'...", config.table_name);
     println!("Name of the instrumentation run: {}\n", config.run_name);
 
-    println!("Waiting on port '8080' for instrumentation data...");
+    println!(
+        "Waiting on port '{}' (UDP and TCP) for instrumentation data...",
+        config.port
+    );
     println!("Control + C to exit");
 
+    let socket_addr = SocketAddr::from(([0, 0, 0, 0], config.port));
+    let losses = Arc::new(Mutex::new(LossTracker::new()));
+
+    // Every datagram holds one batch
     let incoming = SocketReader {
-        socket: UdpSocket::bind(&SOCKET_BINDING.parse().unwrap())?,
+        socket: UdpSocket::bind(&socket_addr)?,
     };
-    let server = incoming.for_each(move |(message, sender_addr)| {
-        let pool_handle = pool.clone();
-        let config = config.clone();
-        tokio::spawn(lazy(move || {
-            if let Some((dyn_data, static_data)) = to_structs(&message) {
-                println!("{:?}  -  {:?} ,  {:?}", sender_addr, dyn_data, static_data);
-                // insert into sqlite db
-                if let Ok(conn) = pool_handle.get() {
-                    insert(conn, &config, dyn_data, static_data);
-                    println!("Inserted in db");
+    let udp_pool = pool.clone();
+    let udp_config = config.clone();
+    let udp_losses = losses.clone();
+    let expiry_pool = pool.clone();
+    let expiry_config = config.clone();
+    let expiry_losses = losses.clone();
+    let udp_server = incoming.for_each(move |(message, sender_addr, truncated)| {
+        spawn_insert(
+            &udp_pool,
+            &udp_config,
+            &udp_losses,
+            message,
+            sender_addr,
+            truncated,
+        );
+        Ok(())
+    });
+
+    // Every producer connection carries length-prefixed batches
+    let listener = TcpListener::bind(&socket_addr)?;
+    let tcp_server = listener
+        .incoming()
+        .map_err(|err| eprintln!("Unable to accept connection: {}", err))
+        .for_each(move |stream| {
+            let sender_addr = match stream.peer_addr() {
+                Ok(sender_addr) => sender_addr,
+                Err(err) => {
+                    eprintln!("Unable to retrieve peer address: {}", err);
+                    return Ok(());
                 }
+            };
+            println!("{:?}  -  connected", sender_addr);
+            let pool = pool.clone();
+            let config = config.clone();
+            let losses = losses.clone();
+            let connection = FramedRead::new(stream, LengthDelimitedCodec::new())
+                .map_err(move |err| eprintln!("{:?}  -  connection failed: {}", sender_addr, err))
+                .for_each(move |frame| {
+                    spawn_insert(&pool, &config, &losses, frame.to_vec(), sender_addr, false);
+                    Ok(())
+                });
+            tokio::spawn(connection);
+            Ok(())
+        });
+
+    // Streams whose last batches never arrive are reported after a timeout
+    let expiry = Interval::new(Instant::now() + EXPIRE_INTERVAL, EXPIRE_INTERVAL)
+        .map_err(|err| eprintln!("Unable to expire streams: {}", err))
+        .for_each(move |_| {
+            let expired = expiry_losses
+                .lock()
+                .unwrap()
+                .expire(LATE_BATCH_TIMEOUT, IDLE_STREAM_TIMEOUT);
+            if expired.is_empty() {
+                return Ok(());
+            }
+            match expiry_pool.get() {
+                Ok(conn) => {
+                    for loss in expired {
+                        insert_loss(&conn, &expiry_config, &loss);
+                    }
+                }
+                Err(err) => eprintln!("Unable to get db connection: {}", err),
             }
             Ok(())
-        }));
-        Ok(())
-    });
+        });
 
-    tokio::run(server);
+    tokio::run(lazy(move || {
+        tokio::spawn(udp_server);
+        tokio::spawn(tcp_server);
+        tokio::spawn(expiry);
+        Ok(())
+    }));
     Ok(())
 }
 
+/// Unpacks a batch and inserts its records into the db on a separate task.
+/// Truncated and undecodable batches are recorded as losses
+fn spawn_insert(
+    pool: &r2d2::Pool<SqliteConnectionManager>,
+    config: &Config,
+    losses: &Arc<Mutex<LossTracker>>,
+    message: Vec<u8>,
+    sender_addr: SocketAddr,
+    truncated: bool,
+) {
+    let pool_handle = pool.clone();
+    let config = config.clone();
+    let losses = losses.clone();
+    tokio::spawn(lazy(move || {
+        let conn = match pool_handle.get() {
+            Ok(conn) => conn,
+            Err(err) => {
+                eprintln!("Unable to get db connection: {}", err);
+                return Ok(());
+            }
+        };
+        if truncated {
+            eprintln!("{:?}  -  truncated datagram", sender_addr);
+            let detail = format!("datagram exceeds {} bytes", MAX_DATAGRAM_SIZE);
+            let loss = Loss::unattributed(LossKind::Truncated, sender_addr.to_string(), detail);
+            insert_loss(&conn, &config, &loss);
+            return Ok(());
+        }
+        match from_batch(&message) {
+            Ok(records) => {
+                println!("{:?}  -  batch of {} records", sender_addr, records.len());
+                // insert into sqlite db
+                insert_batch(&conn, &config, &losses, records);
+                end_run(&conn, &config);
+                println!("Inserted in db");
+            }
+            Err(err) => {
+                eprintln!("{:?}  -  unable to unpack batch: {}", sender_addr, err);
+                let detail = format!("batch of {} bytes: {}", message.len(), err);
+                let loss =
+                    Loss::unattributed(LossKind::Undecodable, sender_addr.to_string(), detail);
+                insert_loss(&conn, &config, &loss);
+            }
+        }
+        Ok(())
+    }));
+}
+
 /// SocketReader struct responsible for retrieve packages from the network interface
 struct SocketReader {
     socket: UdpSocket,
 }
 
 impl Stream for SocketReader {
-    type Item = (Vec<u8>, SocketAddr);
+    /// Datagram, its sender and whether it might have been truncated
+    type Item = (Vec<u8>, SocketAddr, bool);
     type Error = ();
 
     /// Polls the feature
-    fn poll(&mut self) -> Poll<Option<(Vec<u8>, SocketAddr)>, ()> {
-        let mut buffer: Vec<u8> = vec![0; 1024];
+    fn poll(&mut self) -> Poll<Option<(Vec<u8>, SocketAddr, bool)>, ()> {
+        // One byte more than the largest payload, only cut off datagrams fill it
+        let mut buffer: Vec<u8> = vec![0; MAX_DATAGRAM_SIZE + 1];
         match self.socket.poll_recv_from(&mut buffer) {
-            Ok(Async::Ready((_num_bytes, sender_addr))) => {
-                Ok(Async::Ready(Some((buffer, sender_addr))))
+            Ok(Async::Ready((num_bytes, sender_addr))) => {
+                let truncated = num_bytes == buffer.len();
+                buffer.truncate(num_bytes);
+                Ok(Async::Ready(Some((buffer, sender_addr, truncated))))
             }
             Ok(Async::NotReady) => Ok(Async::NotReady),
             Err(_) => Err(()),
@@ -127,39 +575,480 @@ impl Stream for SocketReader {
 fn create_if_not_exist(
     conn: PooledConnection<SqliteConnectionManager>,
     config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    create_table(&conn, config, "_runs", DB_RUNS_SCHEMA)?;
+    if create_table(&conn, config, "", DB_SCHEMA)? {
+        // Events of older collectors might belong to runs which were never registered
+        conn.execute(
+            format!(
+                "INSERT OR IGNORE INTO {table}_runs (run_name) SELECT DISTINCT run_name FROM {table}",
+                table = &config.table_name
+            )
+            .as_str(),
+            NO_PARAMS,
+        )?;
+    }
+    for column in &["thread_id", "absolute_path", "run_name"] {
+        conn.execute(
+            format!(
+                "CREATE INDEX IF NOT EXISTS {table}_{column} ON {table} ({column})",
+                table = &config.table_name,
+                column = column
+            )
+            .as_str(),
+            NO_PARAMS,
+        )?;
+    }
+    create_table(&conn, config, "_sites", DB_SITES_SCHEMA)?;
+    create_table(&conn, config, "_losses", DB_LOSSES_SCHEMA)?;
+    create_table(&conn, config, "_processes", DB_PROCESSES_SCHEMA)?;
+    create_table(&conn, config, "_threads", DB_THREADS_SCHEMA)?;
+    create_table(&conn, config, "_calibrations", DB_CALIBRATIONS_SCHEMA)?;
+    start_run(&conn, config)?;
+    Ok(())
+}
+
+/// Creates table `<table_name><suffix>` if it does not exist and migrates the table of an
+/// older collector. `{table}` in the schema is replaced by the name of the event table.
+/// Returns whether the table was migrated
+fn create_table(
+    conn: &Connection,
+    config: &Config,
+    suffix: &str,
+    schema: &str,
+) -> Result<bool, rusqlite::Error> {
+    let name = format!("{}{}", &config.table_name, suffix);
+    let schema = schema.replace("{table}", &config.table_name);
+    let migrated = migrate_table(conn, &name, &schema)?;
+    conn.execute(
+        format!("CREATE TABLE IF NOT EXISTS {} {}", name, schema).as_str(),
+        NO_PARAMS,
+    )?;
+    Ok(migrated)
+}
+
+/// Brings an existing table to the schema. Missing columns are added, a table whose primary
+/// key or column types changed is rebuilt and its rows are copied. Returns whether the
+/// table was changed
+fn migrate_table(conn: &Connection, name: &str, schema: &str) -> Result<bool, rusqlite::Error> {
+    let mut statement =
+        conn.prepare("SELECT name, upper(type), pk FROM pragma_table_info(?1) ORDER BY pk, cid")?;
+    let existing = statement
+        .query_map(&[name], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)? > 0,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    if existing.is_empty() {
+        return Ok(false);
+    }
+    let columns = schema_columns(schema);
+    let existing_type = |column: &str| {
+        existing
+            .iter()
+            .find(|(name, _, _)| name == column)
+            .map(|(_, kind, _)| kind.as_str())
+    };
+    let existing_key = existing
+        .iter()
+        .filter(|(_, _, key)| *key)
+        .map(|(name, _, _)| name.as_str())
+        .collect::<Vec<_>>();
+    let rebuild = existing_key != schema_key(schema)
+        || columns.iter().any(|(column, kind, _)| {
+            existing_type(column).map_or(false, |existing| existing != kind.to_uppercase())
+        });
+    if !rebuild {
+        let missing = columns
+            .iter()
+            .filter(|(column, _, _)| existing_type(column).is_none())
+            .collect::<Vec<_>>();
+        for (_, _, definition) in &missing {
+            conn.execute(
+                format!("ALTER TABLE {} ADD COLUMN {}", name, definition).as_str(),
+                NO_PARAMS,
+            )?;
+        }
+        return Ok(!missing.is_empty());
+    }
+    println!("Migrating table '{}' of an older collector...", name);
+    // Columns of the old table are copied, the others get their default
+    let (copied, values): (Vec<_>, Vec<_>) = columns
+        .iter()
+        .filter_map(|(column, kind, _)| {
+            let value = match existing_type(column)? {
+                existing if existing == kind.to_uppercase() => column.clone(),
+                _ => match DB_CONVERSIONS.iter().find(|(name, _)| name == column) {
+                    Some((_, conversion)) => conversion.to_string(),
+                    None => format!("CAST({} AS {})", column, kind),
+                },
+            };
+            Some((column.as_str(), value))
+        })
+        .unzip();
+    conn.execute_batch(
+        format!(
+            "BEGIN;
+             CREATE TABLE {name}_migrated {schema};
+             INSERT INTO {name}_migrated ({copied}) SELECT {values} FROM {name};
+             DROP TABLE {name};
+             ALTER TABLE {name}_migrated RENAME TO {name};
+             COMMIT;",
+            name = name,
+            schema = schema,
+            copied = copied.join(", "),
+            values = values.join(", ")
+        )
+        .as_str(),
+    )?;
+    Ok(true)
+}
+
+/// Columns of a schema: name, declared type and definition
+fn schema_columns(schema: &str) -> Vec<(String, String, String)> {
+    schema_lines(schema)
+        .filter(|line| !line.starts_with("PRIMARY KEY"))
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let column = words.next()?.to_string();
+            let kind = words.next()?.to_string();
+            Some((column, kind, line.to_string()))
+        })
+        .collect()
+}
+
+/// Primary key columns of a schema in key order
+fn schema_key(schema: &str) -> Vec<&str> {
+    schema_lines(schema)
+        .find_map(|line| {
+            if line.starts_with("PRIMARY KEY") {
+                let columns = line.trim_start_matches("PRIMARY KEY").trim();
+                let columns = columns.trim_start_matches('(').trim_end_matches(')');
+                Some(columns.split(',').map(str::trim).collect())
+            } else if line.contains("PRIMARY KEY") {
+                line.split_whitespace().next().map(|column| vec![column])
+            } else {
+                None
+            }
+        })
+        .unwrap_or_default()
+}
+
+/// Column and key definitions of a schema, one per line
+fn schema_lines(schema: &str) -> impl Iterator<Item = &str> {
+    let schema = schema.trim();
+    schema[1..schema.len() - 1]
+        .lines()
+        .map(|line| line.trim().trim_end_matches(','))
+        .filter(|line| !line.is_empty())
+}
+
+/// Registers the run. A run which already exists keeps its start time,
+/// so several collector sessions can add to the same run
+fn start_run(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    config: &Config,
 ) -> Result<(), rusqlite::Error> {
     conn.execute(
         format!(
-            "CREATE TABLE IF NOT EXISTS {} {}",
-            &config.table_name, DB_SCHEMA
+            "INSERT OR IGNORE INTO {}_runs {}",
+            &config.table_name, DB_RUNS_INSERT
         )
         .as_str(),
-        NO_PARAMS,
+        &[
+            &config.run_name as &dyn ToSql,
+            &now_ns(),
+            &collector_host(),
+            &config.command,
+        ],
     )?;
     Ok(())
 }
 
+/// Stores the config of the instrumented program with the run. The first process of the
+/// run which reports its config sets it
+fn set_run_config(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    config: &Config,
+    process_info: &ProcessInfo,
+) {
+    if let Err(err) = conn.execute(
+        format!(
+            "UPDATE {}_runs SET config = ?1 WHERE run_name = ?2 AND config IS NULL",
+            &config.table_name
+        )
+        .as_str(),
+        &[&process_info.config as &dyn ToSql, &config.run_name],
+    ) {
+        eprintln!("Unable to store config of run: {}", err);
+    }
+}
+
+/// Sets the end time of the run to now
+fn end_run(conn: &PooledConnection<SqliteConnectionManager>, config: &Config) {
+    if let Err(err) = conn.execute(
+        format!(
+            "UPDATE {}_runs SET end_time = ?1 WHERE run_name = ?2",
+            &config.table_name
+        )
+        .as_str(),
+        &[&now_ns() as &dyn ToSql, &config.run_name],
+    ) {
+        eprintln!("Unable to update run: {}", err);
+    }
+}
+
+/// Wall clock time in nanoseconds since the unix epoch
+fn now_ns() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as i64)
+        .unwrap_or_default()
+}
+
+/// Host name of the machine running the collector
+fn collector_host() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .map(|host| host.trim().to_string())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+/// Inserts the records of a batch into the db.
+/// Events are joined with the site definitions and thread records of their batch
+fn insert_batch(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    config: &Config,
+    losses: &Mutex<LossTracker>,
+    records: Vec<Record>,
+) {
+    let mut sites = HashMap::new();
+    let mut origins = HashMap::new();
+    for record in records {
+        match record {
+            Record::Site(static_data) => {
+                insert_site(conn, config, &static_data);
+                sites.insert(static_data.site_id, static_data);
+            }
+            Record::Event(dyn_data, site_id) => {
+                let origin = match origins.get(&(dyn_data.pid, dyn_data.thread_id)) {
+                    Some(origin) => origin,
+                    None => {
+                        eprintln!(
+                            "Unable to resolve thread {} of process {} of event",
+                            dyn_data.thread_id, dyn_data.pid
+                        );
+                        let detail = String::from("thread is not defined in the batch");
+                        insert_loss(conn, config, &Loss::unresolved(None, &dyn_data, detail));
+                        continue;
+                    }
+                };
+                // Arrived either way, an unresolved event is no gap but a loss of its own
+                let stream_losses = losses.lock().unwrap().record_event(origin, &dyn_data);
+                for loss in stream_losses {
+                    insert_loss(conn, config, &loss);
+                }
+                match sites.get(&site_id) {
+                    Some(static_data) => {
+                        insert(conn, config, origin, dyn_data, static_data.clone())
+                    }
+                    None => {
+                        eprintln!("Unable to resolve site {} of event", site_id);
+                        let detail = format!("site {} is not defined in the batch", site_id);
+                        let loss = Loss::unresolved(Some(origin), &dyn_data, detail);
+                        insert_loss(conn, config, &loss);
+                    }
+                }
+            }
+            Record::Thread(origin, thread_info) => {
+                insert_process(conn, config, &origin);
+                insert_thread(conn, config, &origin, thread_info);
+                origins.insert((origin.pid, origin.thread_id), origin);
+            }
+            Record::Calibration(origin, calibration) => {
+                insert_calibration(conn, config, &origin, &calibration)
+            }
+            Record::Process(origin, process_info) => {
+                insert_process(conn, config, &origin);
+                set_run_config(conn, config, &process_info)
+            }
+            Record::Summary(origin, summary) => {
+                let stream_losses = losses.lock().unwrap().finish_stream(&origin, &summary);
+                for loss in stream_losses {
+                    insert_loss(conn, config, &loss);
+                }
+            }
+        }
+    }
+}
+
+/// Inserts site definition into the db if not yet known
+fn insert_site(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    config: &Config,
+    static_data: &StaticData,
+) {
+    if let Err(err) = conn.execute(
+        format!(
+            "INSERT OR IGNORE INTO {}_sites {}",
+            config.table_name, DB_SITES_INSERT
+        )
+        .as_ref(),
+        &static_data
+            .clone()
+            .prepare_store()
+            .iter()
+            .map(std::convert::AsRef::as_ref)
+            .collect::<Vec<_>>(),
+    ) {
+        eprintln!("Unable to insert site: {}", err);
+    }
+}
+
+/// Inserts an instrumented process if not yet known
+fn insert_process(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    config: &Config,
+    origin: &Origin,
+) {
+    let params: Vec<Box<dyn ToSql>> = vec![
+        Box::new(config.run_name.clone()),
+        Box::new(origin.machine_id.clone()),
+        Box::new(origin.pid),
+        Box::new(origin.run_id.clone()),
+    ];
+    if let Err(err) = conn.execute(
+        format!(
+            "INSERT OR IGNORE INTO {}_processes {}",
+            config.table_name, DB_PROCESSES_INSERT
+        )
+        .as_ref(),
+        &params
+            .iter()
+            .map(std::convert::AsRef::as_ref)
+            .collect::<Vec<_>>(),
+    ) {
+        eprintln!("Unable to insert process: {}", err);
+    }
+}
+
+/// Inserts the OS thread id and name of an instrumented thread if not yet known
+fn insert_thread(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    config: &Config,
+    origin: &Origin,
+    thread_info: ThreadInfo,
+) {
+    let params: Vec<Box<dyn ToSql>> = vec![
+        Box::new(config.run_name.clone()),
+        Box::new(origin.machine_id.clone()),
+        Box::new(origin.pid),
+        Box::new(origin.run_id.clone()),
+        Box::new(origin.thread_id as i64),
+        Box::new(thread_info.os_tid.map(|os_tid| os_tid as i64)),
+        Box::new(thread_info.name),
+    ];
+    if let Err(err) = conn.execute(
+        format!(
+            "INSERT OR IGNORE INTO {}_threads {}",
+            config.table_name, DB_THREADS_INSERT
+        )
+        .as_ref(),
+        &params
+            .iter()
+            .map(std::convert::AsRef::as_ref)
+            .collect::<Vec<_>>(),
+    ) {
+        eprintln!("Unable to insert thread: {}", err);
+    }
+}
+
+/// Inserts the probe cost of a process, a later calibration of the process replaces it
+fn insert_calibration(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    config: &Config,
+    origin: &Origin,
+    calibration: &Calibration,
+) {
+    let params: Vec<Box<dyn ToSql>> = vec![
+        Box::new(config.run_name.clone()),
+        Box::new(origin.machine_id.clone()),
+        Box::new(origin.pid),
+        Box::new(origin.run_id.clone()),
+        Box::new(calibration.probes as i64),
+        Box::new(calibration.total_ns as i64),
+    ];
+    if let Err(err) = conn.execute(
+        format!(
+            "INSERT OR REPLACE INTO {}_calibrations {}",
+            config.table_name, DB_CALIBRATIONS_INSERT
+        )
+        .as_ref(),
+        &params
+            .iter()
+            .map(std::convert::AsRef::as_ref)
+            .collect::<Vec<_>>(),
+    ) {
+        eprintln!("Unable to insert calibration: {}", err);
+    }
+}
+
+/// Inserts loss into the db
+fn insert_loss(conn: &PooledConnection<SqliteConnectionManager>, config: &Config, loss: &Loss) {
+    let stream = loss.stream.as_ref();
+    let params: Vec<Box<dyn ToSql>> = vec![
+        Box::new(config.run_name.clone()),
+        Box::new(String::from(loss.kind.as_str())),
+        Box::new(stream.map(|stream| stream.machine_id.clone())),
+        Box::new(stream.map(|stream| stream.pid)),
+        Box::new(stream.map(|stream| stream.run_id.clone())),
+        Box::new(stream.map(|stream| stream.thread_id as i64)),
+        Box::new(loss.source.clone()),
+        Box::new(loss.missing.map(|missing| missing.0 as i64)),
+        Box::new(loss.missing.map(|missing| missing.1 as i64)),
+        Box::new(loss.events.map(|events| events as i64)),
+        Box::new(loss.detail.clone()),
+    ];
+    if let Err(err) = conn.execute(
+        format!(
+            "INSERT INTO {}_losses {}",
+            config.table_name, DB_LOSSES_INSERT
+        )
+        .as_ref(),
+        &params
+            .iter()
+            .map(std::convert::AsRef::as_ref)
+            .collect::<Vec<_>>(),
+    ) {
+        eprintln!("Unable to insert loss: {}", err);
+    }
+}
+
 /// Inserts incomming event data into the db
 fn insert(
-    conn: PooledConnection<SqliteConnectionManager>,
+    conn: &PooledConnection<SqliteConnectionManager>,
     config: &Config,
+    origin: &Origin,
     dyn_data: DynData,
     static_data: StaticData,
 ) {
     let dyn_d_store = dyn_data.prepare_store();
-    let params = dyn_d_store
+    let static_d_store = static_data.prepare_store();
+    let mut params = dyn_d_store
         .iter()
+        .chain(static_d_store.iter())
         .map(std::convert::AsRef::as_ref)
         .collect::<Vec<_>>();
+    params.push(&origin.machine_id);
+    params.push(&origin.run_id);
+    params.push(&config.run_name);
     if let Err(err) = conn.execute(
         format!("INSERT INTO {} {}", config.table_name, DB_INSERT).as_ref(),
-        &static_data
-            .prepare_store()
-            .iter()
-            .fold(params, |mut acc, x| {
-                acc.push(x.as_ref());
-                acc
-            }),
+        &params,
     ) {
         eprintln!("Unable to insert instrumentation data: {}", err);
     }
@@ -174,12 +1063,13 @@ impl PrepareStorage for StaticData {
     /// Prepares static data for database storage
     fn prepare_store(self) -> Vec<Box<dyn ToSql>> {
         vec![
+            Box::new(self.site_id as i64),
             Box::new(self.absolute_path),
             Box::new(self.description),
-            Box::new(self.ast_depth as f64),
+            Box::new(self.ast_depth as i64),
             Box::new(self.source_file),
-            Box::new(self.lines_begin as f64),
-            Box::new(self.lines_end as f64),
+            Box::new(self.lines_begin as i64),
+            Box::new(self.lines_end as i64),
         ]
     }
 }
@@ -188,28 +1078,48 @@ impl PrepareStorage for DynData {
     /// Prepares dynamic data for database storage
     fn prepare_store(self) -> Vec<Box<dyn ToSql>> {
         vec![
-            Box::new(self.system_time as f64),
-            Box::new(self.counter as f64),
+            Box::new(self.system_time as i64),
+            Box::new(self.counter as i64),
             Box::new(self.pid),
-            Box::new(self.thread_id),
-            Box::new(self.machine_id),
+            Box::new(self.thread_id as i64),
+            Box::new(self.channel.map(|channel| channel.id as i64)),
+            Box::new(self.channel.map(|channel| channel.op.as_str())),
+            Box::new(self.channel.map(|channel| channel.seq as i64)),
+            Box::new(self.spawn.map(|spawn| spawn.parent_thread_id as i64)),
+            Box::new(self.spawn.map(|spawn| spawn.site_id as i64)),
         ]
     }
 }
 
 /// SQL statement
 const DB_SCHEMA: &str = "(
-    time_stamp      REAL    PRIMARY KEY,
-    counter         REAL,
+    event_id        INTEGER PRIMARY KEY AUTOINCREMENT,
+    time_stamp      INTEGER NOT NULL,
+    counter         INTEGER,
     pid             INTEGER,
-    thread_id       TEXT,
+    thread_id       INTEGER,
     machine_id      TEXT,
+    run_id          TEXT,
+    channel_id      INTEGER,
+    channel_op      TEXT,
+    channel_seq     INTEGER,
+    parent_thread_id INTEGER,
+    spawn_site_id   INTEGER,
+    site_id         INTEGER,
     absolute_path   TEXT,
     description     TEXT,
-    ast_depth       REAL,
+    ast_depth       INTEGER,
     source_file     TEXT,
-    lines_begin     REAL,
-    lines_end       REAL)";
+    lines_begin     INTEGER,
+    lines_end       INTEGER,
+    run_name        TEXT    NOT NULL DEFAULT 'run_legacy' REFERENCES {table}_runs (run_name))";
+
+/// Conversions of columns whose type changed. Older collectors stored thread ids as
+/// `ThreadId(<n>)`
+const DB_CONVERSIONS: &[(&str, &str)] = &[(
+    "thread_id",
+    "CAST(replace(replace(thread_id, 'ThreadId(', ''), ')', '') AS INTEGER)",
+)];
 
 /// SQL statement
 const DB_INSERT: &str = "(
@@ -217,11 +1127,186 @@ const DB_INSERT: &str = "(
     counter,
     pid,
     thread_id,
+    channel_id,
+    channel_op,
+    channel_seq,
+    parent_thread_id,
+    spawn_site_id,
+    site_id,
+    absolute_path,
+    description,
+    ast_depth,
+    source_file,
+    lines_begin,
+    lines_end,
     machine_id,
+    run_id,
+    run_name
+    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)";
+
+/// SQL statement
+const DB_RUNS_SCHEMA: &str = "(
+    run_name        TEXT    PRIMARY KEY,
+    start_time      INTEGER,
+    end_time        INTEGER,
+    collector_host  TEXT,
+    config          TEXT,
+    command         TEXT)";
+
+/// SQL statement
+const DB_RUNS_INSERT: &str = "(
+    run_name,
+    start_time,
+    end_time,
+    collector_host,
+    command
+    ) VALUES (?1, ?2, ?2, ?3, ?4)";
+
+/// SQL statement
+const DB_SITES_SCHEMA: &str = "(
+    site_id         INTEGER PRIMARY KEY,
+    absolute_path   TEXT,
+    description     TEXT,
+    ast_depth       INTEGER,
+    source_file     TEXT,
+    lines_begin     INTEGER,
+    lines_end       INTEGER)";
+
+/// SQL statement
+const DB_SITES_INSERT: &str = "(
+    site_id,
     absolute_path,
     description,
     ast_depth,
     source_file,
     lines_begin,
     lines_end
-    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)";
\ No newline at end of file
+    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)";
+
+/// SQL statement
+const DB_LOSSES_SCHEMA: &str = "(
+    run_name        TEXT    REFERENCES {table}_runs (run_name),
+    kind            TEXT,
+    machine_id      TEXT,
+    pid             INTEGER,
+    run_id          TEXT,
+    thread_id       INTEGER,
+    source          TEXT,
+    first_missing   INTEGER,
+    last_missing    INTEGER,
+    events          INTEGER,
+    detail          TEXT)";
+
+/// SQL statement
+const DB_LOSSES_INSERT: &str = "(
+    run_name,
+    kind,
+    machine_id,
+    pid,
+    run_id,
+    thread_id,
+    source,
+    first_missing,
+    last_missing,
+    events,
+    detail
+    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)";
+
+/// SQL statement
+const DB_PROCESSES_SCHEMA: &str = "(
+    run_name        TEXT    REFERENCES {table}_runs (run_name),
+    machine_id      TEXT,
+    pid             INTEGER,
+    run_id          TEXT,
+    PRIMARY KEY (run_name, machine_id, pid, run_id))";
+
+/// SQL statement
+const DB_PROCESSES_INSERT: &str = "(
+    run_name,
+    machine_id,
+    pid,
+    run_id
+    ) VALUES (?1, ?2, ?3, ?4)";
+
+/// SQL statement
+const DB_THREADS_SCHEMA: &str = "(
+    run_name        TEXT    REFERENCES {table}_runs (run_name),
+    machine_id      TEXT,
+    pid             INTEGER,
+    run_id          TEXT,
+    thread_id       INTEGER,
+    os_tid          INTEGER,
+    name            TEXT,
+    PRIMARY KEY (run_name, machine_id, pid, run_id, thread_id))";
+
+/// SQL statement
+const DB_THREADS_INSERT: &str = "(
+    run_name,
+    machine_id,
+    pid,
+    run_id,
+    thread_id,
+    os_tid,
+    name
+    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)";
+
+/// SQL statement
+const DB_CALIBRATIONS_SCHEMA: &str = "(
+    run_name        TEXT    REFERENCES {table}_runs (run_name),
+    machine_id      TEXT,
+    pid             INTEGER,
+    run_id          TEXT,
+    probes          INTEGER,
+    total_ns        INTEGER,
+    PRIMARY KEY (run_name, machine_id, pid, run_id))";
+
+/// SQL statement
+const DB_CALIBRATIONS_INSERT: &str = "(
+    run_name,
+    machine_id,
+    pid,
+    run_id,
+    probes,
+    total_ns
+    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_tables_of_older_collectors() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE inst (
+                time_stamp REAL PRIMARY KEY, counter REAL, pid INTEGER, thread_id TEXT,
+                machine_id TEXT, absolute_path TEXT, description TEXT, ast_depth REAL,
+                source_file TEXT, lines_begin REAL, lines_end REAL);
+             INSERT INTO inst VALUES (5.0, 1.0, 7, 'ThreadId(3)', 'm', 'main', 'BEGIN', 1.0,
+                'src/main.rs', 2.0, 2.0);
+             CREATE TABLE inst_sites (site_id INTEGER PRIMARY KEY, absolute_path TEXT);",
+        )
+        .unwrap();
+        let schema = DB_SCHEMA.replace("{table}", "inst");
+        assert!(migrate_table(&conn, "inst", &schema).unwrap());
+        assert!(!migrate_table(&conn, "inst", &schema).unwrap());
+        let row: (i64, i64, i64, String) = conn
+            .query_row(
+                "SELECT event_id, time_stamp, thread_id, run_name FROM inst",
+                NO_PARAMS,
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(row, (1, 5, 3, String::from("run_legacy")));
+
+        assert!(migrate_table(&conn, "inst_sites", DB_SITES_SCHEMA).unwrap());
+        let columns: i64 = conn
+            .query_row(
+                "SELECT count(*) FROM pragma_table_info('inst_sites')",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(columns, 7);
+    }
+}
@@ -0,0 +1,414 @@
+use instdata::{DynData, Origin, Summary};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Identifies an event stream, every instrumented thread sends one
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct StreamKey {
+    pub machine_id: String,
+    pub pid: u32,
+    pub run_id: String,
+    pub thread_id: u64,
+}
+
+impl StreamKey {
+    /// Stream of the origin
+    fn of(origin: &Origin) -> StreamKey {
+        StreamKey {
+            machine_id: origin.machine_id.clone(),
+            pid: origin.pid,
+            run_id: origin.run_id.clone(),
+            thread_id: origin.thread_id,
+        }
+    }
+}
+
+/// Kind of a recorded loss
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LossKind {
+    /// Totals of a finished stream, also written if nothing was lost
+    Stream,
+    /// Range of sequence numbers which never arrived
+    Gap,
+    /// Events the producer did not send when flushing, batches kept for a TCP reconnect may
+    /// still arrive
+    Unsent,
+    /// Events the probes dropped because the buffer of their thread was full
+    Dropped,
+    /// Datagram which filled the whole receive buffer
+    Truncated,
+    /// Batch which could not be unpacked
+    Undecodable,
+    /// Stream which ended without summary, its totals are unknown
+    NoSummary,
+    /// Event whose site is not defined in its batch, it is not stored
+    Unresolved,
+}
+
+impl LossKind {
+    /// Name stored in the losses table
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LossKind::Stream => "stream",
+            LossKind::Gap => "gap",
+            LossKind::Unsent => "unsent",
+            LossKind::Dropped => "dropped",
+            LossKind::Truncated => "truncated",
+            LossKind::Undecodable => "undecodable",
+            LossKind::NoSummary => "no_summary",
+            LossKind::Unresolved => "unresolved",
+        }
+    }
+}
+
+/// Row of the losses table
+#[derive(Debug)]
+pub struct Loss {
+    pub kind: LossKind,
+    /// Stream of the lost events, None if the batch could not be attributed
+    pub stream: Option<StreamKey>,
+    /// Sender address or trace file the loss was noticed on
+    pub source: Option<String>,
+    /// First and last missing sequence number of a gap
+    pub missing: Option<(u64, u64)>,
+    /// Number of lost events, None if unknown
+    pub events: Option<u64>,
+    pub detail: String,
+}
+
+impl Loss {
+    /// Loss which can not be attributed to a stream
+    pub fn unattributed(kind: LossKind, source: String, detail: String) -> Loss {
+        Loss {
+            kind,
+            stream: None,
+            source: Some(source),
+            missing: None,
+            events: None,
+            detail,
+        }
+    }
+
+    /// Event whose site or stream could not be resolved, the origin is None if the thread
+    /// of the event is not defined in its batch
+    pub fn unresolved(origin: Option<&Origin>, dyn_data: &DynData, detail: String) -> Loss {
+        let counter = dyn_data.counter as u64;
+        Loss {
+            kind: LossKind::Unresolved,
+            stream: origin.map(StreamKey::of),
+            source: None,
+            missing: Some((counter, counter)),
+            events: Some(1),
+            detail,
+        }
+    }
+}
+
+/// Received sequence numbers of a stream as sorted, disjoint and inclusive ranges.
+/// Kept after the losses of the stream were reported, so late events do not start a new stream
+struct StreamState {
+    received: Vec<(u64, u64)>,
+    /// Totals the stream sent when it ended
+    summary: Option<Summary>,
+    /// Whether the losses of the stream were recorded
+    reported: bool,
+    /// Arrival of the last event or summary of the stream
+    last_update: Instant,
+}
+
+impl StreamState {
+    /// Constructs state of a stream without received events
+    fn new() -> StreamState {
+        StreamState {
+            received: Vec::new(),
+            summary: None,
+            reported: false,
+            last_update: Instant::now(),
+        }
+    }
+
+    /// Marks the sequence number as received
+    fn insert(&mut self, counter: u64) {
+        // Events mostly arrive in order
+        if let Some(last) = self.received.last_mut() {
+            if last.1 + 1 == counter {
+                last.1 = counter;
+                return;
+            }
+        }
+        let index = match self
+            .received
+            .binary_search_by_key(&counter, |range| range.0)
+        {
+            Ok(_) => return,
+            Err(index) => index,
+        };
+        if index > 0 && self.received[index - 1].1 >= counter {
+            return;
+        }
+        let joins_prev = index > 0 && self.received[index - 1].1 + 1 == counter;
+        let joins_next = index < self.received.len() && self.received[index].0 == counter + 1;
+        match (joins_prev, joins_next) {
+            (true, true) => {
+                self.received[index - 1].1 = self.received[index].1;
+                self.received.remove(index);
+            }
+            (true, false) => self.received[index - 1].1 = counter,
+            (false, true) => self.received[index].0 = counter,
+            (false, false) => self.received.insert(index, (counter, counter)),
+        }
+    }
+
+    /// Number of received events
+    fn count(&self) -> u64 {
+        self.received
+            .iter()
+            .map(|(first, last)| last - first + 1)
+            .sum()
+    }
+
+    /// Highest received sequence number
+    fn last(&self) -> u64 {
+        self.received.last().map_or(0, |range| range.1)
+    }
+
+    /// Whether all events the summary counts as sent were received
+    fn is_complete(&self) -> bool {
+        match &self.summary {
+            Some(summary) => self.count() >= summary.events.saturating_sub(summary.failed_events),
+            None => false,
+        }
+    }
+
+    /// Ranges of sequence numbers up to `last` which were not received
+    fn missing(&self, last: u64) -> Vec<(u64, u64)> {
+        let mut missing = Vec::new();
+        let mut expected = 1;
+        for &(first, end) in &self.received {
+            if first > last {
+                break;
+            }
+            if first > expected {
+                missing.push((expected, first - 1));
+            }
+            expected = end + 1;
+        }
+        if expected <= last {
+            missing.push((expected, last));
+        }
+        missing
+    }
+}
+
+/// Tracks the sequence numbers of all streams of a run.
+/// Batches may arrive out of order, also after the summary of their stream. So the losses of a
+/// stream are only reported once all its events arrived, or when it expires or the import ends
+#[derive(Default)]
+pub struct LossTracker {
+    streams: HashMap<StreamKey, StreamState>,
+}
+
+impl LossTracker {
+    /// Constructs new empty LossTracker
+    pub fn new() -> LossTracker {
+        LossTracker::default()
+    }
+
+    /// Marks the event as received. Returns the losses of the stream if the event completes it
+    pub fn record_event(&mut self, origin: &Origin, dyn_data: &DynData) -> Vec<Loss> {
+        let stream = StreamKey::of(origin);
+        let state = self
+            .streams
+            .entry(stream.clone())
+            .or_insert_with(StreamState::new);
+        state.insert(dyn_data.counter as u64);
+        state.last_update = Instant::now();
+        if !state.reported && state.is_complete() {
+            report(&stream, state)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Stores the summary of the stream. Returns the losses of the stream if all its events
+    /// arrived, unsent events show up as gaps as well
+    pub fn finish_stream(&mut self, origin: &Origin, summary: &Summary) -> Vec<Loss> {
+        let stream = StreamKey::of(origin);
+        let state = self
+            .streams
+            .entry(stream.clone())
+            .or_insert_with(StreamState::new);
+        // A stream which expired without summary is reported again with its totals
+        state.summary = Some(summary.clone());
+        state.reported = false;
+        state.last_update = Instant::now();
+        if state.is_complete() {
+            report(&stream, state)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Reports the streams which did not change for the timeout, the timeout of streams
+    /// which sent their summary applies to late batches
+    pub fn expire(&mut self, finished_timeout: Duration, open_timeout: Duration) -> Vec<Loss> {
+        let mut losses = Vec::new();
+        for (stream, state) in &mut self.streams {
+            let timeout = match state.summary {
+                Some(_) => finished_timeout,
+                None => open_timeout,
+            };
+            if !state.reported && state.last_update.elapsed() >= timeout {
+                losses.extend(report(stream, state));
+            }
+        }
+        losses
+    }
+
+    /// Reports all streams which were not reported yet. Only gaps below the highest
+    /// received sequence number can be detected for streams without summary
+    pub fn finish_all(&mut self) -> Vec<Loss> {
+        let mut losses = Vec::new();
+        for (stream, state) in &mut self.streams {
+            if !state.reported {
+                losses.extend(report(stream, state));
+            }
+        }
+        losses
+    }
+}
+
+/// Loss rows of the stream, compares its received events with its summary if there is one
+fn report(stream: &StreamKey, state: &mut StreamState) -> Vec<Loss> {
+    state.reported = true;
+    let received = state.count();
+    let summary = match &state.summary {
+        Some(summary) => summary,
+        None => {
+            let mut losses = vec![Loss {
+                kind: LossKind::NoSummary,
+                stream: Some(stream.clone()),
+                source: None,
+                missing: None,
+                events: None,
+                detail: format!("received {} events", received),
+            }];
+            losses.extend(gaps(stream, state, state.last()));
+            return losses;
+        }
+    };
+    let mut losses = vec![Loss {
+        kind: LossKind::Stream,
+        stream: Some(stream.clone()),
+        source: None,
+        missing: None,
+        events: Some(summary.events.saturating_sub(received)),
+        detail: format!(
+            "received {} of {} events in {} batches",
+            received, summary.events, summary.batches
+        ),
+    }];
+    if summary.failed_batches > 0 {
+        losses.push(Loss {
+            kind: LossKind::Unsent,
+            stream: Some(stream.clone()),
+            source: None,
+            missing: None,
+            events: Some(summary.failed_events),
+            detail: format!(
+                "{} batches were not sent when flushed",
+                summary.failed_batches
+            ),
+        });
+    }
+    if summary.dropped_events > 0 {
+        losses.push(Loss {
+            kind: LossKind::Dropped,
+            stream: Some(stream.clone()),
+            source: None,
+            missing: None,
+            events: Some(summary.dropped_events),
+            detail: String::from("dropped by the probes, the buffer of the thread was full"),
+        });
+    }
+    losses.extend(gaps(stream, state, summary.events));
+    losses
+}
+
+/// Gap rows of the stream up to the sequence number `last`
+fn gaps(stream: &StreamKey, state: &StreamState, last: u64) -> Vec<Loss> {
+    state
+        .missing(last)
+        .into_iter()
+        .map(|(first, end)| Loss {
+            kind: LossKind::Gap,
+            stream: Some(stream.clone()),
+            source: None,
+            missing: Some((first, end)),
+            events: Some(end - first + 1),
+            detail: String::new(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin() -> Origin {
+        Origin::new("127.0.0.1", "run", 1)
+    }
+
+    fn event(counter: u128) -> DynData {
+        let mut dyn_data = DynData::new(1);
+        dyn_data.counter = counter;
+        dyn_data
+    }
+
+    fn summary(events: u64) -> Summary {
+        Summary {
+            events,
+            batches: 3,
+            failed_batches: 0,
+            failed_events: 0,
+            dropped_events: 0,
+        }
+    }
+
+    #[test]
+    fn finds_gaps_in_unordered_events() {
+        let mut tracker = LossTracker::new();
+        for counter in &[1, 2, 6, 5, 9, 3, 9] {
+            assert!(tracker.record_event(&origin(), &event(*counter)).is_empty());
+        }
+        // Missing events may still arrive
+        assert!(tracker.finish_stream(&origin(), &summary(10)).is_empty());
+        let losses = tracker.finish_all();
+
+        assert_eq!(losses[0].kind, LossKind::Stream);
+        assert_eq!(losses[0].events, Some(4));
+        let gaps = losses
+            .iter()
+            .filter(|loss| loss.kind == LossKind::Gap)
+            .map(|loss| loss.missing.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(gaps, vec![(4, 4), (7, 8), (10, 10)]);
+        assert!(tracker.finish_all().is_empty());
+    }
+
+    #[test]
+    fn applies_events_arriving_after_the_summary() {
+        let mut tracker = LossTracker::new();
+        tracker.record_event(&origin(), &event(1));
+        assert!(tracker.finish_stream(&origin(), &summary(3)).is_empty());
+        assert!(tracker.record_event(&origin(), &event(3)).is_empty());
+
+        let losses = tracker.record_event(&origin(), &event(2));
+        assert_eq!(losses.len(), 1);
+        assert_eq!(losses[0].kind, LossKind::Stream);
+        assert_eq!(losses[0].events, Some(0));
+        // A duplicate of a reported stream does not open it again
+        assert!(tracker.record_event(&origin(), &event(2)).is_empty());
+        assert!(tracker.finish_all().is_empty());
+    }
+}
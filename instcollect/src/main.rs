@@ -8,7 +8,27 @@ fn main() {
     // Display usage info
     if args.len() >= 2 && args[1] == "--help" {
         println!(
-            "Usage:\n\n instcollect <run_name> [--db <database_name>] [--table <table_name>]\n"
+            "Usage:\n\n instcollect <run_name> [--port <port>] [--db <database_name>] [--table <table_name>]\n"
+        );
+        println!("To import trace files written by the file transport:");
+        println!(
+            " instcollect import <run_name> <trace_file>... [--db <database_name>] [--table <table_name>]\n"
+        );
+        println!("To export a stored run for chrome://tracing or Perfetto:");
+        println!(
+            " instcollect export --format chrome-trace --run <run_name> [--out <file>] [--db <database_name>] [--table <table_name>]\n"
+        );
+        println!("To print call statistics of a stored run:");
+        println!(
+            " instcollect analyze --run <run_name> [--db <database_name>] [--table <table_name>]\n"
+        );
+        println!("To print the parallelism of a stored run:");
+        println!(
+            " instcollect timeline --run <run_name> [--width <columns>] [--html <file>] [--db <database_name>] [--table <table_name>]\n"
+        );
+        println!("To print the fork/join tree of a stored run:");
+        println!(
+            " instcollect tree --run <run_name> [--db <database_name>] [--table <table_name>]\n"
         );
         println!("Or to display this usage info:\n instcollect --help");
 
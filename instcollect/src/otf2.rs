@@ -0,0 +1,521 @@
+use std::collections::HashMap;
+
+use crate::spans::{Event, Spans};
+
+/// Enter or leave record of a location with time stamp and region reference
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Record {
+    Enter(u64, u32),
+    Leave(u64, u32),
+}
+
+/// Region definition, one per absolute path
+#[derive(Debug)]
+pub struct Region {
+    pub name: u32,
+    pub source_file: u32,
+    pub line: u32,
+}
+
+/// Location definition, one per thread of a process
+#[derive(Debug)]
+pub struct Location {
+    pub name: u32,
+    pub group: u32,
+    pub records: Vec<Record>,
+}
+
+/// OTF2 definitions and records of a run. Machines become location groups, threads of a
+/// process become locations, absolute paths become regions and spans become Enter/Leave
+/// records. All references are indices into the vectors
+#[derive(Debug, Default)]
+pub struct Trace {
+    pub strings: Vec<String>,
+    pub regions: Vec<Region>,
+    /// Names of the location groups
+    pub groups: Vec<u32>,
+    pub locations: Vec<Location>,
+    /// Latest time stamp of all records
+    pub trace_length: u64,
+    string_refs: HashMap<String, u32>,
+    region_refs: HashMap<String, u32>,
+    group_refs: HashMap<String, u32>,
+    location_refs: HashMap<(String, u32, u64), usize>,
+}
+
+impl Trace {
+    /// Maps the spans of a run. Unmatched events are left out, OTF2 readers expect
+    /// every Enter to be followed by its Leave
+    pub fn from_spans(spans: &Spans) -> Trace {
+        let origins = spans.origins();
+        let mut trace = Trace::default();
+        let mut ordered: Vec<Vec<(usize, Record)>> = Vec::new();
+        for span in &spans.spans {
+            let origin = origins[span.begin.machine_id.as_str()];
+            let region = trace.region(&span.begin);
+            let location = trace.location(&span.begin);
+            if location == ordered.len() {
+                ordered.push(Vec::new());
+            }
+            let begin = span.begin.time_stamp - origin;
+            let end = span.end_time - origin;
+            ordered[location].push((span.begin_index, Record::Enter(begin, region)));
+            ordered[location].push((span.end_index, Record::Leave(end, region)));
+            trace.trace_length = trace.trace_length.max(end);
+        }
+        // Records keep the order of their events, which keeps them nested
+        for (location, mut records) in trace.locations.iter_mut().zip(ordered) {
+            records.sort_by_key(|(index, _)| *index);
+            location.records = records.into_iter().map(|(_, record)| record).collect();
+        }
+        trace
+    }
+
+    /// Reference of the string, defines it if needed
+    fn string(&mut self, string: &str) -> u32 {
+        let strings = &mut self.strings;
+        *self
+            .string_refs
+            .entry(String::from(string))
+            .or_insert_with(|| {
+                strings.push(String::from(string));
+                strings.len() as u32 - 1
+            })
+    }
+
+    /// Reference of the region of the event, defines it if needed
+    fn region(&mut self, event: &Event) -> u32 {
+        if let Some(region) = self.region_refs.get(&event.absolute_path) {
+            return *region;
+        }
+        let region = Region {
+            name: self.string(&event.absolute_path),
+            source_file: self.string(&event.source_file),
+            line: event.line as u32,
+        };
+        self.regions.push(region);
+        let region = self.regions.len() as u32 - 1;
+        self.region_refs.insert(event.absolute_path.clone(), region);
+        region
+    }
+
+    /// Index of the location of the event, defines it and its group if needed
+    fn location(&mut self, event: &Event) -> usize {
+        let key = (event.machine_id.clone(), event.pid, event.thread_id);
+        if let Some(location) = self.location_refs.get(&key) {
+            return *location;
+        }
+        let group = match self.group_refs.get(&event.machine_id) {
+            Some(group) => *group,
+            None => {
+                let name = self.string(&event.machine_id);
+                self.groups.push(name);
+                let group = self.groups.len() as u32 - 1;
+                self.group_refs.insert(event.machine_id.clone(), group);
+                group
+            }
+        };
+        let location = Location {
+            name: self.string(&format!("{} {}", event.pid, event.thread_label())),
+            group,
+            records: Vec::new(),
+        };
+        self.locations.push(location);
+        self.location_refs.insert(key, self.locations.len() - 1);
+        self.locations.len() - 1
+    }
+}
+
+/// Writes the trace as OTF2 archive `<archive_path>/traces.otf2` with libotf2 (version 2)
+#[cfg(feature = "otf2")]
+pub fn write(trace: &Trace, archive_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    use ffi::*;
+    use std::ffi::CString;
+    use std::ptr;
+
+    /// Turns an OTF2 error code into an error
+    fn check(code: OTF2_ErrorCode, call: &str) -> Result<(), String> {
+        if code == OTF2_SUCCESS {
+            Ok(())
+        } else {
+            Err(format!("{} failed with OTF2 error {}", call, code))
+        }
+    }
+
+    let path = CString::new(archive_path)?;
+    let name = CString::new("traces")?;
+    let strings = trace
+        .strings
+        .iter()
+        .map(|string| CString::new(string.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let root_name = CString::new("tRust")?;
+    let root_class = CString::new("run")?;
+
+    unsafe {
+        let archive = OTF2_Archive_Open(
+            path.as_ptr(),
+            name.as_ptr(),
+            OTF2_FILEMODE_WRITE,
+            OTF2_CHUNK_SIZE_EVENTS,
+            OTF2_CHUNK_SIZE_DEFINITIONS,
+            OTF2_SUBSTRATE_POSIX,
+            OTF2_COMPRESSION_NONE,
+        );
+        if archive.is_null() {
+            return Err(format!("Unable to create OTF2 archive in '{}'", archive_path).into());
+        }
+        let result = (|| -> Result<(), String> {
+            check(
+                OTF2_Archive_SetFlushCallbacks(archive, &FLUSH_CALLBACKS, ptr::null_mut()),
+                "OTF2_Archive_SetFlushCallbacks",
+            )?;
+            check(
+                OTF2_Archive_SetSerialCollectiveCallbacks(archive),
+                "OTF2_Archive_SetSerialCollectiveCallbacks",
+            )?;
+
+            check(
+                OTF2_Archive_OpenEvtFiles(archive),
+                "OTF2_Archive_OpenEvtFiles",
+            )?;
+            for (location_ref, location) in trace.locations.iter().enumerate() {
+                let writer = OTF2_Archive_GetEvtWriter(archive, location_ref as u64);
+                for record in &location.records {
+                    match *record {
+                        Record::Enter(time, region) => check(
+                            OTF2_EvtWriter_Enter(writer, ptr::null_mut(), time, region),
+                            "OTF2_EvtWriter_Enter",
+                        )?,
+                        Record::Leave(time, region) => check(
+                            OTF2_EvtWriter_Leave(writer, ptr::null_mut(), time, region),
+                            "OTF2_EvtWriter_Leave",
+                        )?,
+                    }
+                }
+                check(
+                    OTF2_Archive_CloseEvtWriter(archive, writer),
+                    "OTF2_Archive_CloseEvtWriter",
+                )?;
+            }
+            check(
+                OTF2_Archive_CloseEvtFiles(archive),
+                "OTF2_Archive_CloseEvtFiles",
+            )?;
+
+            // Readers expect a local definition file for every location, even if empty
+            check(
+                OTF2_Archive_OpenDefFiles(archive),
+                "OTF2_Archive_OpenDefFiles",
+            )?;
+            for location_ref in 0..trace.locations.len() {
+                let writer = OTF2_Archive_GetDefWriter(archive, location_ref as u64);
+                check(
+                    OTF2_Archive_CloseDefWriter(archive, writer),
+                    "OTF2_Archive_CloseDefWriter",
+                )?;
+            }
+            check(
+                OTF2_Archive_CloseDefFiles(archive),
+                "OTF2_Archive_CloseDefFiles",
+            )?;
+
+            let writer = OTF2_Archive_GetGlobalDefWriter(archive);
+            check(
+                OTF2_GlobalDefWriter_WriteClockProperties(
+                    writer,
+                    1_000_000_000,
+                    0,
+                    trace.trace_length + 1,
+                ),
+                "OTF2_GlobalDefWriter_WriteClockProperties",
+            )?;
+            for (string_ref, string) in strings.iter().enumerate() {
+                check(
+                    OTF2_GlobalDefWriter_WriteString(writer, string_ref as u32, string.as_ptr()),
+                    "OTF2_GlobalDefWriter_WriteString",
+                )?;
+            }
+            let root_name_ref = strings.len() as u32;
+            let root_class_ref = root_name_ref + 1;
+            check(
+                OTF2_GlobalDefWriter_WriteString(writer, root_name_ref, root_name.as_ptr()),
+                "OTF2_GlobalDefWriter_WriteString",
+            )?;
+            check(
+                OTF2_GlobalDefWriter_WriteString(writer, root_class_ref, root_class.as_ptr()),
+                "OTF2_GlobalDefWriter_WriteString",
+            )?;
+            check(
+                OTF2_GlobalDefWriter_WriteSystemTreeNode(
+                    writer,
+                    0,
+                    root_name_ref,
+                    root_class_ref,
+                    OTF2_UNDEFINED_SYSTEM_TREE_NODE,
+                ),
+                "OTF2_GlobalDefWriter_WriteSystemTreeNode",
+            )?;
+            for (group_ref, name) in trace.groups.iter().enumerate() {
+                check(
+                    OTF2_GlobalDefWriter_WriteLocationGroup(
+                        writer,
+                        group_ref as u32,
+                        *name,
+                        OTF2_LOCATION_GROUP_TYPE_PROCESS,
+                        0,
+                    ),
+                    "OTF2_GlobalDefWriter_WriteLocationGroup",
+                )?;
+            }
+            for (region_ref, region) in trace.regions.iter().enumerate() {
+                check(
+                    OTF2_GlobalDefWriter_WriteRegion(
+                        writer,
+                        region_ref as u32,
+                        region.name,
+                        region.name,
+                        OTF2_UNDEFINED_STRING,
+                        OTF2_REGION_ROLE_FUNCTION,
+                        OTF2_PARADIGM_USER,
+                        OTF2_REGION_FLAG_NONE,
+                        region.source_file,
+                        region.line,
+                        region.line,
+                    ),
+                    "OTF2_GlobalDefWriter_WriteRegion",
+                )?;
+            }
+            for (location_ref, location) in trace.locations.iter().enumerate() {
+                check(
+                    OTF2_GlobalDefWriter_WriteLocation(
+                        writer,
+                        location_ref as u64,
+                        location.name,
+                        OTF2_LOCATION_TYPE_CPU_THREAD,
+                        location.records.len() as u64,
+                        location.group,
+                    ),
+                    "OTF2_GlobalDefWriter_WriteLocation",
+                )?;
+            }
+            Ok(())
+        })();
+        let closed = check(OTF2_Archive_Close(archive), "OTF2_Archive_Close");
+        result.and(closed)?;
+    }
+    Ok(())
+}
+
+/// Bindings of the parts of the libotf2 2.x API the exporter uses
+#[cfg(feature = "otf2")]
+#[allow(non_camel_case_types, non_snake_case)]
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    pub enum OTF2_Archive {}
+    pub enum OTF2_EvtWriter {}
+    pub enum OTF2_DefWriter {}
+    pub enum OTF2_GlobalDefWriter {}
+    pub enum OTF2_AttributeList {}
+
+    pub type OTF2_ErrorCode = c_int;
+    pub type OTF2_TimeStamp = u64;
+    pub type OTF2_LocationRef = u64;
+    pub type OTF2_StringRef = u32;
+    pub type OTF2_RegionRef = u32;
+    pub type OTF2_LocationGroupRef = u32;
+    pub type OTF2_SystemTreeNodeRef = u32;
+
+    pub const OTF2_SUCCESS: OTF2_ErrorCode = 0;
+    pub const OTF2_FILEMODE_WRITE: u8 = 0;
+    pub const OTF2_SUBSTRATE_POSIX: u8 = 1;
+    pub const OTF2_COMPRESSION_NONE: u8 = 1;
+    pub const OTF2_FLUSH: u8 = 1;
+    pub const OTF2_CHUNK_SIZE_EVENTS: u64 = 1024 * 1024;
+    pub const OTF2_CHUNK_SIZE_DEFINITIONS: u64 = 4 * 1024 * 1024;
+    pub const OTF2_UNDEFINED_STRING: OTF2_StringRef = u32::max_value();
+    pub const OTF2_UNDEFINED_SYSTEM_TREE_NODE: OTF2_SystemTreeNodeRef = u32::max_value();
+    pub const OTF2_REGION_ROLE_FUNCTION: u8 = 1;
+    pub const OTF2_PARADIGM_USER: u8 = 1;
+    pub const OTF2_REGION_FLAG_NONE: u32 = 0;
+    pub const OTF2_LOCATION_GROUP_TYPE_PROCESS: u8 = 1;
+    pub const OTF2_LOCATION_TYPE_CPU_THREAD: u8 = 1;
+
+    #[repr(C)]
+    pub struct OTF2_FlushCallbacks {
+        pub otf2_pre_flush:
+            extern "C" fn(*mut c_void, u8, OTF2_LocationRef, *mut c_void, bool) -> u8,
+        pub otf2_post_flush: extern "C" fn(*mut c_void, u8, OTF2_LocationRef) -> OTF2_TimeStamp,
+    }
+
+    /// Always flushes, time stamps of the flushes are not recorded
+    pub static FLUSH_CALLBACKS: OTF2_FlushCallbacks = OTF2_FlushCallbacks {
+        otf2_pre_flush: pre_flush,
+        otf2_post_flush: post_flush,
+    };
+
+    extern "C" fn pre_flush(
+        _user_data: *mut c_void,
+        _file_type: u8,
+        _location: OTF2_LocationRef,
+        _caller_data: *mut c_void,
+        _final: bool,
+    ) -> u8 {
+        OTF2_FLUSH
+    }
+
+    extern "C" fn post_flush(
+        _user_data: *mut c_void,
+        _file_type: u8,
+        _location: OTF2_LocationRef,
+    ) -> OTF2_TimeStamp {
+        0
+    }
+
+    #[link(name = "otf2")]
+    extern "C" {
+        pub fn OTF2_Archive_Open(
+            archive_path: *const c_char,
+            archive_name: *const c_char,
+            file_mode: u8,
+            chunk_size_events: u64,
+            chunk_size_defs: u64,
+            file_substrate: u8,
+            compression: u8,
+        ) -> *mut OTF2_Archive;
+        pub fn OTF2_Archive_Close(archive: *mut OTF2_Archive) -> OTF2_ErrorCode;
+        pub fn OTF2_Archive_SetFlushCallbacks(
+            archive: *mut OTF2_Archive,
+            flush_callbacks: *const OTF2_FlushCallbacks,
+            flush_data: *mut c_void,
+        ) -> OTF2_ErrorCode;
+        pub fn OTF2_Archive_SetSerialCollectiveCallbacks(
+            archive: *mut OTF2_Archive,
+        ) -> OTF2_ErrorCode;
+        pub fn OTF2_Archive_OpenEvtFiles(archive: *mut OTF2_Archive) -> OTF2_ErrorCode;
+        pub fn OTF2_Archive_CloseEvtFiles(archive: *mut OTF2_Archive) -> OTF2_ErrorCode;
+        pub fn OTF2_Archive_GetEvtWriter(
+            archive: *mut OTF2_Archive,
+            location: OTF2_LocationRef,
+        ) -> *mut OTF2_EvtWriter;
+        pub fn OTF2_Archive_CloseEvtWriter(
+            archive: *mut OTF2_Archive,
+            writer: *mut OTF2_EvtWriter,
+        ) -> OTF2_ErrorCode;
+        pub fn OTF2_Archive_OpenDefFiles(archive: *mut OTF2_Archive) -> OTF2_ErrorCode;
+        pub fn OTF2_Archive_CloseDefFiles(archive: *mut OTF2_Archive) -> OTF2_ErrorCode;
+        pub fn OTF2_Archive_GetDefWriter(
+            archive: *mut OTF2_Archive,
+            location: OTF2_LocationRef,
+        ) -> *mut OTF2_DefWriter;
+        pub fn OTF2_Archive_CloseDefWriter(
+            archive: *mut OTF2_Archive,
+            writer: *mut OTF2_DefWriter,
+        ) -> OTF2_ErrorCode;
+        pub fn OTF2_Archive_GetGlobalDefWriter(
+            archive: *mut OTF2_Archive,
+        ) -> *mut OTF2_GlobalDefWriter;
+        pub fn OTF2_EvtWriter_Enter(
+            writer: *mut OTF2_EvtWriter,
+            attribute_list: *mut OTF2_AttributeList,
+            time: OTF2_TimeStamp,
+            region: OTF2_RegionRef,
+        ) -> OTF2_ErrorCode;
+        pub fn OTF2_EvtWriter_Leave(
+            writer: *mut OTF2_EvtWriter,
+            attribute_list: *mut OTF2_AttributeList,
+            time: OTF2_TimeStamp,
+            region: OTF2_RegionRef,
+        ) -> OTF2_ErrorCode;
+        pub fn OTF2_GlobalDefWriter_WriteClockProperties(
+            writer: *mut OTF2_GlobalDefWriter,
+            timer_resolution: u64,
+            global_offset: u64,
+            trace_length: u64,
+        ) -> OTF2_ErrorCode;
+        pub fn OTF2_GlobalDefWriter_WriteString(
+            writer: *mut OTF2_GlobalDefWriter,
+            string_ref: OTF2_StringRef,
+            string: *const c_char,
+        ) -> OTF2_ErrorCode;
+        pub fn OTF2_GlobalDefWriter_WriteSystemTreeNode(
+            writer: *mut OTF2_GlobalDefWriter,
+            node_ref: OTF2_SystemTreeNodeRef,
+            name: OTF2_StringRef,
+            class_name: OTF2_StringRef,
+            parent: OTF2_SystemTreeNodeRef,
+        ) -> OTF2_ErrorCode;
+        pub fn OTF2_GlobalDefWriter_WriteLocationGroup(
+            writer: *mut OTF2_GlobalDefWriter,
+            group_ref: OTF2_LocationGroupRef,
+            name: OTF2_StringRef,
+            group_type: u8,
+            system_tree_parent: OTF2_SystemTreeNodeRef,
+        ) -> OTF2_ErrorCode;
+        pub fn OTF2_GlobalDefWriter_WriteRegion(
+            writer: *mut OTF2_GlobalDefWriter,
+            region_ref: OTF2_RegionRef,
+            name: OTF2_StringRef,
+            canonical_name: OTF2_StringRef,
+            description: OTF2_StringRef,
+            region_role: u8,
+            paradigm: u8,
+            region_flags: u32,
+            source_file: OTF2_StringRef,
+            begin_line_number: u32,
+            end_line_number: u32,
+        ) -> OTF2_ErrorCode;
+        pub fn OTF2_GlobalDefWriter_WriteLocation(
+            writer: *mut OTF2_GlobalDefWriter,
+            location_ref: OTF2_LocationRef,
+            name: OTF2_StringRef,
+            location_type: u8,
+            number_of_events: u64,
+            location_group: OTF2_LocationGroupRef,
+        ) -> OTF2_ErrorCode;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spans::pair;
+    use crate::spans::tests::event;
+
+    #[test]
+    fn maps_spans_to_nested_records() {
+        let spans = pair(vec![
+            event("main", "GLOBAL_BEGIN", 10),
+            event("send", "BEGIN", 10),
+            event("send", "END", 12),
+            event("main", "GLOBAL_END", 12),
+            event("send", "BEGIN", 11).on_thread(2),
+            event("send", "END", 13).on_thread(2),
+        ]);
+        let trace = Trace::from_spans(&spans);
+
+        assert_eq!(trace.groups.len(), 1);
+        assert_eq!(trace.locations.len(), 2);
+        assert_eq!(trace.regions.len(), 2);
+        assert_eq!(trace.trace_length, 3);
+        let main = trace.locations[0].records.clone();
+        let (send, main_region) = match (main[1], main[0]) {
+            (Record::Enter(_, send), Record::Enter(_, main_region)) => (send, main_region),
+            records => panic!("unexpected records {:?}", records),
+        };
+        assert_eq!(
+            main,
+            vec![
+                Record::Enter(0, main_region),
+                Record::Enter(0, send),
+                Record::Leave(2, send),
+                Record::Leave(2, main_region),
+            ]
+        );
+        assert_eq!(
+            trace.locations[1].records,
+            vec![Record::Enter(1, send), Record::Leave(3, send)]
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use instdata::{Channel, ChannelOp, Spawn};
+use rusqlite::Connection;
+use std::collections::HashMap;
+
+/// Event of a run as stored in the db
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub time_stamp: u64,
+    pub machine_id: String,
+    pub pid: u32,
+    /// Id of the run of the process, tells processes with the same pid apart
+    pub run_id: Option<String>,
+    pub thread_id: u64,
+    /// Name of the thread, if it has one
+    pub thread_name: Option<String>,
+    pub absolute_path: String,
+    pub description: String,
+    pub ast_depth: u64,
+    pub source_file: String,
+    pub line: u64,
+    /// Message sent or received, set on the begin events of channel operations
+    pub channel: Option<Channel>,
+    /// Parent thread, set on the begin events of spawned closures
+    pub spawn: Option<Spawn>,
+}
+
+impl Event {
+    /// Name of the thread followed by its number, or only the number
+    pub fn thread_label(&self) -> String {
+        match &self.thread_name {
+            Some(name) => format!("{} ({})", name, self.thread_id),
+            None => format!("thread {}", self.thread_id),
+        }
+    }
+
+    /// Pid and machine of the event, followed by the run id if there is one
+    pub fn process_label(&self) -> String {
+        match &self.run_id {
+            Some(run_id) => format!("pid {} on {} (run {})", self.pid, self.machine_id, run_id),
+            None => format!("pid {} on {}", self.pid, self.machine_id),
+        }
+    }
+}
+
+/// Thread of a run: machine, pid, run id and thread id
+pub type ThreadKey<'a> = (&'a str, u32, Option<&'a str>, u64);
+
+/// Kind of a span, given by the descriptions of its events
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SpanKind {
+    /// BEGIN and END around a call
+    Call,
+    /// LOCAL_BEGIN and LOCAL_END around the closure of a spawned thread
+    Thread,
+    /// GLOBAL_BEGIN and GLOBAL_END around main
+    Main,
+}
+
+impl SpanKind {
+    /// Kind of the description and whether it opens a span
+    pub fn of(description: &str) -> Option<(SpanKind, bool)> {
+        match description {
+            "BEGIN" => Some((SpanKind::Call, true)),
+            "END" => Some((SpanKind::Call, false)),
+            "LOCAL_BEGIN" => Some((SpanKind::Thread, true)),
+            "LOCAL_END" => Some((SpanKind::Thread, false)),
+            "GLOBAL_BEGIN" => Some((SpanKind::Main, true)),
+            "GLOBAL_END" => Some((SpanKind::Main, false)),
+            _ => None,
+        }
+    }
+
+    /// Short name of the kind
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SpanKind::Call => "call",
+            SpanKind::Thread => "thread",
+            SpanKind::Main => "main",
+        }
+    }
+}
+
+/// Matched pair of begin and end event
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub kind: SpanKind,
+    pub begin: Event,
+    pub end_time: u64,
+    /// Positions of the begin and end event in the paired events
+    pub begin_index: usize,
+    pub end_index: usize,
+    /// Time spent in directly nested spans
+    pub child_time: u64,
+    /// Number of directly nested spans
+    pub children: u64,
+    /// Number of spans nested at any depth
+    pub nested: u64,
+}
+
+impl Span {
+    /// Time between begin and end in nanoseconds
+    pub fn duration(&self) -> u64 {
+        self.end_time.saturating_sub(self.begin.time_stamp)
+    }
+
+    /// Time spent in the span itself and not in nested spans
+    pub fn exclusive(&self) -> u64 {
+        self.duration().saturating_sub(self.child_time)
+    }
+}
+
+/// Spans of a run and the events without counterpart
+#[derive(Debug, Default)]
+pub struct Spans {
+    pub spans: Vec<Span>,
+    pub unmatched_begins: Vec<Event>,
+    pub unmatched_ends: Vec<Event>,
+}
+
+impl Spans {
+    /// Earliest time stamp of every machine. Time stamps of different machines
+    /// are not comparable, exporters let every machine start at zero
+    pub fn origins(&self) -> HashMap<&str, u64> {
+        let mut origins: HashMap<&str, u64> = HashMap::new();
+        let begins = self
+            .spans
+            .iter()
+            .map(|span| &span.begin)
+            .chain(&self.unmatched_begins);
+        for event in begins {
+            let origin = origins
+                .entry(event.machine_id.as_str())
+                .or_insert(event.time_stamp);
+            *origin = (*origin).min(event.time_stamp);
+        }
+        origins
+    }
+}
+
+/// Loads the events of a run ordered by stream and sequence number
+pub fn load_events(
+    conn: &Connection,
+    table_name: &str,
+    run_name: &str,
+) -> Result<Vec<Event>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        format!(
+            "SELECT event.time_stamp, event.machine_id, event.pid, event.thread_id, \
+             event.absolute_path, event.description, event.ast_depth, event.source_file, \
+             event.lines_begin, event.channel_id, event.channel_op, event.channel_seq, \
+             event.parent_thread_id, event.spawn_site_id, thread.name, event.run_id \
+             FROM {table} AS event LEFT JOIN {table}_threads AS thread \
+             ON thread.run_name = event.run_name AND thread.machine_id = event.machine_id \
+             AND thread.pid = event.pid AND thread.run_id IS event.run_id \
+             AND thread.thread_id = event.thread_id \
+             WHERE event.run_name = ?1 \
+             ORDER BY event.machine_id, event.pid, event.run_id, event.thread_id, event.counter",
+            table = table_name
+        )
+        .as_str(),
+    )?;
+    let events = stmt
+        .query_map(&[run_name], |row| {
+            Ok(Event {
+                time_stamp: row.get::<_, i64>(0)? as u64,
+                machine_id: row.get(1)?,
+                pid: row.get(2)?,
+                run_id: row.get(15)?,
+                thread_id: row.get::<_, i64>(3)? as u64,
+                thread_name: row.get(14)?,
+                absolute_path: row.get(4)?,
+                description: row.get(5)?,
+                ast_depth: row.get::<_, i64>(6)? as u64,
+                source_file: row.get(7)?,
+                line: row.get::<_, i64>(8)? as u64,
+                channel: match (
+                    row.get::<_, Option<i64>>(9)?,
+                    row.get::<_, Option<String>>(10)?,
+                    row.get::<_, Option<i64>>(11)?,
+                ) {
+                    (Some(id), Some(op), Some(seq)) => ChannelOp::parse(&op).map(|op| Channel {
+                        id: id as u64,
+                        op,
+                        seq: seq as u64,
+                    }),
+                    _ => None,
+                },
+                spawn: match (
+                    row.get::<_, Option<i64>>(12)?,
+                    row.get::<_, Option<i64>>(13)?,
+                ) {
+                    (Some(parent_thread_id), Some(site_id)) => Some(Spawn {
+                        parent_thread_id: parent_thread_id as u64,
+                        site_id: site_id as u64,
+                    }),
+                    _ => None,
+                },
+            })
+        })?
+        .collect();
+    events
+}
+
+/// Checks if the run is known
+pub fn run_exists(
+    conn: &Connection,
+    table_name: &str,
+    run_name: &str,
+) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        format!(
+            "SELECT count(*) > 0 FROM {}_runs WHERE run_name = ?1",
+            table_name
+        )
+        .as_str(),
+        &[run_name],
+        |row| row.get(0),
+    )
+}
+
+/// Open span while pairing
+struct OpenSpan {
+    kind: SpanKind,
+    begin_index: usize,
+    begin: Event,
+    child_time: u64,
+    children: u64,
+    nested: u64,
+}
+
+/// Pairs begin and end events per thread. The events have to be ordered by stream and
+/// sequence number. An end event closes the innermost open span of the same kind, path
+/// and AST depth, spans opened after that one are left unmatched
+pub fn pair(events: Vec<Event>) -> Spans {
+    let mut result = Spans::default();
+    let mut open: Vec<OpenSpan> = Vec::new();
+    let mut stream: Option<(String, u32, Option<String>, u64)> = None;
+    for (position, event) in events.into_iter().enumerate() {
+        let key = (
+            event.machine_id.clone(),
+            event.pid,
+            event.run_id.clone(),
+            event.thread_id,
+        );
+        if stream.as_ref() != Some(&key) {
+            result
+                .unmatched_begins
+                .extend(open.drain(..).map(|unmatched| unmatched.begin));
+            stream = Some(key);
+        }
+        match SpanKind::of(&event.description) {
+            Some((kind, true)) => open.push(OpenSpan {
+                kind,
+                begin_index: position,
+                begin: event,
+                child_time: 0,
+                children: 0,
+                nested: 0,
+            }),
+            Some((kind, false)) => {
+                let index = open.iter().rposition(|open| {
+                    open.kind == kind
+                        && open.begin.absolute_path == event.absolute_path
+                        && open.begin.ast_depth == event.ast_depth
+                });
+                match index {
+                    Some(index) => {
+                        result
+                            .unmatched_begins
+                            .extend(open.drain(index + 1..).map(|unmatched| unmatched.begin));
+                        let closed = open.pop().unwrap();
+                        let span = Span {
+                            kind: closed.kind,
+                            begin: closed.begin,
+                            end_time: event.time_stamp,
+                            begin_index: closed.begin_index,
+                            end_index: position,
+                            child_time: closed.child_time,
+                            children: closed.children,
+                            nested: closed.nested,
+                        };
+                        if let Some(parent) = open.last_mut() {
+                            parent.child_time += span.duration();
+                            parent.children += 1;
+                            parent.nested += span.nested + 1;
+                        }
+                        result.spans.push(span);
+                    }
+                    None => result.unmatched_ends.push(event),
+                }
+            }
+            None => (),
+        }
+    }
+    result
+        .unmatched_begins
+        .extend(open.drain(..).map(|unmatched| unmatched.begin));
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Event of thread 1 of process 1 at src/main.rs:1. Tests change the fields they need
+    pub(crate) fn event(absolute_path: &str, description: &str, time_stamp: u64) -> Event {
+        Event {
+            time_stamp,
+            machine_id: String::from("127.0.0.1"),
+            pid: 1,
+            run_id: Some(String::from("run")),
+            thread_id: 1,
+            thread_name: None,
+            absolute_path: String::from(absolute_path),
+            description: String::from(description),
+            ast_depth: 1,
+            source_file: String::from("src/main.rs"),
+            line: 1,
+            channel: None,
+            spawn: None,
+        }
+    }
+
+    impl Event {
+        /// Moves the event to the thread
+        pub(crate) fn on_thread(mut self, thread_id: u64) -> Event {
+            self.thread_id = thread_id;
+            self
+        }
+
+        /// Makes the event a channel operation
+        pub(crate) fn with_channel(mut self, channel: Channel) -> Event {
+            self.channel = Some(channel);
+            self
+        }
+
+        /// Makes the event the start of a spawned closure
+        pub(crate) fn with_spawn(mut self, spawn: Spawn) -> Event {
+            self.spawn = Some(spawn);
+            self
+        }
+    }
+
+    #[test]
+    fn pairs_nested_spans_per_thread() {
+        let spans = pair(vec![
+            event("main", "GLOBAL_BEGIN", 1),
+            event("send", "BEGIN", 2),
+            event("lock", "BEGIN", 3),
+            event("send", "END", 5),
+            event("main", "GLOBAL_END", 8),
+            event("recv", "END", 4).on_thread(2),
+            event("recv", "BEGIN", 6).on_thread(2),
+        ]);
+
+        let found = spans
+            .spans
+            .iter()
+            .map(|span| {
+                (
+                    span.begin.absolute_path.as_str(),
+                    span.duration(),
+                    span.exclusive(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![("send", 3, 3), ("main", 7, 4)]);
+        let unmatched = spans
+            .unmatched_begins
+            .iter()
+            .map(|event| event.absolute_path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(unmatched, vec!["lock", "recv"]);
+        assert_eq!(spans.unmatched_ends.len(), 1);
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::analyze::millis;
+use crate::spans::{SpanKind, Spans};
+
+/// Number of idle gaps listed in the text report
+const LISTED_GAPS: usize = 5;
+
+/// Thread of the timeline with the times its spawned closures or main ran
+#[derive(Debug)]
+pub struct Lane {
+    pub label: String,
+    /// Merged intervals of thread spans, nested closures (e.g. of `rayon::join`) count once
+    pub busy: Vec<(u64, u64)>,
+    /// Interval of the main function if it ran on this thread
+    pub main: Option<(u64, u64)>,
+}
+
+/// Parallelism of the threads of one machine. Time stamps of different machines are not
+/// comparable, so every machine gets its own timeline. Parallelism counts the threads
+/// running a spawned closure, the main thread only counts while it runs one itself
+#[derive(Debug)]
+pub struct Timeline {
+    pub machine_id: String,
+    pub start: u64,
+    pub end: u64,
+    pub lanes: Vec<Lane>,
+    /// Most threads running at the same time
+    pub peak: usize,
+    /// Running threads averaged over the whole timeline
+    pub average: f64,
+    /// Intervals in which no spawned closure ran, longest first
+    pub idle_gaps: Vec<(u64, u64)>,
+    /// Lane and interval of the spans on the critical path, in order of time
+    pub critical_path: Vec<(usize, u64, u64)>,
+}
+
+impl Timeline {
+    /// Length of the timeline in nanoseconds
+    pub fn duration(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Running threads per time step
+    fn steps(&self) -> Vec<(u64, usize)> {
+        let mut changes: BTreeMap<u64, i64> = BTreeMap::new();
+        for lane in &self.lanes {
+            for &(begin, end) in &lane.busy {
+                *changes.entry(begin).or_insert(0) += 1;
+                *changes.entry(end).or_insert(0) -= 1;
+            }
+        }
+        let mut running = 0;
+        changes
+            .into_iter()
+            .map(|(time, change)| {
+                running += change;
+                (time, running as usize)
+            })
+            .collect()
+    }
+}
+
+/// Builds the timelines of all machines of a run
+pub fn timelines(spans: &Spans) -> Vec<Timeline> {
+    // machine -> (pid, thread) -> lane
+    let mut machines: BTreeMap<&str, BTreeMap<(u32, u64), Lane>> = BTreeMap::new();
+    for span in &spans.spans {
+        let begin = &span.begin;
+        let lane = machines
+            .entry(&begin.machine_id)
+            .or_default()
+            .entry((begin.pid, begin.thread_id))
+            .or_insert_with(|| Lane {
+                label: format!("{} {}", begin.pid, begin.thread_label()),
+                busy: Vec::new(),
+                main: None,
+            });
+        match span.kind {
+            SpanKind::Thread => lane.busy.push((begin.time_stamp, span.end_time)),
+            SpanKind::Main => lane.main = Some((begin.time_stamp, span.end_time)),
+            SpanKind::Call => (),
+        }
+    }
+
+    let mut timelines = Vec::new();
+    for (machine_id, lanes) in machines {
+        let mut lanes = lanes
+            .into_iter()
+            .map(|(_, mut lane)| {
+                lane.busy = merge(lane.busy);
+                lane
+            })
+            .filter(|lane| !lane.busy.is_empty() || lane.main.is_some())
+            .collect::<Vec<_>>();
+        lanes.sort_by_key(|lane| (lane.main.is_none(), lane.busy.first().map(|busy| busy.0)));
+        let intervals = lanes
+            .iter()
+            .flat_map(|lane| lane.busy.iter().chain(&lane.main))
+            .collect::<Vec<_>>();
+        let start = match intervals.iter().map(|interval| interval.0).min() {
+            Some(start) => start,
+            None => continue,
+        };
+        let end = intervals.iter().map(|interval| interval.1).max().unwrap();
+
+        let mut timeline = Timeline {
+            machine_id: String::from(machine_id),
+            start,
+            end,
+            lanes,
+            peak: 0,
+            average: 0.0,
+            idle_gaps: Vec::new(),
+            critical_path: Vec::new(),
+        };
+        let mut busy_time = 0;
+        let mut last = (start, 0);
+        for (time, running) in timeline.steps() {
+            busy_time += (time - last.0) * last.1 as u64;
+            if last.1 == 0 && time > last.0 {
+                timeline.idle_gaps.push((last.0, time));
+            }
+            timeline.peak = timeline.peak.max(running);
+            last = (time, running);
+        }
+        if end > last.0 {
+            timeline.idle_gaps.push((last.0, end));
+        }
+        if timeline.duration() > 0 {
+            timeline.average = busy_time as f64 / timeline.duration() as f64;
+        }
+        timeline
+            .idle_gaps
+            .sort_by_key(|gap| std::cmp::Reverse(gap.1 - gap.0));
+        timeline.critical_path = critical_path(&timeline.lanes);
+        timelines.push(timeline);
+    }
+    timelines
+}
+
+/// Merges overlapping intervals
+fn merge(mut intervals: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    intervals.sort();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(intervals.len());
+    for (begin, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if begin <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((begin, end)),
+        }
+    }
+    merged
+}
+
+/// Approximates the critical path greedily: starting from the end, the busy interval
+/// which finished last before the current one began is added to the path
+fn critical_path(lanes: &[Lane]) -> Vec<(usize, u64, u64)> {
+    let mut busy = lanes
+        .iter()
+        .enumerate()
+        .flat_map(|(index, lane)| {
+            lane.busy
+                .iter()
+                .map(move |&(begin, end)| (index, begin, end))
+        })
+        .collect::<Vec<_>>();
+    busy.sort_by_key(|&(_, _, end)| end);
+
+    let mut path = Vec::new();
+    let mut until = u64::max_value();
+    while let Some(position) = busy.iter().rposition(|&(_, _, end)| end <= until) {
+        let interval = busy[position];
+        path.push(interval);
+        busy.truncate(position);
+        until = interval.1;
+    }
+    path.reverse();
+    path
+}
+
+/// Writes the statistics and an ASCII chart with `width` columns per machine.
+/// `#` marks a running spawned closure, `=` the main function, `*` the critical path
+pub fn ascii<W: Write>(timelines: &[Timeline], width: usize, mut writer: W) -> io::Result<()> {
+    for timeline in timelines {
+        writeln!(writer, "Machine {}", timeline.machine_id)?;
+        writeln!(
+            writer,
+            "  duration:            {}",
+            millis(timeline.duration())
+        )?;
+        writeln!(writer, "  peak parallelism:    {}", timeline.peak)?;
+        writeln!(writer, "  average parallelism: {:.2}", timeline.average)?;
+        let on_path = timeline
+            .critical_path
+            .iter()
+            .map(|&(_, begin, end)| end - begin)
+            .sum();
+        writeln!(
+            writer,
+            "  critical path:       {} in {} spans",
+            millis(on_path),
+            timeline.critical_path.len()
+        )?;
+        let idle = timeline.idle_gaps.iter().map(|gap| gap.1 - gap.0).sum();
+        writeln!(
+            writer,
+            "  idle:                {} in {} gaps",
+            millis(idle),
+            timeline.idle_gaps.len()
+        )?;
+        for gap in timeline.idle_gaps.iter().take(LISTED_GAPS) {
+            writeln!(
+                writer,
+                "    {} at +{}",
+                millis(gap.1 - gap.0),
+                millis(gap.0 - timeline.start)
+            )?;
+        }
+        writeln!(writer)?;
+
+        let column = |time: u64| {
+            ((time - timeline.start) as u128 * width as u128 / (timeline.duration() as u128 + 1))
+                as usize
+        };
+        let label_width = timeline
+            .lanes
+            .iter()
+            .map(|lane| lane.label.len())
+            .max()
+            .unwrap_or(0)
+            .max("parallelism".len());
+        for (index, lane) in timeline.lanes.iter().enumerate() {
+            let mut row = vec![' '; width];
+            let mut paint = |(begin, end): (u64, u64), mark: char| {
+                for cell in &mut row[column(begin)..=column(end).min(width - 1)] {
+                    *cell = mark;
+                }
+            };
+            if let Some(main) = lane.main {
+                paint(main, '=');
+            }
+            for &busy in &lane.busy {
+                paint(busy, '#');
+            }
+            for &(_, begin, end) in timeline.critical_path.iter().filter(|path| path.0 == index) {
+                paint((begin, end), '*');
+            }
+            writeln!(
+                writer,
+                "  {:>width$} |{}|",
+                lane.label,
+                row.into_iter().collect::<String>(),
+                width = label_width
+            )?;
+        }
+
+        // Highest parallelism reached within each column
+        let mut parallelism = vec![0; width];
+        let steps = timeline.steps();
+        for (step, &(time, running)) in steps.iter().enumerate() {
+            let until = steps.get(step + 1).map_or(timeline.end, |next| next.0);
+            for cell in &mut parallelism[column(time)..=column(until).min(width - 1)] {
+                *cell = (*cell).max(running);
+            }
+        }
+        let parallelism = parallelism
+            .into_iter()
+            .map(|running| match running {
+                0 => ' ',
+                1..=9 => (b'0' + running as u8) as char,
+                _ => '+',
+            })
+            .collect::<String>();
+        writeln!(
+            writer,
+            "  {:>width$} |{}|\n",
+            "parallelism",
+            parallelism,
+            width = label_width
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes a self-contained HTML page with one SVG chart per machine
+pub fn html<W: Write>(timelines: &[Timeline], run_name: &str, mut writer: W) -> io::Result<()> {
+    const WIDTH: f64 = 1000.0;
+    const LABEL: f64 = 200.0;
+    const ROW: f64 = 20.0;
+
+    writeln!(
+        writer,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+         <style>body {{ font-family: sans-serif; }} text {{ font-size: 12px; }}</style>\n\
+         </head>\n<body>\n<h1>{}</h1>",
+        escape(run_name),
+        escape(run_name)
+    )?;
+    for timeline in timelines {
+        let x = |time: u64| {
+            LABEL + (time - timeline.start) as f64 * WIDTH / timeline.duration().max(1) as f64
+        };
+        writeln!(
+            writer,
+            "<h2>Machine {}</h2>\n<p>Duration {}, peak parallelism {}, average parallelism {:.2}, \
+             {} idle gaps</p>",
+            escape(&timeline.machine_id),
+            millis(timeline.duration()),
+            timeline.peak,
+            timeline.average,
+            timeline.idle_gaps.len()
+        )?;
+        writeln!(
+            writer,
+            "<svg width=\"{}\" height=\"{}\">",
+            LABEL + WIDTH + 10.0,
+            ROW * (timeline.lanes.len() as f64 + 1.0)
+        )?;
+        for &(begin, end) in &timeline.idle_gaps {
+            writeln!(
+                writer,
+                "<rect x=\"{:.1}\" y=\"0\" width=\"{:.1}\" height=\"{}\" fill=\"#eeeeee\">\
+                 <title>idle {}</title></rect>",
+                x(begin),
+                x(end) - x(begin),
+                ROW * timeline.lanes.len() as f64,
+                millis(end - begin)
+            )?;
+        }
+        for (index, lane) in timeline.lanes.iter().enumerate() {
+            let y = ROW * index as f64;
+            writeln!(
+                writer,
+                "<text x=\"0\" y=\"{}\">{}</text>",
+                y + ROW * 0.7,
+                escape(&lane.label)
+            )?;
+            let critical = |begin: u64, end: u64| {
+                timeline
+                    .critical_path
+                    .iter()
+                    .any(|path| *path == (index, begin, end))
+            };
+            let intervals = lane
+                .main
+                .iter()
+                .map(|&(begin, end)| (begin, end, "#9ecae1"))
+                .chain(lane.busy.iter().map(|&(begin, end)| {
+                    (
+                        begin,
+                        end,
+                        if critical(begin, end) {
+                            "#de2d26"
+                        } else {
+                            "#3182bd"
+                        },
+                    )
+                }));
+            for (begin, end, color) in intervals {
+                writeln!(
+                    writer,
+                    "<rect x=\"{:.1}\" y=\"{}\" width=\"{:.1}\" height=\"{}\" fill=\"{}\">\
+                     <title>{}</title></rect>",
+                    x(begin),
+                    y + 2.0,
+                    (x(end) - x(begin)).max(1.0),
+                    ROW - 4.0,
+                    color,
+                    millis(end - begin)
+                )?;
+            }
+        }
+        writeln!(writer, "</svg>")?;
+    }
+    writeln!(
+        writer,
+        "<p>Light blue: main, blue: spawned closures, red: critical path, grey: idle</p>\n\
+         </body>\n</html>"
+    )
+}
+
+/// Escapes text for HTML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spans::pair;
+    use crate::spans::tests::event;
+
+    #[test]
+    fn measures_parallelism_and_gaps() {
+        let spans = pair(vec![
+            event("main", "GLOBAL_BEGIN", 0),
+            event("main", "GLOBAL_END", 100),
+            event("spawn", "LOCAL_BEGIN", 10).on_thread(2),
+            event("spawn", "LOCAL_END", 50).on_thread(2),
+            event("spawn", "LOCAL_BEGIN", 20).on_thread(3),
+            event("spawn", "LOCAL_END", 40).on_thread(3),
+            event("spawn", "LOCAL_BEGIN", 60).on_thread(3),
+            event("spawn", "LOCAL_END", 90).on_thread(3),
+        ]);
+        let timelines = timelines(&spans);
+        let timeline = &timelines[0];
+
+        assert_eq!(timeline.lanes.len(), 3);
+        assert_eq!(timeline.peak, 2);
+        assert!((timeline.average - 0.9).abs() < 1e-9);
+        assert_eq!(timeline.idle_gaps, vec![(0, 10), (50, 60), (90, 100)]);
+        assert_eq!(timeline.critical_path, vec![(1, 10, 50), (2, 60, 90)]);
+    }
+}
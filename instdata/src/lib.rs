@@ -1,41 +1,125 @@
 use bincode;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::process;
-use std::thread;
 use time;
 
 /// Default data structure for dynamic data
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DynData {
     pub system_time: u64,
+    /// Sequence number of the event within its stream, starting at 1
     pub counter: u128,
     pub pid: u32,
-    pub thread_id: String,
-    pub machine_id: String,
+    /// Number of the instrumented thread within its process, starting at 1
+    pub thread_id: u64,
+    /// Message sent or received if the probe begins a channel operation
+    pub channel: Option<Channel>,
+    /// Parent thread if the probe starts a spawned closure
+    pub spawn: Option<Spawn>,
 }
 
 impl DynData {
     /// Constructs new DynData object
-    pub fn new(machine_id: &str) -> DynData {
+    pub fn new(thread_id: u64) -> DynData {
         DynData {
             system_time: time::precise_time_ns(),
             counter: 0,
             pid: process::id(),
-            thread_id: format!("{:?}", thread::current().id()),
-            machine_id: String::from(machine_id),
+            thread_id,
+            channel: None,
+            spawn: None,
         }
     }
 
-    /// Updates dynamic Data
-    pub fn update(&mut self) {
+    /// Updates dynamic Data with the time stamp the probe took
+    pub fn update(&mut self, system_time: u64, channel: Option<Channel>, spawn: Option<Spawn>) {
         self.counter += 1;
-        self.system_time = time::precise_time_ns();
+        self.system_time = system_time;
+        self.channel = channel;
+        self.spawn = spawn;
+    }
+}
+
+/// Identifies an event stream, or with thread id 0 a process. Sent with the records which
+/// describe a stream or process, events refer to the thread record of their batch
+/// by pid and thread id
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Origin {
+    pub machine_id: String,
+    pub pid: u32,
+    /// Id of the run of the process, tells processes with the same pid apart
+    pub run_id: String,
+    pub thread_id: u64,
+}
+
+impl Origin {
+    /// Constructs the origin of a stream of the current process
+    pub fn new(machine_id: &str, run_id: &str, thread_id: u64) -> Origin {
+        Origin {
+            machine_id: String::from(machine_id),
+            pid: process::id(),
+            run_id: String::from(run_id),
+            thread_id,
+        }
     }
 }
 
+/// Origin of a spawned closure
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Spawn {
+    /// Thread id of the spawning thread
+    pub parent_thread_id: u64,
+    /// Site id of the BEGIN probe of the spawning call
+    pub site_id: u64,
+}
+
+/// Operation on a channel
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum ChannelOp {
+    Send,
+    Recv,
+}
+
+impl ChannelOp {
+    /// Name of the operation as stored by the collector
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChannelOp::Send => "send",
+            ChannelOp::Recv => "recv",
+        }
+    }
+
+    /// Parses the name returned by as_str
+    pub fn parse(name: &str) -> Option<ChannelOp> {
+        match name {
+            "send" => Some(ChannelOp::Send),
+            "recv" => Some(ChannelOp::Recv),
+            _ => None,
+        }
+    }
+}
+
+/// Message passed through a channel
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Channel {
+    /// Identity of the channel, unique within its process
+    pub id: u64,
+    pub op: ChannelOp,
+    /// Sequence number of the operation on the channel, starting at 1.
+    /// The n-th recv of a channel takes the message of its n-th send
+    pub seq: u64,
+}
+
 /// Default data structure for static data
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct StaticData {
+    pub site_id: u64,
     pub absolute_path: String,
     pub description: String,
     pub ast_depth: u128,
@@ -55,6 +139,14 @@ impl StaticData {
         lines_end: u128,
     ) -> StaticData {
         StaticData {
+            site_id: site_id(
+                absolute_path,
+                description,
+                ast_depth,
+                source_file,
+                lines_begin,
+                lines_end,
+            ),
             absolute_path: String::from(absolute_path),
             description: String::from(description),
             ast_depth,
@@ -65,32 +157,433 @@ impl StaticData {
     }
 }
 
-/// Convert for bincode to Rust struct
-pub fn to_structs(bytes: &[u8]) -> Option<(DynData, StaticData)> {
-    match bincode::deserialize(bytes) {
-        Ok(data) => Some(data),
-        Err(err) => {
-            eprintln!("Unable to deserialize SendData: {}", err);
-            None
+/// Computes the site id of a probe. The id only depends on the static data,
+/// so the same source code yields the same id in every build (64 bit FNV-1a)
+pub fn site_id(
+    absolute_path: &str,
+    description: &str,
+    ast_depth: u128,
+    source_file: &str,
+    lines_begin: u128,
+    lines_end: u128,
+) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let fields: [&[u8]; 6] = [
+        absolute_path.as_bytes(),
+        description.as_bytes(),
+        &ast_depth.to_le_bytes(),
+        source_file.as_bytes(),
+        &lines_begin.to_le_bytes(),
+        &lines_end.to_le_bytes(),
+    ];
+    for field in fields.iter() {
+        // Separator keeps ("ab", "c") and ("a", "bc") apart
+        for byte in field.iter().chain(&[0xff]) {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
         }
     }
+    hash
 }
 
-/// Convert from Rust struct to bincode
-pub fn to_bincode(dynamic_data: &DynData, static_data: &StaticData) -> Option<Vec<u8>> {
-    match bincode::serialize(&(dynamic_data, static_data)) {
-        Ok(serialized) => Some(serialized),
-        Err(err) => {
-            eprintln!("Unable to serialize SendData: {}", err);
-            None
+/// Identity of an instrumented thread besides its number
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ThreadInfo {
+    /// Thread id of the operating system, if known
+    pub os_tid: Option<u64>,
+    pub name: Option<String>,
+}
+
+/// Totals of an event stream, sent when the stream ends
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Summary {
+    /// Number of events in the stream
+    pub events: u64,
+    /// Number of batches of the stream
+    pub batches: u64,
+    /// Number of batches which were not sent when flushed. A batch kept by a TCP connection
+    /// counts as well, even if it is sent after reconnecting
+    pub failed_batches: u64,
+    /// Number of events in the batches which were not sent when flushed
+    pub failed_events: u64,
+    /// Number of events dropped by the probes because the buffer of the thread was full.
+    /// They are not part of the stream
+    pub dropped_events: u64,
+}
+
+/// Cost of a probe, measured once per process at global init
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Calibration {
+    /// Number of timed probe calls
+    pub probes: u64,
+    /// Time of all timed probe calls in nanoseconds
+    pub total_ns: u64,
+}
+
+/// Settings of an instrumented process, sent once per process
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ProcessInfo {
+    /// Text of the config the process runs with
+    pub config: String,
+}
+
+/// Record of the event stream
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Record {
+    /// Definition of a probe site
+    Site(StaticData),
+    /// Probe hit, refers to its site by id and to the thread record of its stream
+    /// by pid and thread id
+    Event(DynData, u64),
+    /// End of the stream
+    Summary(Origin, Summary),
+    /// Identity of the thread of the stream, precedes the events of the stream in every batch
+    Thread(Origin, ThreadInfo),
+    /// Probe cost of the process
+    Calibration(Origin, Calibration),
+    /// Settings of the process
+    Process(Origin, ProcessInfo),
+}
+
+/// Borrowed counterpart of Record, serializes to the same bytes
+#[derive(Serialize)]
+enum RecordRef<'a> {
+    Site(&'a StaticData),
+    Event(&'a DynData, u64),
+    Summary(&'a Origin, &'a Summary),
+    Thread(&'a Origin, &'a ThreadInfo),
+    Calibration(&'a Origin, &'a Calibration),
+    Process(&'a Origin, &'a ProcessInfo),
+}
+
+/// Magic bytes at the beginning of every batch
+const BATCH_MAGIC: [u8; 2] = *b"tR";
+/// Version of the batch format
+const BATCH_VERSION: u8 = 10;
+/// Flag marking a deflate compressed payload
+const FLAG_COMPRESSED: u8 = 0b0000_0001;
+/// Size of the batch header: magic, version, flags and event count
+const BATCH_HEADER_LEN: usize = 8;
+/// Largest uncompressed payload of a batch which is unpacked
+pub const MAX_PAYLOAD_LEN: usize = 8 * 1024 * 1024;
+/// Smallest encoded record, the tag of its variant
+const MIN_RECORD_LEN: usize = 4;
+
+/// Reason why a record could not be batched or a batch could not be unpacked
+#[derive(Debug)]
+pub enum BatchError {
+    /// The record could not be encoded, it is not part of the batch
+    Serialize(bincode::Error),
+    /// The bytes do not start with a batch header
+    MissingHeader,
+    /// The batch was framed by another version of tRust
+    Version(u8),
+    /// The compressed payload is corrupt
+    Decompress(io::Error),
+    /// The payload inflates to more than MAX_PAYLOAD_LEN bytes
+    TooLarge,
+    /// A record is corrupt or the payload ends before the last record
+    Deserialize(bincode::Error),
+    /// The encoded length of a record exceeds the payload
+    RecordLength,
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BatchError::Serialize(err) => write!(f, "unable to serialize record: {}", err),
+            BatchError::MissingHeader => write!(f, "missing batch header"),
+            BatchError::Version(version) => write!(f, "unknown batch version {}", version),
+            BatchError::Decompress(err) => write!(f, "unable to decompress batch: {}", err),
+            BatchError::TooLarge => write!(f, "batch exceeds {} bytes", MAX_PAYLOAD_LEN),
+            BatchError::Deserialize(err) => write!(f, "unable to deserialize record: {}", err),
+            BatchError::RecordLength => write!(f, "unable to deserialize record: unknown length"),
+        }
+    }
+}
+
+impl Error for BatchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BatchError::Serialize(err) | BatchError::Deserialize(err) => Some(err),
+            BatchError::Decompress(err) => Some(err),
+            BatchError::MissingHeader
+            | BatchError::Version(_)
+            | BatchError::TooLarge
+            | BatchError::RecordLength => None,
         }
     }
 }
 
+/// Collects serialized records and frames them as one batch.
+///
+/// Batch format: 2 magic bytes, 1 version byte, 1 flag byte, the number of records
+/// as u32 (little endian), followed by the bincode encoded records. If the compressed
+/// flag is set the records are deflate compressed.
+#[derive(Default)]
+pub struct BatchBuilder {
+    payload: Vec<u8>,
+    count: u32,
+}
+
+impl BatchBuilder {
+    /// Constructs new empty BatchBuilder
+    pub fn new() -> BatchBuilder {
+        BatchBuilder::default()
+    }
+
+    /// Appends site definition to the batch
+    pub fn push_site(&mut self, static_data: &StaticData) -> Result<(), BatchError> {
+        self.push(&RecordRef::Site(static_data))
+    }
+
+    /// Appends event to the batch
+    pub fn push_event(&mut self, dynamic_data: &DynData, site_id: u64) -> Result<(), BatchError> {
+        self.push(&RecordRef::Event(dynamic_data, site_id))
+    }
+
+    /// Appends stream summary to the batch
+    pub fn push_summary(&mut self, origin: &Origin, summary: &Summary) -> Result<(), BatchError> {
+        self.push(&RecordRef::Summary(origin, summary))
+    }
+
+    /// Appends thread identity to the batch
+    pub fn push_thread(
+        &mut self,
+        origin: &Origin,
+        thread_info: &ThreadInfo,
+    ) -> Result<(), BatchError> {
+        self.push(&RecordRef::Thread(origin, thread_info))
+    }
+
+    /// Appends probe cost of the process to the batch
+    pub fn push_calibration(
+        &mut self,
+        origin: &Origin,
+        calibration: &Calibration,
+    ) -> Result<(), BatchError> {
+        self.push(&RecordRef::Calibration(origin, calibration))
+    }
+
+    /// Appends settings of the process to the batch
+    pub fn push_process(
+        &mut self,
+        origin: &Origin,
+        process_info: &ProcessInfo,
+    ) -> Result<(), BatchError> {
+        self.push(&RecordRef::Process(origin, process_info))
+    }
+
+    /// Appends record to the batch. A record which fails to serialize leaves no bytes behind
+    fn push(&mut self, record: &RecordRef) -> Result<(), BatchError> {
+        let len = self.payload.len();
+        match bincode::serialize_into(&mut self.payload, record) {
+            Ok(()) => {
+                self.count += 1;
+                Ok(())
+            }
+            Err(err) => {
+                self.payload.truncate(len);
+                Err(BatchError::Serialize(err))
+            }
+        }
+    }
+
+    /// Number of records in the batch
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    /// Checks if the batch contains no records
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Uncompressed size of the records in bytes
+    pub fn payload_len(&self) -> usize {
+        self.payload.len()
+    }
+
+    /// Frames the batch and resets the builder. Compresses the payload if that saves space,
+    /// it is sent uncompressed if compression fails
+    pub fn finish(&mut self) -> Vec<u8> {
+        let payload = std::mem::replace(&mut self.payload, Vec::new());
+        let count = std::mem::replace(&mut self.count, 0);
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        let compressed = match encoder.write_all(&payload).and_then(|_| encoder.finish()) {
+            Ok(compressed) if compressed.len() < payload.len() => Some(compressed),
+            Ok(_) | Err(_) => None,
+        };
+
+        let mut batch = Vec::with_capacity(BATCH_HEADER_LEN + payload.len());
+        batch.extend_from_slice(&BATCH_MAGIC);
+        batch.push(BATCH_VERSION);
+        match compressed {
+            Some(compressed) => {
+                batch.push(FLAG_COMPRESSED);
+                batch.extend_from_slice(&count.to_le_bytes());
+                batch.extend_from_slice(&compressed);
+            }
+            None => {
+                batch.push(0);
+                batch.extend_from_slice(&count.to_le_bytes());
+                batch.extend_from_slice(&payload);
+            }
+        }
+        batch
+    }
+}
+
+/// Checks if the bytes start with a batch header
+pub fn is_batch(bytes: &[u8]) -> bool {
+    bytes.len() >= BATCH_HEADER_LEN && bytes[..2] == BATCH_MAGIC
+}
+
+/// Unpacks all records of a batch
+pub fn from_batch(bytes: &[u8]) -> Result<Vec<Record>, BatchError> {
+    if !is_batch(bytes) {
+        return Err(BatchError::MissingHeader);
+    }
+    if bytes[2] != BATCH_VERSION {
+        return Err(BatchError::Version(bytes[2]));
+    }
+    let mut count_bytes = [0; 4];
+    count_bytes.copy_from_slice(&bytes[4..BATCH_HEADER_LEN]);
+    let count = u32::from_le_bytes(count_bytes);
+
+    let payload = if bytes[3] & FLAG_COMPRESSED != 0 {
+        let mut decompressed = Vec::new();
+        // Inflates at most one byte more than allowed
+        let mut decoder =
+            DeflateDecoder::new(&bytes[BATCH_HEADER_LEN..]).take(MAX_PAYLOAD_LEN as u64 + 1);
+        decoder
+            .read_to_end(&mut decompressed)
+            .map_err(BatchError::Decompress)?;
+        if decompressed.len() > MAX_PAYLOAD_LEN {
+            return Err(BatchError::TooLarge);
+        }
+        decompressed
+    } else {
+        bytes[BATCH_HEADER_LEN..].to_vec()
+    };
+
+    // The count is not trusted, the payload can not hold more records than this
+    let mut records = Vec::with_capacity((count as usize).min(payload.len() / MIN_RECORD_LEN));
+    let mut rest = payload.as_slice();
+    for _ in 0..count {
+        // Decoding from a slice checks every length against the remaining bytes
+        let record = bincode::deserialize::<Record>(rest).map_err(BatchError::Deserialize)?;
+        let len = bincode::serialized_size(&record).ok();
+        rest = len
+            .and_then(|len| rest.get(len as usize..))
+            .ok_or(BatchError::RecordLength)?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn batch_round_trip() {
+        let mut builder = BatchBuilder::new();
+        let static_data = StaticData::new(
+            "crossbeam_channel::bounded",
+            "BEGIN",
+            3,
+            "src/main.rs",
+            7,
+            7,
+        );
+        builder.push_site(&static_data).unwrap();
+        let origin = Origin::new("127.0.0.1", "run", 1);
+        builder
+            .push_thread(&origin, &ThreadInfo::default())
+            .unwrap();
+        let mut dyn_data = DynData::new(1);
+        for seq in 1..=100 {
+            dyn_data.update(
+                seq * 10,
+                Some(Channel {
+                    id: 7,
+                    op: ChannelOp::Send,
+                    seq,
+                }),
+                None,
+            );
+            builder.push_event(&dyn_data, static_data.site_id).unwrap();
+        }
+        assert_eq!(builder.len(), 102);
+
+        let batch = builder.finish();
+        assert!(builder.is_empty());
+        assert!(is_batch(&batch));
+        // Repeating records compress well
+        assert_ne!(batch[3] & FLAG_COMPRESSED, 0);
+
+        let records = from_batch(&batch).unwrap();
+        assert_eq!(records.len(), 102);
+        match &records[0] {
+            Record::Site(site) => {
+                assert_eq!(site.site_id, static_data.site_id);
+                assert_eq!(site.absolute_path, "crossbeam_channel::bounded");
+            }
+            record => panic!("unexpected record {:?}", record),
+        }
+        match &records[1] {
+            Record::Thread(thread_origin, _) => assert_eq!(thread_origin, &origin),
+            record => panic!("unexpected record {:?}", record),
+        }
+        match &records[43] {
+            Record::Event(dyn_data, site_id) => {
+                assert_eq!(*site_id, static_data.site_id);
+                assert_eq!((dyn_data.pid, dyn_data.thread_id), (origin.pid, 1));
+                assert_eq!(dyn_data.counter, 42);
+                assert_eq!(dyn_data.system_time, 420);
+                assert_eq!(
+                    dyn_data.channel,
+                    Some(Channel {
+                        id: 7,
+                        op: ChannelOp::Send,
+                        seq: 42,
+                    })
+                );
+            }
+            record => panic!("unexpected record {:?}", record),
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_batches() {
+        let mut header = BatchBuilder::new().finish();
+        // Claims the largest possible number of records
+        header[4..BATCH_HEADER_LEN].copy_from_slice(&[0xff; 4]);
+        assert!(from_batch(&header).is_err());
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&vec![0; MAX_PAYLOAD_LEN + 1]).unwrap();
+        let mut batch = header[..BATCH_HEADER_LEN].to_vec();
+        batch[3] = FLAG_COMPRESSED;
+        batch.extend_from_slice(&encoder.finish().unwrap());
+        assert!(from_batch(&batch).is_err());
+    }
+
+    #[test]
+    fn site_id_is_stable() {
+        let begin = StaticData::new("send", "BEGIN", 5, "src/main.rs", 40, 40);
+        let end = StaticData::new("send", "END", 5, "src/main.rs", 40, 40);
+        assert_eq!(
+            begin.site_id,
+            site_id("send", "BEGIN", 5, "src/main.rs", 40, 40)
+        );
+        assert_ne!(begin.site_id, end.site_id);
+    }
 }
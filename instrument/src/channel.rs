@@ -0,0 +1,256 @@
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use instdata::{Channel, ChannelOp};
+
+/// Id of the next wrapped channel within the process
+static NEXT_CHANNEL_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identity and message counters of a channel, shared by all its handles
+#[derive(Debug)]
+pub struct ChannelState {
+    id: u64,
+    sends: AtomicU64,
+    recvs: AtomicU64,
+}
+
+impl ChannelState {
+    fn new() -> ChannelState {
+        ChannelState {
+            id: NEXT_CHANNEL_ID.fetch_add(1, Ordering::Relaxed),
+            sends: AtomicU64::new(0),
+            recvs: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts the operation on the channel and returns its sequence number
+    pub(crate) fn next_message(&self, op: ChannelOp) -> Channel {
+        let count = match op {
+            ChannelOp::Send => &self.sends,
+            ChannelOp::Recv => &self.recvs,
+        };
+        Channel {
+            id: self.id,
+            op,
+            seq: count.fetch_add(1, Ordering::Relaxed) + 1,
+        }
+    }
+}
+
+/// Wraps both ends returned by a channel constructor
+/// Inserted around `std::sync::mpsc` and `crossbeam_channel` constructors
+pub fn channel<S, R>(handles: (S, R)) -> (Sender<S>, Receiver<R>) {
+    let state = Arc::new(ChannelState::new());
+    (
+        Sender {
+            handle: handles.0,
+            state: state.clone(),
+        },
+        Receiver {
+            handle: handles.1,
+            state,
+        },
+    )
+}
+
+/// Wraps a receiver returned by a constructor without sending end, e.g. `crossbeam_channel::tick`
+/// Inserted around these constructors
+pub fn receiver<R>(handle: R) -> Receiver<R> {
+    Receiver {
+        handle,
+        state: Arc::new(ChannelState::new()),
+    }
+}
+
+/// Sending end of a wrapped channel. Dereferences to the original sender
+pub struct Sender<S> {
+    handle: S,
+    state: Arc<ChannelState>,
+}
+
+/// Receiving end of a wrapped channel. Dereferences to the original receiver
+pub struct Receiver<R> {
+    handle: R,
+    state: Arc<ChannelState>,
+}
+
+impl<S> Sender<S> {
+    /// Original sender, its messages are no longer matched
+    pub fn into_inner(self) -> S {
+        self.handle
+    }
+}
+
+impl<R> Receiver<R> {
+    /// Original receiver, its messages are no longer matched
+    pub fn into_inner(self) -> R {
+        self.handle
+    }
+}
+
+impl<S> Deref for Sender<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.handle
+    }
+}
+
+impl<R> Deref for Receiver<R> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        &self.handle
+    }
+}
+
+impl<S: Clone> Clone for Sender<S> {
+    fn clone(&self) -> Sender<S> {
+        Sender {
+            handle: self.handle.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<R: Clone> Clone for Receiver<R> {
+    fn clone(&self) -> Receiver<R> {
+        Receiver {
+            handle: self.handle.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for Sender<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.handle.fmt(f)
+    }
+}
+
+impl<R: fmt::Debug> fmt::Debug for Receiver<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.handle.fmt(f)
+    }
+}
+
+impl<R: IntoIterator> IntoIterator for Receiver<R> {
+    type Item = R::Item;
+    type IntoIter = R::IntoIter;
+
+    fn into_iter(self) -> R::IntoIter {
+        self.handle.into_iter()
+    }
+}
+
+impl<'a, R> IntoIterator for &'a Receiver<R>
+where
+    &'a R: IntoIterator,
+{
+    type Item = <&'a R as IntoIterator>::Item;
+    type IntoIter = <&'a R as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        (&self.handle).into_iter()
+    }
+}
+
+/// Finds the channel of the value a send or recv is called on:
+/// `instrument::ChannelProbe(&handle).channel()`.
+/// Wrapped handles, also behind references, have a channel. Every other value
+/// dereferences to `NoChannel`
+pub struct ChannelProbe<'a, H>(pub &'a H);
+
+/// Value which is no wrapped channel handle
+pub struct NoChannel;
+
+impl NoChannel {
+    pub fn channel(&self) -> Option<&'static ChannelState> {
+        None
+    }
+}
+
+impl<'a, H> Deref for ChannelProbe<'a, H> {
+    type Target = NoChannel;
+
+    fn deref(&self) -> &NoChannel {
+        &NoChannel
+    }
+}
+
+impl<'a, S> ChannelProbe<'a, Sender<S>> {
+    pub fn channel(&self) -> Option<&'a ChannelState> {
+        Some(&self.0.state)
+    }
+}
+
+impl<'a, S> ChannelProbe<'a, &Sender<S>> {
+    pub fn channel(&self) -> Option<&'a ChannelState> {
+        Some(&self.0.state)
+    }
+}
+
+impl<'a, S> ChannelProbe<'a, &&Sender<S>> {
+    pub fn channel(&self) -> Option<&'a ChannelState> {
+        Some(&self.0.state)
+    }
+}
+
+impl<'a, R> ChannelProbe<'a, Receiver<R>> {
+    pub fn channel(&self) -> Option<&'a ChannelState> {
+        Some(&self.0.state)
+    }
+}
+
+impl<'a, R> ChannelProbe<'a, &Receiver<R>> {
+    pub fn channel(&self) -> Option<&'a ChannelState> {
+        Some(&self.0.state)
+    }
+}
+
+impl<'a, R> ChannelProbe<'a, &&Receiver<R>> {
+    pub fn channel(&self) -> Option<&'a ChannelState> {
+        Some(&self.0.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn wrapped_handles_number_their_messages() {
+        let (sender, receiver) = channel(mpsc::channel::<u128>());
+        let (other_sender, _other_receiver) = channel(mpsc::sync_channel::<u128>(1));
+        let cloned = sender.clone();
+        let sender_ref = &cloned;
+
+        let sent = ChannelProbe(&sender)
+            .channel()
+            .unwrap()
+            .next_message(ChannelOp::Send);
+        let sent_again = ChannelProbe(&sender_ref)
+            .channel()
+            .unwrap()
+            .next_message(ChannelOp::Send);
+        let received = ChannelProbe(&&receiver)
+            .channel()
+            .unwrap()
+            .next_message(ChannelOp::Recv);
+        let other = ChannelProbe(&other_sender)
+            .channel()
+            .unwrap()
+            .next_message(ChannelOp::Send);
+        assert_eq!((sent.id, sent.seq), (received.id, 1));
+        assert_eq!((sent_again.id, sent_again.seq), (sent.id, 2));
+        assert_eq!(received.seq, 1);
+        assert_ne!(other.id, sent.id);
+
+        sender.send(7).unwrap();
+        assert_eq!(receiver.recv(), Ok(7));
+        assert!(ChannelProbe(&sender.into_inner()).channel().is_none());
+    }
+}
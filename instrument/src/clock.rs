@@ -0,0 +1,146 @@
+use std::thread;
+use std::time::Duration;
+
+use crate::configuration::ClockKind;
+
+/// Time the TSC is compared with the precise time when calibrating
+const TSC_CALIBRATION: Duration = Duration::from_millis(20);
+
+/// Source of the time stamps, read by the probes on the instrumented thread.
+/// All sources count nanoseconds and start close to `time::precise_time_ns`, but drift apart
+/// from it: CLOCK_MONOTONIC_RAW is not slewed by NTP and the TSC rate is only calibrated
+/// once. Time stamps of different sources must not be compared
+#[derive(Clone, Copy, Debug)]
+pub enum Clock {
+    /// `time::precise_time_ns`, CLOCK_MONOTONIC on Linux
+    PreciseTime,
+    /// CLOCK_MONOTONIC_RAW, not slewed by NTP
+    MonotonicRaw,
+    /// Time stamp counter of the CPU, converted with a calibrated rate
+    Tsc(TscCalibration),
+}
+
+/// Conversion of time stamp counter ticks to nanoseconds
+#[derive(Clone, Copy, Debug)]
+pub struct TscCalibration {
+    base_ticks: u64,
+    base_ns: u64,
+    ns_per_tick: f64,
+}
+
+impl Clock {
+    /// Sets up the configured source. Sources not available on this machine fall back to
+    /// the precise time
+    pub fn new(kind: ClockKind) -> Clock {
+        match kind {
+            ClockKind::PreciseTime => Clock::PreciseTime,
+            ClockKind::MonotonicRaw => {
+                if monotonic_raw_ns().is_some() {
+                    Clock::MonotonicRaw
+                } else {
+                    log!(
+                        Warn,
+                        "CLOCK_MONOTONIC_RAW is not available, using precise time"
+                    );
+                    Clock::PreciseTime
+                }
+            }
+            ClockKind::Tsc => match TscCalibration::calibrate() {
+                Some(calibration) => Clock::Tsc(calibration),
+                None => {
+                    log!(Warn, "No invariant time stamp counter, using precise time");
+                    Clock::PreciseTime
+                }
+            },
+        }
+    }
+
+    /// Current time in nanoseconds
+    #[inline]
+    pub fn now(&self) -> u64 {
+        match self {
+            Clock::PreciseTime => time::precise_time_ns(),
+            Clock::MonotonicRaw => monotonic_raw_ns().unwrap_or_else(time::precise_time_ns),
+            Clock::Tsc(calibration) => calibration.to_ns(read_tsc().unwrap_or(0)),
+        }
+    }
+}
+
+impl TscCalibration {
+    /// Measures the rate of the time stamp counter against the precise time.
+    /// Returns None if the counter does not tick at a constant rate
+    fn calibrate() -> Option<TscCalibration> {
+        if !has_invariant_tsc() {
+            return None;
+        }
+        let start_ns = time::precise_time_ns();
+        let start_ticks = read_tsc()?;
+        thread::sleep(TSC_CALIBRATION);
+        let end_ns = time::precise_time_ns();
+        let end_ticks = read_tsc()?;
+        if end_ticks <= start_ticks {
+            return None;
+        }
+        Some(TscCalibration {
+            base_ticks: end_ticks,
+            base_ns: end_ns,
+            ns_per_tick: (end_ns - start_ns) as f64 / (end_ticks - start_ticks) as f64,
+        })
+    }
+
+    /// Converts a counter value to nanoseconds
+    #[inline]
+    fn to_ns(self, ticks: u64) -> u64 {
+        let elapsed = ticks.wrapping_sub(self.base_ticks) as i64 as f64 * self.ns_per_tick;
+        (self.base_ns as f64 + elapsed) as u64
+    }
+}
+
+/// CLOCK_MONOTONIC_RAW in nanoseconds
+#[cfg(target_os = "linux")]
+fn monotonic_raw_ns() -> Option<u64> {
+    let mut spec = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_RAW, &mut spec) } == 0 {
+        Some(spec.tv_sec as u64 * 1_000_000_000 + spec.tv_nsec as u64)
+    } else {
+        None
+    }
+}
+
+/// CLOCK_MONOTONIC_RAW in nanoseconds
+#[cfg(not(target_os = "linux"))]
+fn monotonic_raw_ns() -> Option<u64> {
+    None
+}
+
+/// Current value of the time stamp counter
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn read_tsc() -> Option<u64> {
+    Some(unsafe { std::arch::x86_64::_rdtsc() })
+}
+
+/// Current value of the time stamp counter
+#[cfg(not(target_arch = "x86_64"))]
+#[inline]
+fn read_tsc() -> Option<u64> {
+    None
+}
+
+/// Whether the time stamp counter ticks at a constant rate in all power states
+#[cfg(target_arch = "x86_64")]
+// __cpuid is only safe to call since Rust 1.87
+#[allow(unused_unsafe)]
+fn has_invariant_tsc() -> bool {
+    use std::arch::x86_64::__cpuid;
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0 }
+}
+
+/// Whether the time stamp counter ticks at a constant rate in all power states
+#[cfg(not(target_arch = "x86_64"))]
+fn has_invariant_tsc() -> bool {
+    false
+}
@@ -0,0 +1,62 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::AddrParseError;
+use std::path::PathBuf;
+
+/// Reason why the instrumentation could not be set up. The instrumentation is disabled then,
+/// all probes are no-ops
+#[derive(Debug)]
+pub enum InstError {
+    /// No config file was found at the searched paths
+    NoConfigFile(Vec<PathBuf>),
+    /// The config file could not be read
+    ReadConfig(PathBuf, io::Error),
+    /// The config file is no valid TOML or misses keys
+    ParseConfig(toml::de::Error),
+    /// A special_behaviour rule is unknown
+    Behaviour(String),
+    /// A config key has a value outside of its valid range
+    Setting(&'static str, String),
+    /// collector_ip is no ip address
+    CollectorIp(String, AddrParseError),
+    /// An environment variable overriding the config has an invalid value
+    EnvVar(&'static str, String),
+    /// The connection to the collector could not be opened
+    Connection(io::Error),
+}
+
+impl fmt::Display for InstError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstError::NoConfigFile(paths) => {
+                write!(f, "no config file found, searched {:?}", paths)
+            }
+            InstError::ReadConfig(path, err) => {
+                write!(f, "unable to read config file {:?}: {}", path, err)
+            }
+            InstError::ParseConfig(err) => write!(f, "unable to deserialize config: {}", err),
+            InstError::Behaviour(err) => write!(f, "unable to parse special behaviour: {}", err),
+            InstError::Setting(key, err) => write!(f, "invalid {}: {}", key, err),
+            InstError::CollectorIp(ip, err) => {
+                write!(f, "unable to parse collector ip '{}': {}", ip, err)
+            }
+            InstError::EnvVar(name, value) => write!(f, "invalid value '{}' of {}", value, name),
+            InstError::Connection(err) => write!(f, "unable to open connection: {}", err),
+        }
+    }
+}
+
+impl Error for InstError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InstError::NoConfigFile(_)
+            | InstError::Behaviour(_)
+            | InstError::Setting(..)
+            | InstError::EnvVar(..) => None,
+            InstError::ReadConfig(_, err) | InstError::Connection(err) => Some(err),
+            InstError::ParseConfig(err) => Some(err),
+            InstError::CollectorIp(_, err) => Some(err),
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use instdata::{
+    BatchBuilder, BatchError, Calibration, Channel, DynData, Origin, ProcessInfo, Spawn, Summary,
+    ThreadInfo,
+};
+
+use crate::configuration::LocalConfig;
+use crate::error::InstError;
+use crate::ring::{self, Consumer, Producer};
+use crate::transport::Connection;
+use crate::Site;
+
+/// Event handed from a probe to the exporter
+pub struct ProbeEvent {
+    pub site: &'static Site,
+    /// Time stamp taken by the probe
+    pub time_stamp: u64,
+    pub channel: Option<Channel>,
+    pub spawn: Option<Spawn>,
+}
+
+/// Process-wide exporter. One thread drains the ring buffers of all instrumented threads
+/// and sends their events over one connection
+pub struct Exporter {
+    shared: Arc<Shared>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    buffer_capacity: usize,
+}
+
+/// State shared with the exporter thread
+#[derive(Default)]
+struct Shared {
+    /// Streams registered since the exporter thread last looked
+    new_streams: Mutex<Vec<Stream>>,
+    /// Probe costs waiting to be sent
+    calibrations: Mutex<Vec<(Origin, Calibration)>>,
+    shut_down: AtomicBool,
+}
+
+impl Exporter {
+    /// Opens the connection and starts the exporter thread
+    pub fn start(local_config: &LocalConfig) -> Result<Exporter, InstError> {
+        let shared = Arc::new(Shared::default());
+        let export_thread = ExportThread::new(shared.clone(), local_config)?;
+        let handle = thread::spawn(move || export_thread.run());
+        Ok(Exporter {
+            shared,
+            thread: Mutex::new(Some(handle)),
+            buffer_capacity: local_config.buffer_capacity,
+        })
+    }
+
+    /// Registers the event stream of an instrumented thread.
+    /// Returns the writing end of the ring buffer of the stream
+    pub fn register(&self, origin: Origin, thread_info: ThreadInfo) -> Producer<ProbeEvent> {
+        let (mut producer, consumer) = ring::ring(self.buffer_capacity);
+        if let Some(handle) = self.thread.lock().unwrap().as_ref() {
+            producer.set_consumer_thread(handle.thread().clone());
+        }
+        self.shared.new_streams.lock().unwrap().push(Stream {
+            consumer,
+            dynamic_data: DynData::new(origin.thread_id),
+            origin,
+            thread_info,
+            summary: Summary::default(),
+            batch_events: 0,
+        });
+        producer
+    }
+
+    /// Sends the probe cost of the process with the next batch
+    pub fn send_calibration(&self, origin: Origin, calibration: Calibration) {
+        self.shared
+            .calibrations
+            .lock()
+            .unwrap()
+            .push((origin, calibration));
+    }
+
+    /// Stops the exporter thread after it drained all buffers and sent the summaries of all
+    /// streams. Events of threads which are still running are dropped afterwards
+    pub fn shut_down(&self) {
+        let handle = match self.thread.lock().unwrap().take() {
+            Some(handle) => handle,
+            None => return,
+        };
+        self.shared.shut_down.store(true, Ordering::Release);
+        handle.thread().unpark();
+        if let Some(err) = handle.join().err() {
+            log!(Error, "Unable to join exporter thread: {:?}", err);
+        }
+    }
+}
+
+/// Event stream of an instrumented thread
+struct Stream {
+    /// Reading end of the ring buffer of the thread
+    consumer: Consumer<ProbeEvent>,
+    /// Dynamic data
+    dynamic_data: DynData,
+    /// Machine and run of the stream, sent with its thread info
+    origin: Origin,
+    /// Identity of the instrumented thread, sent in every batch with its events
+    thread_info: ThreadInfo,
+    /// Totals of the stream for loss accounting
+    summary: Summary,
+    /// Number of events of the stream in the current batch
+    batch_events: u64,
+}
+
+/// Data structure for the exporter thread
+struct ExportThread {
+    shared: Arc<Shared>,
+    streams: Vec<Stream>,
+    /// Connection to the collector
+    connection: Connection,
+    /// Events waiting to be sent
+    batch: BatchBuilder,
+    /// Sites already defined in the current batch
+    batch_sites: HashSet<u64>,
+    /// Uncompressed batch size in bytes which triggers a flush
+    batch_size: usize,
+    /// Maximal time an event waits in the batch
+    flush_interval: Duration,
+}
+
+impl ExportThread {
+    /// Constructs a new ExportThread. The settings of the process travel in the first batch
+    fn new(shared: Arc<Shared>, local_config: &LocalConfig) -> Result<ExportThread, InstError> {
+        let mut batch = BatchBuilder::new();
+        batched(batch.push_process(
+            &Origin::new(&local_config.machine_id, &local_config.run_id, 0),
+            &ProcessInfo {
+                config: local_config.config_text.clone(),
+            },
+        ));
+        Ok(ExportThread {
+            shared,
+            streams: Vec::new(),
+            connection: Connection::open(local_config).map_err(InstError::Connection)?,
+            batch,
+            batch_sites: HashSet::new(),
+            batch_size: local_config.batch_size,
+            flush_interval: local_config.flush_interval,
+        })
+    }
+
+    /// Drains the ring buffers, updates dynamic data with the time stamps of the probes
+    /// and adds data to the batch. The batch is sent to the Collector when full
+    /// or when the flush interval elapsed
+    fn run(mut self) {
+        log!(Debug, "Exporter is running...");
+        let mut last_flush = Instant::now();
+        loop {
+            // Checked before draining, threads pushed everything before they ended
+            let shut_down = self.shared.shut_down.load(Ordering::Acquire);
+            self.streams
+                .extend(self.shared.new_streams.lock().unwrap().drain(..));
+            for (origin, calibration) in self.shared.calibrations.lock().unwrap().drain(..) {
+                batched(self.batch.push_calibration(&origin, &calibration));
+            }
+            let mut index = 0;
+            while index < self.streams.len() {
+                let ended = self.streams[index].consumer.is_orphaned();
+                while let Some(event) = self.streams[index].consumer.pop() {
+                    self.add_inst(index, event);
+                    if self.batch.payload_len() >= self.batch_size {
+                        self.flush();
+                        last_flush = Instant::now();
+                    }
+                }
+                if ended {
+                    self.finish_stream(index);
+                } else {
+                    index += 1;
+                }
+            }
+            if shut_down {
+                break;
+            }
+            // Sleeps until a buffer is half full, the process ends or the batch is due
+            match self.flush_interval.checked_sub(last_flush.elapsed()) {
+                Some(timeout) if timeout > Duration::from_millis(0) => {
+                    thread::park_timeout(timeout)
+                }
+                _ => {
+                    self.flush();
+                    last_flush = Instant::now();
+                }
+            }
+        }
+        while !self.streams.is_empty() {
+            self.finish_stream(self.streams.len() - 1);
+        }
+        self.flush();
+        log!(Debug, "Terminating.");
+    }
+
+    /// Adds data to the batch. Every batch identifies the threads of its events and defines
+    /// the sites its events refer to, so the collector can resolve them even if other batches
+    /// got lost
+    fn add_inst(&mut self, index: usize, event: ProbeEvent) {
+        let stream = &mut self.streams[index];
+        // Update dynamic data
+        stream
+            .dynamic_data
+            .update(event.time_stamp, event.channel, event.spawn);
+        if stream.batch_events == 0 {
+            batched(self.batch.push_thread(&stream.origin, &stream.thread_info));
+        }
+        if !self.batch_sites.contains(&event.site.id)
+            && batched(self.batch.push_site(&event.site.to_static_data()))
+        {
+            self.batch_sites.insert(event.site.id);
+        }
+        // An event which is not batched counts, the collector records it as a gap
+        batched(self.batch.push_event(&stream.dynamic_data, event.site.id));
+        stream.batch_events += 1;
+        stream.summary.events += 1;
+        log!(
+            Trace,
+            "Batching item with {:?} {:?}",
+            stream.dynamic_data,
+            event.site
+        );
+    }
+
+    /// Removes the stream of a thread which ended. The totals of the stream travel
+    /// in the next batch, which they count
+    fn finish_stream(&mut self, index: usize) {
+        if self.streams[index].batch_events > 0 {
+            self.flush();
+        }
+        let mut stream = self.streams.swap_remove(index);
+        stream.summary.batches += 1;
+        stream.summary.dropped_events = stream.consumer.dropped();
+        batched(self.batch.push_summary(&stream.origin, &stream.summary));
+    }
+
+    /// Sends the batch to Collector
+    fn flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let batch = self.batch.finish();
+        self.batch_sites.clear();
+        let sent = self.connection.send_batch(&batch);
+        for stream in &mut self.streams {
+            if stream.batch_events == 0 {
+                continue;
+            }
+            stream.summary.batches += 1;
+            if !sent {
+                stream.summary.failed_batches += 1;
+                stream.summary.failed_events += stream.batch_events;
+            }
+            stream.batch_events = 0;
+        }
+    }
+}
+
+/// Whether the record was added to the batch, logs the error otherwise
+fn batched(result: Result<(), BatchError>) -> bool {
+    if let Err(err) = &result {
+        log!(Error, "Unable to batch record: {}", err);
+    }
+    result.is_ok()
+}
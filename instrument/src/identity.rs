@@ -0,0 +1,83 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::{SocketAddr, UdpSocket};
+use std::process;
+
+/// Identifies the current boot of a Linux machine
+const BOOT_ID_FILE: &str = "/proc/sys/kernel/random/boot_id";
+
+/// Id of the machine: the host name and the beginning of the boot id. Without host name
+/// the address of the interface towards the collector is used
+pub fn machine_id(collector_addr: SocketAddr) -> String {
+    match (host_name(), boot_id()) {
+        (Some(host_name), Some(boot_id)) => format!("{}-{}", host_name, boot_id),
+        (Some(host_name), None) => host_name,
+        (None, _) => interface_addr(collector_addr).unwrap_or_else(|| String::from("unknown")),
+    }
+}
+
+/// Random UUID (version 4) identifying the run of the process
+pub fn run_id() -> String {
+    let mut bytes = [0u8; 16];
+    let random = File::open("/dev/urandom").and_then(|mut file| file.read_exact(&mut bytes));
+    if random.is_err() {
+        // Unique enough without a random source
+        let seed = u128::from(time::precise_time_ns()) << 32 | u128::from(process::id());
+        bytes = seed.to_be_bytes();
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Host name of the machine
+#[cfg(unix)]
+fn host_name() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } != 0 {
+        return None;
+    }
+    let length = buffer.iter().position(|&byte| byte == 0)?;
+    let name = String::from_utf8_lossy(&buffer[..length]).into_owned();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Host name of the machine
+#[cfg(not(unix))]
+fn host_name() -> Option<String> {
+    None
+}
+
+/// First group of the boot id, changes with every boot
+fn boot_id() -> Option<String> {
+    let boot_id = fs::read_to_string(BOOT_ID_FILE).ok()?;
+    boot_id
+        .trim()
+        .split('-')
+        .next()
+        .filter(|group| !group.is_empty())
+        .map(String::from)
+}
+
+/// Address of the interface packets to the collector leave from. Connecting a UDP socket
+/// sends nothing
+fn interface_addr(collector_addr: SocketAddr) -> Option<String> {
+    let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).ok()?;
+    socket.connect(collector_addr).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip().to_string())
+}
@@ -2,239 +2,523 @@
 //!
 //! `instrumentation` provides instrumentation functionalities
 use std::cell::RefCell;
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
-use std::thread::{self, JoinHandle};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
 
 #[macro_use]
 extern crate serde;
-use dirs;
-use mio::net::UdpSocket as MioUdpSocket;
-use state::{LocalStorage, Storage};
+use state::Storage;
 
-use configuration::LocalConfig;
-use instdata::DynData;
+use clock::Clock;
+use configuration::{BufferPolicy, LocalConfig};
+use exporter::{Exporter, ProbeEvent};
+use instdata::{Calibration, Channel, ChannelOp, Origin, Spawn, ThreadInfo};
+use ring::Producer;
+
+#[macro_use]
+mod log;
+mod channel;
+mod clock;
+mod error;
+mod exporter;
+mod identity;
+mod ring;
+mod transport;
 
 // Reexporting
-pub use configuration::read_conf_file;
+pub use channel::{channel, receiver, ChannelProbe, ChannelState, NoChannel, Receiver, Sender};
+pub use configuration::{
+    deserialize_config, locate_conf_file, read_conf_file, read_conf_text, Behaviour,
+};
+pub use error::InstError;
 pub use instdata::StaticData;
 
 /// Global singleton
 pub static INSTRUMENTATION: Storage<GlobalInstrumentation> = Storage::new();
 
-/// Thread-local singleton
-pub static LOCAL_INST: LocalStorage<RefCell<ThreadLocalInst>> = LocalStorage::new();
+thread_local! {
+    /// Thread-local singleton, set by `local_init`. Dropped when the thread exits,
+    /// which ends the event stream of the thread
+    pub static LOCAL_INST: RefCell<Option<ThreadLocalInst>> = RefCell::new(None);
+}
+
+/// Number of the next instrumented thread
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Slots of the scratch ring buffer the calibration probes write into
+const CALIBRATION_RING_CAPACITY: usize = 1024;
+
+/// Site of the probes timed by the calibration
+static CALIBRATION_SITE: Site = Site {
+    id: 0,
+    absolute_path: "instrument::calibrate",
+    description: "CALIBRATION",
+    ast_depth: 0,
+    source_file: "",
+    lines_begin: 0,
+    lines_end: 0,
+};
 
 /// Initializes global instrumentation object
-/// Inserted at beginning of main thread
-pub fn global_init() {
-    INSTRUMENTATION.set(GlobalInstrumentation::init());
+/// Inserted at beginning of main thread with the config the program was compiled with.
+/// If the instrumentation can not be set up, it is disabled with a warning
+pub fn global_init(embedded_config: &str) {
+    match GlobalInstrumentation::init(embedded_config) {
+        Ok(global_inst) => {
+            INSTRUMENTATION.set(global_inst);
+            INSTRUMENTATION.get().calibrate();
+        }
+        Err(err) => log!(Warn, "Instrumentation disabled, {}", err),
+    }
 }
 
-/// Initializes thread-local instrumentation object
+/// Initializes thread-local instrumentation object, once per thread
 /// Inserted at beginning of thread closure
-pub fn local_init() -> JoinHandle<()> {
-    LOCAL_INST.set(|| RefCell::new(ThreadLocalInst::new_empty()));
-    LOCAL_INST
-        .get()
-        .borrow_mut()
-        .init(INSTRUMENTATION.get().local_config.clone())
+pub fn local_init() {
+    let global_inst = match INSTRUMENTATION.try_get() {
+        Some(global_inst) => global_inst,
+        None => return,
+    };
+    // Fails while the thread exits
+    let _ = LOCAL_INST.try_with(|local_inst| {
+        local_inst
+            .borrow_mut()
+            .get_or_insert_with(ThreadLocalInst::new_empty)
+            .init(global_inst)
+    });
 }
 
 /// Instrumentation call
 /// Inserted before and after line os interest
-pub fn instrument(static_data: StaticData) {
-    LOCAL_INST.get().borrow().instrument(static_data);
+pub fn instrument(site: &'static Site) {
+    probe(site, Operation::Call);
+}
+
+/// Instrumentation call starting a send, numbers the message on the channel of wrapped handles
+/// Inserted before send calls instead of `instrument`
+pub fn instrument_send(site: &'static Site, channel: Option<&ChannelState>) {
+    channel_probe(site, channel, ChannelOp::Send);
+}
+
+/// Instrumentation call starting a recv, numbers the message on the channel of wrapped handles
+/// Inserted before recv calls instead of `instrument`
+pub fn instrument_recv(site: &'static Site, channel: Option<&ChannelState>) {
+    channel_probe(site, channel, ChannelOp::Recv);
+}
+
+/// Id of the event stream of the calling thread, 0 if the thread is not instrumented
+/// Inserted before thread-local scopes, the id is moved into the spawned closures
+pub fn current_thread() -> u64 {
+    with_local_inst(|local_inst| local_inst.thread_id).unwrap_or(0)
+}
+
+/// Instrumentation call starting a spawned closure, refers to the spawning call and its thread
+/// Inserted at the beginning of thread-local scopes instead of `instrument`
+pub fn instrument_spawned(site: &'static Site, spawn_site: &'static Site, parent_thread_id: u64) {
+    if parent_thread_id == 0 {
+        probe(site, Operation::Call);
+    } else {
+        probe(
+            site,
+            Operation::Spawned(Spawn {
+                parent_thread_id,
+                site_id: spawn_site.id,
+            }),
+        );
+    }
+}
+
+/// Hands the probe to the thread-local object. Probes are no-ops while the instrumentation
+/// is disabled
+fn probe(site: &'static Site, operation: Operation) {
+    with_local_inst(|local_inst| local_inst.instrument(site, operation));
+}
+
+/// Runs the closure on the thread-local object, None if the thread is not instrumented
+/// or already exiting
+fn with_local_inst<R>(f: impl FnOnce(&mut ThreadLocalInst) -> R) -> Option<R> {
+    LOCAL_INST
+        .try_with(|local_inst| local_inst.borrow_mut().as_mut().map(f))
+        .ok()
+        .and_then(|result| result)
 }
 
-/// Joins the helper thread just before spawned thread ends
-/// Inserted at end of thread closure
-pub fn clean_up(handle: JoinHandle<()>) {
-    LOCAL_INST.get().borrow().signal_finish();
-    if let Some(err) = handle.join().err() {
-        eprintln!("Unable to join helper thread: {:?}", err);
+/// Numbers the operation before the call, also if the probe is disabled,
+/// so sends and receives stay matched
+fn channel_probe(site: &'static Site, channel: Option<&ChannelState>, op: ChannelOp) {
+    match channel {
+        Some(channel) => probe(site, Operation::Channel(channel.next_message(op))),
+        None => probe(site, Operation::Call),
     }
-    println!("{:?} - {}", thread::current().id(), "Join is done");
 }
 
-const CONFIG_FILE: &str = ".rust_inst/instconfig.toml";
+/// Ends the event stream of the thread. Streams of other threads end when their thread exits,
+/// the thread-local object of the main thread is not dropped at the end of the process
+/// Inserted at end of main
+pub fn clean_up() {
+    with_local_inst(ThreadLocalInst::finish);
+}
+
+/// Sends the events of all threads and stops the exporter
+/// Inserted at end of main after `clean_up`
+pub fn global_clean_up() {
+    let global_inst = match INSTRUMENTATION.try_get() {
+        Some(global_inst) => global_inst,
+        None => return,
+    };
+    global_inst.exporter.shut_down();
+    log!(Debug, "Export is done");
+}
+
+/// Thread id of the operating system
+#[cfg(target_os = "linux")]
+fn os_tid() -> Option<u64> {
+    // gettid has no wrapper in older glibc versions
+    Some(unsafe { libc::syscall(libc::SYS_gettid) } as u64)
+}
+
+/// Thread id of the operating system
+#[cfg(not(target_os = "linux"))]
+fn os_tid() -> Option<u64> {
+    None
+}
+
+/// Static data of a probe. The drop-in compiler emits a table of sites for every crate,
+/// so a probe only hands a reference to its site to the runtime
+#[derive(Debug)]
+pub struct Site {
+    pub id: u64,
+    pub absolute_path: &'static str,
+    pub description: &'static str,
+    pub ast_depth: u128,
+    pub source_file: &'static str,
+    pub lines_begin: u128,
+    pub lines_end: u128,
+}
+
+impl Site {
+    /// Converts the site to its owned representation for the collector
+    fn to_static_data(&self) -> StaticData {
+        StaticData {
+            site_id: self.id,
+            absolute_path: String::from(self.absolute_path),
+            description: String::from(self.description),
+            ast_depth: self.ast_depth,
+            source_file: String::from(self.source_file),
+            lines_begin: self.lines_begin,
+            lines_end: self.lines_end,
+        }
+    }
+}
 
 /// Global instrumentation object. Holds static and global dyn data
 pub struct GlobalInstrumentation {
     local_config: LocalConfig,
+    /// Sends the events of all threads
+    exporter: Exporter,
 }
 
 impl GlobalInstrumentation {
     /// Reads config JSON and constructs new GlobalInstrumentation object
-    pub fn init() -> GlobalInstrumentation {
+    pub fn init(embedded_config: &str) -> Result<GlobalInstrumentation, InstError> {
         // Set up global object
-        let global_inst = GlobalInstrumentation::set_up_from_config();
+        let global_inst = GlobalInstrumentation::set_up_from_config(embedded_config)?;
 
-        println!(
-            "{:?} - {}",
-            thread::current().id(),
-            "=====> YOU MADE IT!! <====="
+        log!(
+            Info,
+            "Instrumentation enabled, sending {:?} to {}",
+            global_inst.local_config.transport,
+            global_inst.local_config.collector_addr
         );
         // Return GlobalInstrumentation object
-        global_inst
+        Ok(global_inst)
     }
 
-    /// Reads the config JSON file
-    fn set_up_from_config() -> GlobalInstrumentation {
-        if let Some(mut config_path) = dirs::home_dir() {
-            config_path.push(CONFIG_FILE);
-            let config = read_conf_file(config_path);
-            GlobalInstrumentation {
-                local_config: LocalConfig::new(config),
+    /// Reads the config file. Without config file the embedded config is used, a config file
+    /// which differs from it might not match the probes compiled into the program
+    fn set_up_from_config(embedded_config: &str) -> Result<GlobalInstrumentation, InstError> {
+        let (config_path, config_text) = match locate_conf_file() {
+            Ok(config_path) => {
+                let config_text = read_conf_text(&config_path)?;
+                (Some(config_path), config_text)
             }
-        } else {
-            eprintln!("Unable to locate home dir");
-            panic!();
+            Err(InstError::NoConfigFile(_)) if !embedded_config.is_empty() => {
+                (None, String::from(embedded_config))
+            }
+            Err(err) => return Err(err),
+        };
+        let config = deserialize_config(&config_text)?;
+        log::set_up(&config);
+        match config_path {
+            Some(config_path) => {
+                log!(Info, "Using config file {:?}", config_path);
+                if !embedded_config.is_empty() && config_text != embedded_config {
+                    log!(
+                        Warn,
+                        "Config file {:?} differs from the config the program was compiled with",
+                        config_path
+                    );
+                }
+            }
+            None => log!(Info, "Using embedded config"),
+        }
+        let local_config = LocalConfig::new(config)?;
+        Ok(GlobalInstrumentation {
+            exporter: Exporter::start(&local_config)?,
+            local_config,
+        })
+    }
+
+    /// Times probes writing into a scratch buffer and sends their cost to the collector.
+    /// The thread-local lookup of the probe functions is not included
+    fn calibrate(&self) {
+        let probes = self.local_config.calibration_probes;
+        if probes == 0 {
+            return;
+        }
+        let (producer, consumer) = ring::ring(CALIBRATION_RING_CAPACITY);
+        let mut scratch = ThreadLocalInst::new_empty();
+        scratch.producer = Some(producer);
+        scratch.buffer_policy = BufferPolicy::Drop;
+        scratch.clock = self.local_config.clock;
+        // The probes fill the ring in rounds, draining it is not timed
+        let mut total_ns = 0;
+        let mut remaining = probes;
+        while remaining > 0 {
+            let round = remaining.min(CALIBRATION_RING_CAPACITY as u64);
+            let start = scratch.clock.now();
+            for _ in 0..round {
+                scratch.instrument(&CALIBRATION_SITE, Operation::Call);
+            }
+            total_ns += scratch.clock.now().saturating_sub(start);
+            while consumer.pop().is_some() {}
+            remaining -= round;
+        }
+        self.exporter.send_calibration(
+            Origin::new(&self.local_config.machine_id, &self.local_config.run_id, 0),
+            Calibration { probes, total_ns },
+        );
+    }
+}
+
+/// Descriptions of the probes before a call
+const BEGIN_DESCRIPTIONS: [&str; 3] = ["BEGIN", "LOCAL_BEGIN", "GLOBAL_BEGIN"];
+/// Descriptions of the probes after a call
+const END_DESCRIPTIONS: [&str; 3] = ["END", "LOCAL_END", "GLOBAL_END"];
+
+/// Identifies a call site, its BEGIN and END probes only differ in the description
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct CallSiteKey {
+    absolute_path: &'static str,
+    source_file: &'static str,
+    lines_begin: u128,
+    lines_end: u128,
+    ast_depth: u128,
+}
+
+impl CallSiteKey {
+    fn of(site: &'static Site) -> CallSiteKey {
+        CallSiteKey {
+            absolute_path: site.absolute_path,
+            source_file: site.source_file,
+            lines_begin: site.lines_begin,
+            lines_end: site.lines_end,
+            ast_depth: site.ast_depth,
         }
     }
 }
 
-/// This object is initialized for every thread. It hold connection to helper thread
-/// and signals the helper thread when a intrumentation call occures
+/// Special behaviour state of a call site within a thread
+#[derive(Debug, Default)]
+struct CallSite {
+    /// Number of enabled calls
+    hits: u64,
+    /// Decisions of the calls which began but did not end yet, innermost last
+    open: Vec<bool>,
+}
+
+/// This object is initialized for every thread. It hands the events of the thread
+/// to the exporter through a ring buffer
 pub struct ThreadLocalInst {
-    /// Sending end of the channel used to signal helper thread
-    channel_sender: Option<SyncSender<Message<StaticData>>>,
+    /// Writing end of the ring buffer drained by the exporter
+    producer: Option<Producer<ProbeEvent>>,
+    /// What a probe does when the ring buffer is full
+    buffer_policy: BufferPolicy,
+    /// Special behaviour rules by absolute path
+    special_behaviour: HashMap<String, Vec<Behaviour>>,
+    /// Hits and open calls per call site
+    call_sites: HashMap<CallSiteKey, CallSite>,
+    /// Point in time of the global initialization
+    time_origin: Instant,
+    /// Number of the instrumented thread within the process
+    thread_id: u64,
+    /// Thread id of the operating system the stream was registered on
+    os_tid: Option<u64>,
+    /// Source of the time stamps
+    clock: Clock,
 }
 
 impl ThreadLocalInst {
     /// Consructs empty ThreadLocalInst object
     fn new_empty() -> ThreadLocalInst {
         ThreadLocalInst {
-            channel_sender: None,
+            producer: None,
+            buffer_policy: BufferPolicy::Block,
+            special_behaviour: HashMap::new(),
+            call_sites: HashMap::new(),
+            time_origin: Instant::now(),
+            thread_id: 0,
+            os_tid: None,
+            clock: Clock::PreciseTime,
         }
     }
 
-    /// Spawns helper thread and creates channel
-    /// Return JoinHandle for helper thread
-    fn init(&mut self, local_config: LocalConfig) -> JoinHandle<()> {
-        // Create Channel
-        let (sender, receiver) = sync_channel::<Message<StaticData>>(1);
-        self.channel_sender = Some(sender);
-        let parent_thread = thread::current().id();
-
-        // Spawn new helper_thread and return join-handle
-        thread::spawn(move || {
-            println!("{:?}  -->  {:?}", parent_thread, thread::current().id());
-            // Create InstHelper struct
-            let inst_helper = InstHelper::new(receiver, local_config);
-
-            // Run
-            inst_helper.run();
-        })
+    /// Registers the event stream of the thread with the exporter.
+    /// Threads running several spawned closures keep their first stream,
+    /// an object found on another OS thread registers a new one
+    fn init(&mut self, global_inst: &GlobalInstrumentation) {
+        let os_tid = os_tid();
+        if self.thread_id != 0 && self.os_tid == os_tid {
+            return;
+        }
+        if self.producer.is_some() {
+            self.finish();
+        }
+        let local_config = &global_inst.local_config;
+        self.buffer_policy = local_config.buffer_policy;
+        self.special_behaviour = local_config.special_behaviour.clone();
+        self.time_origin = local_config.time_origin;
+        self.clock = local_config.clock;
+        self.thread_id = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+        self.os_tid = os_tid;
+        let thread_info = ThreadInfo {
+            os_tid,
+            name: thread::current().name().map(String::from),
+        };
+        self.producer = Some(global_inst.exporter.register(
+            Origin::new(
+                &local_config.machine_id,
+                &local_config.run_id,
+                self.thread_id,
+            ),
+            thread_info,
+        ));
     }
 
     /// Actual intrumentation call. This method is inserted bevore and after every line of interest.
-    /// Signals helper thread
-    pub fn instrument(&self, static_data: StaticData) {
-        println!(
-            "{:?} - {} with {:?}",
-            thread::current().id(),
-            "Instrumentation call",
-            &static_data
-        );
-        if let Some(channel) = self.channel_sender.as_ref() {
-            match channel.send(Message::Instrument(static_data)) {
-                Ok(()) => {}
-                Err(e) => eprintln!(
-                    "{:?} Unable to send instrumentation data down channel: {}",
-                    thread::current().id(),
-                    e
-                ),
-            }
+    /// Hands the event to the exporter
+    pub fn instrument(&mut self, site: &'static Site, operation: Operation) {
+        // Taken first, so the time stamp does not include the work of the probe or the exporter
+        let time_stamp = self.clock.now();
+        let (channel, spawn) = match operation {
+            Operation::Call => (None, None),
+            Operation::Channel(channel) => (Some(channel), None),
+            Operation::Spawned(spawn) => (None, Some(spawn)),
+        };
+        if !self.is_enabled(site) {
+            return;
+        }
+        log!(Trace, "Instrumentation call with {:?}", site);
+        if let Some(producer) = self.producer.as_ref() {
+            // Dropped events are counted by the buffer and reported in the summary
+            let event = ProbeEvent {
+                site,
+                time_stamp,
+                channel,
+                spawn,
+            };
+            match self.buffer_policy {
+                BufferPolicy::Block => producer.push_or_block(event),
+                BufferPolicy::Drop => producer.push_or_drop(event),
+            };
         } else {
-            eprintln!("Inst call: Unable to retrieve channel, not initialized.");
+            log!(
+                Warn,
+                "Inst call: Unable to retrieve buffer, not initialized."
+            );
         }
     }
 
-    /// Signals the helper_thread to finish
-    fn signal_finish(&self) {
-        println!("{:?} - {}", thread::current().id(), "Signal finish");
-        if let Some(channel) = self.channel_sender.as_ref() {
-            match channel.send(Message::Finish) {
-                Ok(()) => {}
-                Err(e) => eprintln!(
-                    "{:?} Unable to signal helper thread to finish: {}",
-                    thread::current().id(),
-                    e
-                ),
+    /// Applies the special behaviour rules of the probe. Counts the hit if the probe fires
+    fn is_enabled(&mut self, site: &'static Site) -> bool {
+        let behaviours = match self.special_behaviour.get(site.absolute_path) {
+            Some(behaviours) => behaviours,
+            None => return true,
+        };
+        let call_site = self.call_sites.entry(CallSiteKey::of(site)).or_default();
+        // The end of a call follows the decision of its beginning, so the pair stays complete
+        if END_DESCRIPTIONS.contains(&site.description) {
+            if let Some(enabled) = call_site.open.pop() {
+                return enabled;
             }
-        } else {
-            eprintln!("Signal finish: Unable to retrieve channel, not initialized.");
         }
-    }
-}
-
-enum Message<S> {
-    Instrument(S),
-    Finish,
-}
-
-/// Data structure for the helper_thread
-struct InstHelper {
-    /// Receiving end of channel. For receiving signals
-    channel_receiver: Receiver<Message<StaticData>>,
-    /// Dynamic data
-    dynamic_data: DynData,
-    /// UdpSocket
-    udp_socket: MioUdpSocket,
-}
-
-impl InstHelper {
-    /// Constructs a new InstHelper
-    fn new(recv: Receiver<Message<StaticData>>, local_config: LocalConfig) -> InstHelper {
-        let socket =
-            MioUdpSocket::bind(&"0.0.0.0:0".parse().unwrap()).expect("Unable to bind socket.");
-        socket
-            .connect(local_config.collector_addr)
-            .expect("connect function failed");
-        InstHelper {
-            channel_receiver: recv,
-            dynamic_data: DynData::new(&local_config.machine_id),
-            udp_socket: socket,
+        let time_origin = self.time_origin;
+        let hits = call_site.hits;
+        let enabled = behaviours.iter().all(|behaviour| match behaviour {
+            Behaviour::Disabled => false,
+            Behaviour::FirstHits(max_hits) => hits < *max_hits,
+            Behaviour::NotAfter(budget) => time_origin.elapsed() <= *budget,
+            Behaviour::OnlyThread(name) => thread::current().name() == Some(name.as_str()),
+        });
+        if BEGIN_DESCRIPTIONS.contains(&site.description) {
+            call_site.open.push(enabled);
         }
-    }
-
-    /// Waits until signaled, then updates dynamic data and sends data to Collector
-    fn run(mut self) {
-        println!("{:?} - {}", thread::current().id(), "Helper is running...");
-        // Blocks until something was received via channel
-        while let Ok(Message::Instrument(static_data)) = self.channel_receiver.recv() {
-            // Update dynamic data
-            self.dynamic_data.update();
-
-            // Send entire inst data to collector process
-            self.send_inst(static_data);
+        if enabled {
+            call_site.hits += 1;
         }
-        println!("{:?} - {}", thread::current().id(), "Terminating.");
+        enabled
     }
 
-    /// Sends data to Collector
-    fn send_inst(&self, static_data: StaticData) {
-        if let Some(bincode) = instdata::to_bincode(&self.dynamic_data, &static_data) {
-            if let Err(err) = self.udp_socket.send(&bincode) {
-                // eprintln!("Unable to send data: {}", err)
-            }
+    /// Ends the event stream, the exporter sends its summary
+    fn finish(&mut self) {
+        log!(Debug, "Signal finish");
+        if self.producer.take().is_none() {
+            log!(Warn, "Finish: Unable to retrieve buffer, not initialized.");
         }
-        println!(
-            "{:?} - {} with {:?} {:?}",
-            thread::current().id(),
-            "Sending item",
-            self.dynamic_data,
-            &static_data
-        );
     }
 }
 
+/// Operation of a probe besides the call itself
+pub enum Operation {
+    Call,
+    /// Send or recv, numbered on its channel
+    Channel(Channel),
+    /// Start of a closure, spawned by a call on the parent thread
+    Spawned(Spawn),
+}
+
 mod configuration {
     use std::collections::HashMap;
+    use std::env;
     use std::fs;
     use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
     use toml;
 
+    use crate::clock::Clock;
+    use crate::error::InstError;
+    use crate::identity;
+
+    /// Environment variable naming the config file
+    const CONFIG_ENV: &str = "TRUST_CONFIG";
+    /// Name of the config file in the root of the Cargo workspace
+    const WORKSPACE_CONFIG_FILE: &str = "instconfig.toml";
+    /// Config file below the home directory
+    const HOME_CONFIG_FILE: &str = ".rust_inst/instconfig.toml";
+    /// Environment variables overriding the config
+    const COLLECTOR_IP_ENV: &str = "TRUST_COLLECTOR_IP";
+    const COLLECTOR_PORT_ENV: &str = "TRUST_COLLECTOR_PORT";
+    const MACHINE_ID_ENV: &str = "TRUST_MACHINE_ID";
+    const RUN_ID_ENV: &str = "TRUST_RUN_ID";
+    /// Largest batch_size of the udp transport. A datagram carries at most 65507 bytes, the
+    /// rest is left for the batch header and the records added after batch_size was reached
+    const MAX_UDP_BATCH_SIZE: usize = 60 * 1024;
+    /// Largest batch_size, leaves the same room below the payload the collector unpacks
+    const MAX_BATCH_SIZE: usize = instdata::MAX_PAYLOAD_LEN - 4 * 1024;
+
     /// Config struct is constructed when reading the config file
     #[derive(Deserialize)]
     pub struct Config {
@@ -242,53 +526,346 @@ mod configuration {
         pub special_behaviour: Vec<(String, String)>,
         pub collector_ip: String,
         pub collector_port: u16,
-        pub machine_id: String,
+        /// Id of the machine, detected if missing
+        #[serde(default)]
+        pub machine_id: Option<String>,
+        /// Transport used to send events to the collector
+        #[serde(default)]
+        pub transport: TransportKind,
+        /// Directory for trace files of the file transport
+        #[serde(default = "default_trace_dir")]
+        pub trace_dir: PathBuf,
+        /// Uncompressed batch size in bytes which triggers sending the batch
+        #[serde(default = "default_batch_size")]
+        pub batch_size: usize,
+        /// Maximal time in milliseconds an event waits before its batch is sent
+        #[serde(default = "default_flush_interval_ms")]
+        pub flush_interval_ms: u64,
+        /// Source of the time stamps
+        #[serde(default)]
+        pub clock: ClockKind,
+        /// Number of events the ring buffer of a thread holds
+        #[serde(default = "default_buffer_capacity")]
+        pub buffer_capacity: usize,
+        /// What a probe does when the ring buffer of its thread is full
+        #[serde(default)]
+        pub buffer_policy: BufferPolicy,
+        /// Number of probe calls timed at global init, 0 disables the calibration
+        #[serde(default)]
+        pub calibration_probes: u64,
+        /// Most verbose diagnostic messages written, overridden by TRUST_LOG
+        #[serde(default)]
+        pub log_level: Option<LogLevel>,
+        /// File the diagnostic messages are appended to instead of stderr
+        #[serde(default)]
+        pub log_file: Option<PathBuf>,
+        /// Parsed special_behaviour rules, grouped by absolute path
+        #[serde(skip)]
+        pub behaviours: HashMap<String, Vec<Behaviour>>,
+        /// Text the config was deserialized from
+        #[serde(skip)]
+        pub text: String,
     }
 
-    /// Read the config file
-    pub fn read_conf_file<P: AsRef<std::path::Path>>(path: P) -> Config {
-        match fs::read_to_string(path) {
-            Err(err) => {
-                eprintln!("Unable to read config file: {}", err);
-                panic!()
+    /// Transport used to send events to the collector
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum TransportKind {
+        /// One datagram per batch
+        Udp,
+        /// Length-prefixed batches on a reconnecting stream
+        Tcp,
+        /// Length-prefixed batches in a trace file per process
+        File,
+    }
+
+    impl Default for TransportKind {
+        fn default() -> TransportKind {
+            TransportKind::Udp
+        }
+    }
+
+    /// Source of the time stamps
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ClockKind {
+        /// `time::precise_time_ns`
+        PreciseTime,
+        /// CLOCK_MONOTONIC_RAW, Linux only
+        MonotonicRaw,
+        /// Time stamp counter calibrated at global init, x86_64 with invariant TSC only
+        Tsc,
+    }
+
+    impl Default for ClockKind {
+        fn default() -> ClockKind {
+            ClockKind::PreciseTime
+        }
+    }
+
+    /// What a probe does when the ring buffer of its thread is full
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum BufferPolicy {
+        /// Wait until the exporter made space, no event is lost
+        Block,
+        /// Drop the event, the number of dropped events is sent in the summary
+        Drop,
+    }
+
+    impl Default for BufferPolicy {
+        fn default() -> BufferPolicy {
+            BufferPolicy::Block
+        }
+    }
+
+    /// Severity of diagnostic messages, ordered from quiet to verbose
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum LogLevel {
+        Off,
+        Error,
+        Warn,
+        Info,
+        Debug,
+        Trace,
+    }
+
+    impl LogLevel {
+        /// Parses a level name, ignoring case
+        pub fn parse(name: &str) -> Option<LogLevel> {
+            match name.to_lowercase().as_str() {
+                "off" => Some(LogLevel::Off),
+                "error" => Some(LogLevel::Error),
+                "warn" => Some(LogLevel::Warn),
+                "info" => Some(LogLevel::Info),
+                "debug" => Some(LogLevel::Debug),
+                "trace" => Some(LogLevel::Trace),
+                _ => None,
+            }
+        }
+    }
+
+    /// Default for trace_dir
+    fn default_trace_dir() -> PathBuf {
+        PathBuf::from(".")
+    }
+
+    /// Default for batch_size, keeps compressed batches well below the UDP datagram limit
+    fn default_batch_size() -> usize {
+        16 * 1024
+    }
+
+    /// Default for buffer_capacity
+    fn default_buffer_capacity() -> usize {
+        4096
+    }
+
+    /// Default for flush_interval_ms
+    fn default_flush_interval_ms() -> u64 {
+        100
+    }
+
+    /// Finds the config file. Looks for the file named by TRUST_CONFIG, then for
+    /// `instconfig.toml` in the root of the Cargo workspace, then for
+    /// `~/.rust_inst/instconfig.toml`
+    pub fn locate_conf_file() -> Result<PathBuf, InstError> {
+        if let Some(path) = env::var_os(CONFIG_ENV) {
+            return Ok(PathBuf::from(path));
+        }
+        // Set by cargo when compiling and when running through cargo
+        let start_dir = env::var_os("CARGO_MANIFEST_DIR")
+            .map(PathBuf::from)
+            .or_else(|| env::current_dir().ok())
+            .unwrap_or_default();
+        locate_conf_file_from(&start_dir)
+    }
+
+    /// Finds the config file in the root of the Cargo workspace the directory belongs to,
+    /// then below the home directory
+    pub fn locate_conf_file_from(dir: &Path) -> Result<PathBuf, InstError> {
+        let mut searched = Vec::new();
+        if let Some(root) = workspace_root(dir) {
+            searched.push(root.join(WORKSPACE_CONFIG_FILE));
+        }
+        if let Some(home) = dirs::home_dir() {
+            searched.push(home.join(HOME_CONFIG_FILE));
+        }
+        match searched.iter().find(|path| path.is_file()) {
+            Some(path) => Ok(path.clone()),
+            None => Err(InstError::NoConfigFile(searched)),
+        }
+    }
+
+    /// Root of the Cargo workspace the directory belongs to: the nearest ancestor whose
+    /// Cargo.toml declares a workspace, else the nearest ancestor with a Cargo.toml
+    fn workspace_root(dir: &Path) -> Option<PathBuf> {
+        let mut package_root = None;
+        for ancestor in dir.ancestors() {
+            let manifest = match fs::read_to_string(ancestor.join("Cargo.toml")) {
+                Ok(manifest) => manifest,
+                Err(_) => continue,
+            };
+            if manifest.lines().any(|line| line.trim() == "[workspace]") {
+                return Some(ancestor.to_path_buf());
             }
-            Ok(content) => deserialize_config(&content),
+            package_root.get_or_insert_with(|| ancestor.to_path_buf());
         }
+        package_root
+    }
+
+    /// Read the config file
+    pub fn read_conf_file<P: AsRef<Path>>(path: P) -> Result<Config, InstError> {
+        deserialize_config(&read_conf_text(path)?)
+    }
+
+    /// Reads the text of the config file
+    pub fn read_conf_text<P: AsRef<Path>>(path: P) -> Result<String, InstError> {
+        let path = path.as_ref();
+        fs::read_to_string(path).map_err(|err| InstError::ReadConfig(path.to_path_buf(), err))
     }
 
     /// Deserializes the config file
-    fn deserialize_config(content: &str) -> Config {
-        match toml::from_str::<Config>(content) {
-            Err(err) => {
-                eprintln!("Unable to deserialize config: {}", err);
-                panic!()
+    pub fn deserialize_config(content: &str) -> Result<Config, InstError> {
+        let mut config = toml::from_str::<Config>(content).map_err(InstError::ParseConfig)?;
+        config.behaviours =
+            parse_special_behaviour(&config.special_behaviour).map_err(InstError::Behaviour)?;
+        config.text = String::from(content);
+        if config.transport == TransportKind::Udp && config.batch_size > MAX_UDP_BATCH_SIZE {
+            return Err(InstError::Setting(
+                "batch_size",
+                format!(
+                    "{} exceeds {} of the udp transport",
+                    config.batch_size, MAX_UDP_BATCH_SIZE
+                ),
+            ));
+        }
+        if config.batch_size > MAX_BATCH_SIZE {
+            return Err(InstError::Setting(
+                "batch_size",
+                format!("{} exceeds {}", config.batch_size, MAX_BATCH_SIZE),
+            ));
+        }
+        if config.flush_interval_ms == 0 {
+            return Err(InstError::Setting(
+                "flush_interval_ms",
+                String::from("has to be at least 1"),
+            ));
+        }
+        Ok(config)
+    }
+
+    /// Rule from the special_behaviour list, restricting when a probe fires
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Behaviour {
+        /// "nope": the probe never fires
+        Disabled,
+        /// "first_<n>": only the first n calls of each call site per thread fire
+        FirstHits(u64),
+        /// "not_after_<s>": the probe stops firing s seconds after global init
+        NotAfter(Duration),
+        /// "only_thread_<name>": the probe only fires on threads with that name
+        OnlyThread(String),
+    }
+
+    impl Behaviour {
+        /// Parses a single rule, returns None for unknown rules
+        pub fn parse(rule: &str) -> Option<Behaviour> {
+            if rule == "nope" {
+                Some(Behaviour::Disabled)
+            } else if rule.starts_with("first_") {
+                rule["first_".len()..]
+                    .parse()
+                    .ok()
+                    .map(Behaviour::FirstHits)
+            } else if rule.starts_with("not_after_") {
+                rule["not_after_".len()..]
+                    .parse()
+                    .ok()
+                    .map(|secs| Behaviour::NotAfter(Duration::from_secs(secs)))
+            } else if rule.starts_with("only_thread_") && rule.len() > "only_thread_".len() {
+                Some(Behaviour::OnlyThread(
+                    rule["only_thread_".len()..].to_string(),
+                ))
+            } else {
+                None
             }
-            Ok(config) => config,
         }
     }
 
+    /// Parses the special_behaviour list, rejects unknown rules
+    pub fn parse_special_behaviour(
+        rules: &[(String, String)],
+    ) -> Result<HashMap<String, Vec<Behaviour>>, String> {
+        let mut behaviours: HashMap<String, Vec<Behaviour>> = HashMap::new();
+        for (path, rule) in rules {
+            match Behaviour::parse(rule) {
+                Some(behaviour) => behaviours.entry(path.clone()).or_default().push(behaviour),
+                None => return Err(format!("unknown rule '{}' for '{}'", rule, path)),
+            }
+        }
+        Ok(behaviours)
+    }
+
     /// Local config for thread local usage
     #[derive(Clone)]
     pub struct LocalConfig {
         pub collector_addr: SocketAddr,
         pub machine_id: String,
-        pub special_behaviour: HashMap<String, String>,
+        /// Id of the run of the process
+        pub run_id: String,
+        pub transport: TransportKind,
+        pub trace_dir: PathBuf,
+        pub special_behaviour: HashMap<String, Vec<Behaviour>>,
+        /// Point in time of the global initialization
+        pub time_origin: Instant,
+        pub batch_size: usize,
+        pub flush_interval: Duration,
+        pub clock: Clock,
+        pub buffer_capacity: usize,
+        pub buffer_policy: BufferPolicy,
+        pub calibration_probes: u64,
+        /// Text of the config, sent to the collector
+        pub config_text: String,
     }
 
     impl LocalConfig {
-        /// Construction of the thread local config struct
-        pub fn new(config: Config) -> LocalConfig {
-            match config.collector_ip.parse() {
-                Ok(ip_addr) => LocalConfig {
-                    collector_addr: SocketAddr::new(ip_addr, config.collector_port),
-                    machine_id: config.machine_id,
-                    special_behaviour: config.special_behaviour.iter().cloned().collect(),
-                },
-                Err(err) => {
-                    eprintln!("Unable to parse ip address: {}", err);
-                    panic!()
-                }
-            }
+        /// Construction of the thread local config struct. TRUST_COLLECTOR_IP,
+        /// TRUST_COLLECTOR_PORT and TRUST_MACHINE_ID override the config.
+        /// The run id is random unless TRUST_RUN_ID is set
+        pub fn new(config: Config) -> Result<LocalConfig, InstError> {
+            let collector_ip = env::var(COLLECTOR_IP_ENV).unwrap_or(config.collector_ip);
+            let collector_port = match env::var(COLLECTOR_PORT_ENV) {
+                Ok(port) => port
+                    .parse()
+                    .map_err(|_| InstError::EnvVar(COLLECTOR_PORT_ENV, port))?,
+                Err(_) => config.collector_port,
+            };
+            let collector_addr = match collector_ip.parse() {
+                Ok(ip_addr) => SocketAddr::new(ip_addr, collector_port),
+                Err(err) => return Err(InstError::CollectorIp(collector_ip, err)),
+            };
+            let machine_id = env::var(MACHINE_ID_ENV)
+                .ok()
+                .or(config.machine_id)
+                .unwrap_or_else(|| identity::machine_id(collector_addr));
+            let run_id = env::var(RUN_ID_ENV).unwrap_or_else(|_| identity::run_id());
+            Ok(LocalConfig {
+                collector_addr,
+                machine_id,
+                run_id,
+                transport: config.transport,
+                trace_dir: config.trace_dir,
+                special_behaviour: config.behaviours,
+                time_origin: Instant::now(),
+                batch_size: config.batch_size,
+                flush_interval: Duration::from_millis(config.flush_interval_ms),
+                clock: Clock::new(config.clock),
+                buffer_capacity: config.buffer_capacity,
+                buffer_policy: config.buffer_policy,
+                calibration_probes: config.calibration_probes,
+                config_text: config.text,
+            })
         }
     }
 }
@@ -303,4 +880,172 @@ mod tests {
         // TODO
         unimplemented!()
     }
+
+    #[test]
+    fn parse_special_behaviour() {
+        let rules = vec![
+            (String::from("rayon::join"), String::from("nope")),
+            (String::from("hello"), String::from("not_after_10")),
+            (String::from("hello"), String::from("first_3")),
+            (String::from("send"), String::from("only_thread_main")),
+        ];
+        let behaviours = configuration::parse_special_behaviour(&rules).unwrap();
+        assert_eq!(behaviours["rayon::join"], vec![Behaviour::Disabled]);
+        assert_eq!(
+            behaviours["hello"],
+            vec![
+                Behaviour::NotAfter(std::time::Duration::from_secs(10)),
+                Behaviour::FirstHits(3)
+            ]
+        );
+        assert_eq!(
+            behaviours["send"],
+            vec![Behaviour::OnlyThread(String::from("main"))]
+        );
+
+        let unknown = vec![(String::from("hello"), String::from("sometimes"))];
+        assert!(configuration::parse_special_behaviour(&unknown).is_err());
+    }
+
+    #[test]
+    fn reports_config_errors() {
+        let config = |ip: &str, rule: &str| {
+            format!(
+                "code_2_monitor = []\nspecial_behaviour = [[\"main\", \"{}\"]]\n\
+                 collector_ip = \"{}\"\ncollector_port = 8080\nmachine_id = \"m\"\n",
+                rule, ip
+            )
+        };
+        match read_conf_file("/nonexistent/instconfig.toml") {
+            Err(InstError::ReadConfig(..)) => (),
+            _ => panic!("missing file accepted"),
+        }
+        match configuration::deserialize_config("collector_ip = 1") {
+            Err(InstError::ParseConfig(_)) => (),
+            _ => panic!("invalid config accepted"),
+        }
+        match configuration::deserialize_config(&config("127.0.0.1", "sometimes")) {
+            Err(InstError::Behaviour(_)) => (),
+            _ => panic!("unknown rule accepted"),
+        }
+        let setting = |line: &str| format!("{}{}\n", config("127.0.0.1", "nope"), line);
+        match configuration::deserialize_config(&setting("batch_size = 65536")) {
+            Err(InstError::Setting("batch_size", _)) => (),
+            _ => panic!("oversized udp batch accepted"),
+        }
+        assert!(configuration::deserialize_config(&setting(
+            "batch_size = 65536\ntransport = \"tcp\""
+        ))
+        .is_ok());
+        match configuration::deserialize_config(&setting("flush_interval_ms = 0")) {
+            Err(InstError::Setting("flush_interval_ms", _)) => (),
+            _ => panic!("zero flush interval accepted"),
+        }
+        let config = configuration::deserialize_config(&config("localhost", "nope")).unwrap();
+        match LocalConfig::new(config) {
+            Err(InstError::CollectorIp(..)) => (),
+            _ => panic!("invalid ip accepted"),
+        }
+    }
+
+    #[test]
+    fn finds_workspace_config() {
+        let dir = std::env::temp_dir().join(format!("trust-config-{}", std::process::id()));
+        let member = dir.join("member");
+        std::fs::create_dir_all(&member).unwrap();
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"member\"]\n",
+        )
+        .unwrap();
+        std::fs::write(member.join("Cargo.toml"), "[package]\n").unwrap();
+        std::fs::write(dir.join("instconfig.toml"), "").unwrap();
+
+        assert_eq!(
+            configuration::locate_conf_file_from(&member).unwrap(),
+            dir.join("instconfig.toml")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn first_hits_keep_calls_paired() {
+        static BEGIN: Site = Site {
+            id: 1,
+            absolute_path: "hello",
+            description: "BEGIN",
+            ast_depth: 1,
+            source_file: "main.rs",
+            lines_begin: 3,
+            lines_end: 3,
+        };
+        static END: Site = Site {
+            id: 2,
+            description: "END",
+            ..BEGIN
+        };
+        let mut inst = ThreadLocalInst::new_empty();
+        inst.special_behaviour
+            .insert(String::from("hello"), vec![Behaviour::FirstHits(1)]);
+
+        // A recursive call begins before the first one ends
+        assert!(inst.is_enabled(&BEGIN));
+        assert!(!inst.is_enabled(&BEGIN));
+        assert!(!inst.is_enabled(&END));
+        assert!(inst.is_enabled(&END));
+        assert!(!inst.is_enabled(&BEGIN));
+        assert!(!inst.is_enabled(&END));
+    }
+
+    #[test]
+    fn streams_end_when_their_thread_exits() {
+        let (producer, consumer) = ring::ring::<ProbeEvent>(4);
+        thread::spawn(move || {
+            let mut inst = ThreadLocalInst::new_empty();
+            inst.producer = Some(producer);
+            inst.thread_id = 1;
+            LOCAL_INST.with(|local_inst| *local_inst.borrow_mut() = Some(inst));
+            assert_eq!(current_thread(), 1);
+        })
+        .join()
+        .unwrap();
+        assert!(consumer.is_orphaned());
+        assert_eq!(current_thread(), 0);
+    }
+
+    #[test]
+    fn run_ids_are_random_uuids() {
+        let run_id = identity::run_id();
+        assert_eq!(run_id.len(), 36);
+        assert_eq!(run_id.matches('-').count(), 4);
+        assert_eq!(&run_id[14..15], "4");
+        assert_ne!(run_id, identity::run_id());
+    }
+
+    #[test]
+    fn probes_are_no_ops_when_disabled() {
+        let (sender, _receiver) = channel(std::sync::mpsc::channel::<u128>());
+        local_init();
+        instrument(&CALIBRATION_SITE);
+        instrument_send(&CALIBRATION_SITE, ChannelProbe(&sender).channel());
+        clean_up();
+        global_clean_up();
+        assert!(INSTRUMENTATION.try_get().is_none());
+    }
+
+    #[test]
+    fn clocks_follow_precise_time() {
+        use configuration::ClockKind;
+        for kind in &[
+            ClockKind::PreciseTime,
+            ClockKind::MonotonicRaw,
+            ClockKind::Tsc,
+        ] {
+            let clock = clock::Clock::new(*kind);
+            let before = clock.now();
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            let elapsed = clock.now() - before;
+            assert!(elapsed >= 4_000_000 && elapsed < 500_000_000, "{:?}", clock);
+        }
+    }
 }
@@ -0,0 +1,87 @@
+use std::env;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use state::Storage;
+
+use crate::configuration::{Config, LogLevel};
+
+/// Environment variable overriding the log_level of the config
+const LOG_ENV: &str = "TRUST_LOG";
+/// Level used if neither TRUST_LOG nor log_level is set
+const DEFAULT_LEVEL: LogLevel = LogLevel::Warn;
+/// Marks LEVEL before TRUST_LOG was read
+const UNSET: usize = LogLevel::Trace as usize + 1;
+
+/// Most verbose level which is written
+static LEVEL: AtomicUsize = AtomicUsize::new(UNSET);
+
+/// Log file, messages go to stderr without it
+static LOG_FILE: Storage<Mutex<File>> = Storage::new();
+
+/// Writes a diagnostic message if its level is enabled: `log!(Warn, "format", args)`
+macro_rules! log {
+    ($level:ident, $($arg:tt)+) => {
+        if $crate::log::enabled($crate::configuration::LogLevel::$level) {
+            $crate::log::write($crate::configuration::LogLevel::$level, format_args!($($arg)+));
+        }
+    };
+}
+
+/// Applies the log settings of the config. TRUST_LOG takes precedence over log_level
+pub fn set_up(config: &Config) {
+    let level = env_level().or(config.log_level).unwrap_or(DEFAULT_LEVEL);
+    LEVEL.store(level as usize, Ordering::Relaxed);
+    if let Ok(value) = env::var(LOG_ENV) {
+        if LogLevel::parse(&value).is_none() {
+            log!(Warn, "Ignoring unknown {} level '{}'", LOG_ENV, value);
+        }
+    }
+    if let Some(path) = &config.log_file {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => {
+                LOG_FILE.set(Mutex::new(file));
+            }
+            Err(err) => log!(Warn, "Unable to open log file {:?}: {}", path, err),
+        }
+    }
+}
+
+/// Whether messages of the level are written
+#[inline]
+pub fn enabled(level: LogLevel) -> bool {
+    let mut max_level = LEVEL.load(Ordering::Relaxed);
+    // Messages before the config was read only follow TRUST_LOG
+    if max_level == UNSET {
+        max_level = env_level().unwrap_or(DEFAULT_LEVEL) as usize;
+        LEVEL.store(max_level, Ordering::Relaxed);
+    }
+    level != LogLevel::Off && level as usize <= max_level
+}
+
+/// Writes the message with level and thread to the log file or stderr
+pub fn write(level: LogLevel, message: fmt::Arguments) {
+    let line = format!(
+        "tRust {:?} {:?} - {}\n",
+        level,
+        thread::current().id(),
+        message
+    );
+    if let Some(file) = LOG_FILE.try_get() {
+        if let Ok(mut file) = file.lock() {
+            if file.write_all(line.as_bytes()).is_ok() {
+                return;
+            }
+        }
+    }
+    eprint!("{}", line);
+}
+
+/// Level set by TRUST_LOG. An unknown level is ignored here, set_up warns about it
+fn env_level() -> Option<LogLevel> {
+    LogLevel::parse(&env::var(LOG_ENV).ok()?)
+}
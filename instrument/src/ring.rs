@@ -0,0 +1,206 @@
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, Thread};
+
+/// Bounded lock-free ring buffer with a single producer and a single consumer
+struct Ring<T> {
+    slots: Box<[UnsafeCell<Option<T>>]>,
+    /// Capacity - 1, the capacity is a power of two
+    mask: usize,
+    /// Position of the next slot to read, only advanced by the consumer
+    head: AtomicUsize,
+    /// Position of the next slot to write, only advanced by the producer
+    tail: AtomicUsize,
+    /// Number of values the producer dropped
+    dropped: AtomicU64,
+    producer_dropped: AtomicBool,
+    consumer_dropped: AtomicBool,
+}
+
+// Every slot is accessed either by the producer or by the consumer, head and tail hand it over
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+/// Writing end of a ring buffer
+pub struct Producer<T> {
+    ring: Arc<Ring<T>>,
+    /// Thread draining the buffer, woken when the buffer fills up
+    consumer_thread: Option<Thread>,
+    /// A single thread may push at a time
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// Reading end of a ring buffer
+pub struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// Creates a ring buffer holding at least `capacity` values
+pub fn ring<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let capacity = capacity.max(2).next_power_of_two();
+    let ring = Arc::new(Ring {
+        slots: (0..capacity).map(|_| UnsafeCell::new(None)).collect(),
+        mask: capacity - 1,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        dropped: AtomicU64::new(0),
+        producer_dropped: AtomicBool::new(false),
+        consumer_dropped: AtomicBool::new(false),
+    });
+    (
+        Producer {
+            ring: ring.clone(),
+            consumer_thread: None,
+            _not_sync: PhantomData,
+        },
+        Consumer {
+            ring,
+            _not_sync: PhantomData,
+        },
+    )
+}
+
+impl<T> Producer<T> {
+    /// Sets the thread which is woken when the buffer is half full
+    pub fn set_consumer_thread(&mut self, thread: Thread) {
+        self.consumer_thread = Some(thread);
+    }
+
+    /// Appends the value, drops it if the buffer is full.
+    /// Returns whether the value was appended
+    pub fn push_or_drop(&self, value: T) -> bool {
+        match self.try_push(value) {
+            Ok(()) => true,
+            Err(_) => {
+                self.ring.dropped.fetch_add(1, Ordering::Relaxed);
+                self.wake_consumer();
+                false
+            }
+        }
+    }
+
+    /// Appends the value, waits for the consumer while the buffer is full.
+    /// Returns false and drops the value if the consumer is gone
+    pub fn push_or_block(&self, mut value: T) -> bool {
+        loop {
+            match self.try_push(value) {
+                Ok(()) => return true,
+                Err(rejected) => value = rejected,
+            }
+            if self.ring.consumer_dropped.load(Ordering::Acquire) {
+                self.ring.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            self.wake_consumer();
+            thread::yield_now();
+        }
+    }
+
+    /// Wakes the consumer, e.g. to take a last value
+    pub fn wake_consumer(&self) {
+        if let Some(thread) = &self.consumer_thread {
+            thread.unpark();
+        }
+    }
+
+    /// Appends the value if the buffer has space
+    fn try_push(&self, value: T) -> Result<(), T> {
+        let ring = &*self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let head = ring.head.load(Ordering::Acquire);
+        let queued = tail.wrapping_sub(head);
+        if queued == ring.slots.len() {
+            return Err(value);
+        }
+        // The consumer does not read the slot before the tail passed it
+        unsafe {
+            *ring.slots[tail & ring.mask].get() = Some(value);
+        }
+        ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        if queued + 1 == ring.slots.len() / 2 {
+            self.wake_consumer();
+        }
+        Ok(())
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.ring.producer_dropped.store(true, Ordering::Release);
+        self.wake_consumer();
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Takes the oldest value, None if the buffer is empty
+    pub fn pop(&self) -> Option<T> {
+        let ring = &*self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+        let tail = ring.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        // The producer does not write the slot before the head passed it
+        let value = unsafe { (*ring.slots[head & ring.mask].get()).take() };
+        ring.head.store(head.wrapping_add(1), Ordering::Release);
+        value
+    }
+
+    /// Whether the producer is gone. Values it pushed before can still be taken
+    pub fn is_orphaned(&self) -> bool {
+        self.ring.producer_dropped.load(Ordering::Acquire)
+    }
+
+    /// Number of values the producer dropped
+    pub fn dropped(&self) -> u64 {
+        self.ring.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.ring.consumer_dropped.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_order_across_threads() {
+        let (producer, consumer) = ring::<u64>(64);
+        let writer = thread::spawn(move || {
+            for value in 0..100_000 {
+                assert!(producer.push_or_block(value));
+            }
+        });
+        let mut expected = 0;
+        while expected < 100_000 {
+            match consumer.pop() {
+                Some(value) => {
+                    assert_eq!(value, expected);
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        writer.join().unwrap();
+        assert!(consumer.is_orphaned());
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn drops_when_full() {
+        let (producer, consumer) = ring::<u64>(3);
+        let pushed = (0..6).filter(|&value| producer.push_or_drop(value)).count();
+        assert_eq!(pushed, 4);
+        assert_eq!(consumer.dropped(), 2);
+        assert_eq!(consumer.pop(), Some(0));
+        assert!(producer.push_or_drop(6));
+        drop(consumer);
+        assert!(!producer.push_or_block(7));
+    }
+}
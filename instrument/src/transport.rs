@@ -0,0 +1,229 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::process;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use mio::net::UdpSocket as MioUdpSocket;
+use state::Storage;
+
+use crate::configuration::{LocalConfig, TransportKind};
+
+/// Timeout for establishing a TCP connection
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// First delay before reconnecting
+const TCP_MIN_BACKOFF: Duration = Duration::from_millis(100);
+/// Maximal delay before reconnecting
+const TCP_MAX_BACKOFF: Duration = Duration::from_secs(10);
+/// Maximal number of batches kept while the collector is unreachable
+const TCP_MAX_UNSENT: usize = 64;
+
+/// Trace file of the process
+static TRACE_FILE: Storage<Mutex<File>> = Storage::new();
+
+/// Connection to the collector, sends framed batches
+pub enum Connection {
+    /// One datagram per batch, lost datagrams are not noticed
+    Udp(MioUdpSocket),
+    /// Length-prefixed batches on a TCP stream
+    Tcp(TcpConnection),
+    /// Length-prefixed batches in the trace file of the process
+    File(&'static Mutex<File>),
+}
+
+impl Connection {
+    /// Opens the connection specified in the config. An unreachable TCP collector is no
+    /// error, the connection is retried when sending
+    pub fn open(local_config: &LocalConfig) -> io::Result<Connection> {
+        match local_config.transport {
+            TransportKind::Udp => {
+                let any_addr = SocketAddr::from(([0, 0, 0, 0], 0));
+                let socket = MioUdpSocket::bind(&any_addr)?;
+                socket.connect(local_config.collector_addr)?;
+                Ok(Connection::Udp(socket))
+            }
+            TransportKind::Tcp => Ok(Connection::Tcp(TcpConnection::new(
+                local_config.collector_addr,
+            ))),
+            TransportKind::File => Ok(Connection::File(trace_file(&local_config.trace_dir)?)),
+        }
+    }
+
+    /// Sends one batch to the collector. Returns whether the batch is on the wire, a batch
+    /// which waits for a TCP connection is not
+    pub fn send_batch(&mut self, batch: &[u8]) -> bool {
+        match self {
+            Connection::Udp(socket) => match socket.send(batch) {
+                Ok(_) => true,
+                Err(err) => {
+                    log!(Warn, "Unable to send data: {}", err);
+                    false
+                }
+            },
+            Connection::Tcp(connection) => connection.send_batch(batch),
+            Connection::File(file) => match file.lock() {
+                Ok(mut file) => match write_frame(&mut *file, batch) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        log!(Error, "Unable to write trace file: {}", err);
+                        false
+                    }
+                },
+                Err(err) => {
+                    log!(Error, "Unable to lock trace file: {}", err);
+                    false
+                }
+            },
+        }
+    }
+}
+
+/// TCP stream to the collector which reconnects with exponential backoff
+pub struct TcpConnection {
+    collector_addr: SocketAddr,
+    stream: Option<TcpStream>,
+    /// Delay before the next connection attempt
+    backoff: Duration,
+    /// Point in time of the next connection attempt
+    next_attempt: Instant,
+    /// Frames not yet written, oldest first
+    unsent: VecDeque<Vec<u8>>,
+}
+
+impl TcpConnection {
+    /// Constructs new TcpConnection and tries to connect
+    fn new(collector_addr: SocketAddr) -> TcpConnection {
+        let mut connection = TcpConnection {
+            collector_addr,
+            stream: None,
+            backoff: TCP_MIN_BACKOFF,
+            next_attempt: Instant::now(),
+            unsent: VecDeque::new(),
+        };
+        connection.connect_if_due();
+        connection
+    }
+
+    /// Sends the batch prefixed by its length as u32 (big endian). While the collector is
+    /// unreachable up to TCP_MAX_UNSENT batches are kept and sent after reconnecting,
+    /// further batches are dropped. Returns whether the batch was written, a kept batch
+    /// might still be dropped and counts as unsent
+    fn send_batch(&mut self, batch: &[u8]) -> bool {
+        if self.unsent.len() >= TCP_MAX_UNSENT {
+            // Retry the queue first, it might have room afterwards
+            self.send_unsent();
+        }
+        if self.unsent.len() >= TCP_MAX_UNSENT {
+            log!(
+                Warn,
+                "Dropping batch, {} batches wait for {}",
+                self.unsent.len(),
+                self.collector_addr
+            );
+            return false;
+        }
+        self.unsent.push_back(batch.to_vec());
+        self.send_unsent();
+        // The batch is the last frame, it was written if the queue is empty
+        self.unsent.is_empty()
+    }
+
+    /// Writes the unsent frames in order until the queue is empty or the connection fails.
+    /// A frame whose write failed is sent again in full on the next connection
+    fn send_unsent(&mut self) {
+        while !self.unsent.is_empty() && self.connect_if_due() {
+            let result = match self.stream.as_mut() {
+                Some(stream) => write_frame(stream, &self.unsent[0]),
+                None => break,
+            };
+            match result {
+                Ok(()) => {
+                    self.unsent.pop_front();
+                }
+                Err(err) => {
+                    log!(Warn, "Lost connection to {}: {}", self.collector_addr, err);
+                    self.stream = None;
+                    self.next_attempt = Instant::now();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Connects if disconnected and the backoff elapsed. Returns whether connected
+    fn connect_if_due(&mut self) -> bool {
+        if self.stream.is_some() {
+            return true;
+        }
+        if Instant::now() < self.next_attempt {
+            return false;
+        }
+        match TcpStream::connect_timeout(&self.collector_addr, TCP_CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                let _ = stream.set_nodelay(true);
+                self.stream = Some(stream);
+                self.backoff = TCP_MIN_BACKOFF;
+                true
+            }
+            Err(err) => {
+                log!(
+                    Warn,
+                    "Unable to connect to {}, retrying in {:?}: {}",
+                    self.collector_addr,
+                    self.backoff,
+                    err
+                );
+                self.next_attempt = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(TCP_MAX_BACKOFF);
+                false
+            }
+        }
+    }
+}
+
+impl Drop for TcpConnection {
+    /// Sends the unsent frames if the backoff allows it, the rest is lost
+    fn drop(&mut self) {
+        self.send_unsent();
+        if !self.unsent.is_empty() {
+            log!(
+                Warn,
+                "Dropping {} unsent batches, not connected to {}",
+                self.unsent.len(),
+                self.collector_addr
+            );
+        }
+    }
+}
+
+/// Opens the trace file `trace-<pid>.bin` of the process on first use
+fn trace_file(trace_dir: &Path) -> io::Result<&'static Mutex<File>> {
+    if let Some(file) = TRACE_FILE.try_get() {
+        return Ok(file);
+    }
+    let path = trace_dir.join(format!("trace-{}.bin", process::id()));
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("trace file {}: {}", path.display(), err),
+            )
+        })?;
+    // Another thread might have been faster, its file is used then
+    TRACE_FILE.set(Mutex::new(file));
+    Ok(TRACE_FILE.get())
+}
+
+/// Writes the length prefix and the frame in one go
+fn write_frame<W: Write>(writer: &mut W, frame: &[u8]) -> io::Result<()> {
+    let mut buffer = Vec::with_capacity(4 + frame.len());
+    buffer.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(frame);
+    writer.write_all(&buffer)
+}
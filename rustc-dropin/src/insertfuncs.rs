@@ -1,12 +1,12 @@
+use std::collections::HashMap;
 use syntax::ast::DUMMY_NODE_ID;
 use syntax::source_map::{symbol::Symbol, DUMMY_SP};
-use syntax::{ast, ptr, source_map, ThinVec};
+use syntax::{ast, attr, ptr, source_map, ThinVec};
 
 use crate::instfinder::InstPoint;
 use instrument::StaticData;
 
 // Constants
-const NAME_OF_LOCAL_THREAD_HANDLE_VAR: &str = "instrumentation_local_join_handle";
 const NAME_OF_RETURN_VAR: &str = "instrumentation_return_value";
 const NAME_OF_INTERMEDIATE_VAR: &str = "instrumentation_intermediate_var_";
 const NAME_OF_ARGUMENT_VAR: &str = "instrumentation_argument_var_";
@@ -14,9 +14,20 @@ const NAME_OF_INST_CRATE: &str = "instrument";
 const NAME_OF_GLOBAL_INIT_FN: &str = "global_init";
 const NAME_OF_LOCAL_INIT_FN: &str = "local_init";
 const NAME_OF_LOCAL_CLEAN_UP_FN: &str = "clean_up";
+const NAME_OF_GLOBAL_CLEAN_UP_FN: &str = "global_clean_up";
 const NAME_OF_INSTRUMENT_CALL_FN: &str = "instrument";
-const NAME_OF_STATICDATA_STRUCT: &str = "StaticData";
-const NAME_OF_STATICDATA_NEW_FN: &str = "new";
+const NAME_OF_INSTRUMENT_SEND_FN: &str = "instrument_send";
+const NAME_OF_INSTRUMENT_RECV_FN: &str = "instrument_recv";
+const NAME_OF_INSTRUMENT_SPAWNED_FN: &str = "instrument_spawned";
+const NAME_OF_CURRENT_THREAD_FN: &str = "current_thread";
+const NAME_OF_PARENT_VAR: &str = "instrumentation_parent_thread";
+const NAME_OF_SPAWNED_FN_VAR: &str = "instrumentation_spawned_fn";
+const NAME_OF_CHANNEL_PROBE_STRUCT: &str = "ChannelProbe";
+const NAME_OF_CHANNEL_PROBE_METHOD: &str = "channel";
+const NAME_OF_SITE_STRUCT: &str = "Site";
+const NAME_OF_SITE_TABLE: &str = "INSTRUMENTATION_SITES";
+const NAME_OF_EMBEDDED_CONFIG: &str = "INSTRUMENTATION_EMBEDDED_CONFIG";
+const NAME_OF_CRATE_ROOT: &str = "crate";
 
 const DESCRIPTION_GLOBAL_BEGIN: &str = "GLOBAL_BEGIN";
 const DESCRIPTION_GLOBAL_END: &str = "GLOBAL_END";
@@ -25,39 +36,92 @@ const DESCRIPTION_LOCAL_END: &str = "LOCAL_END";
 const DESCRIPTION_INST_CALL_BEGIN: &str = "BEGIN";
 const DESCRIPTION_INST_CALL_END: &str = "END";
 
+/// Collects the probe sites of a crate. A probe refers to its site by the index in the table
+#[derive(Default)]
+pub struct SiteTable {
+    sites: Vec<StaticData>,
+    indices: HashMap<u64, usize>,
+}
+
+impl SiteTable {
+    /// Constructs empty SiteTable
+    pub fn new() -> SiteTable {
+        SiteTable::default()
+    }
+
+    /// Adds the site of a probe if not yet present, returns its index in the table
+    fn add(&mut self, static_data: &StaticData, description: &str) -> usize {
+        let site = StaticData::new(
+            static_data.absolute_path.as_str(),
+            description,
+            static_data.ast_depth,
+            static_data.source_file.as_str(),
+            static_data.lines_begin,
+            static_data.lines_end,
+        );
+        let sites = &mut self.sites;
+        *self.indices.entry(site.site_id).or_insert_with(|| {
+            sites.push(site);
+            sites.len() - 1
+        })
+    }
+}
+
 // Insert functions
 // ------------------------------------------------------------------------------------------------
 
+/// Inserts the site table into the crate root module.
+pub fn insert_site_table(mut_mod: &mut ast::Mod, sites: SiteTable) {
+    mut_mod.items.insert(0, ptr::P(create_site_table(sites)));
+}
+
 /// Inserts extern crate item into the AST of the original program.
-pub fn insert_extern_crate_item(mut_mod: &mut ast::Mod, _inst_point: InstPoint) {
+pub fn insert_extern_crate_item(
+    mut_mod: &mut ast::Mod,
+    _inst_point: InstPoint,
+    _sites: &mut SiteTable,
+) {
     dbg!("insert_extern_crate_item");
     // Insert use item for instrument crate
     mut_mod.items.insert(0, ptr::P(create_extern_crate_item()));
 }
 
 /// Inserts global scope initialization and finalization into the AST of the original program.
-pub fn insert_global_scope(mut_item: &mut ast::Item, inst_point: InstPoint) {
+/// The config used for compiling is embedded, the runtime falls back to it
+pub fn insert_global_scope(
+    mut_item: &mut ast::Item,
+    inst_point: InstPoint,
+    sites: &mut SiteTable,
+    embedded_config: &str,
+) {
     dbg!("insert global scope");
     if let ast::ItemKind::Fn(_, _, _, block) = &mut mut_item.node {
+        // Insert the config used for compiling
+        block
+            .stmts
+            .insert(0, create_embedded_config(embedded_config));
         // Insert actuall global init
-        block.stmts.insert(0, create_global_init());
+        block.stmts.insert(1, create_global_init());
         // Insert local init for main thread
-        block.stmts.insert(1, create_local_init());
+        block.stmts.insert(2, create_local_init());
         // Insert starting instrumentation call
         block.stmts.insert(
-            2,
-            create_instrumentation_call(&inst_point.static_data, DESCRIPTION_GLOBAL_BEGIN),
+            3,
+            create_instrumentation_call(sites, &inst_point.static_data, DESCRIPTION_GLOBAL_BEGIN),
         );
 
         // The entire code
 
         // Insert ending instrumentation call
         block.stmts.push(create_instrumentation_call(
+            sites,
             &inst_point.static_data,
             DESCRIPTION_GLOBAL_END,
         ));
         // Insert local clean up for main thread
         block.stmts.push(create_local_clean_up());
+        // Insert global clean up
+        block.stmts.push(create_global_clean_up());
     } else {
         // Should never be reached, InstFinder is resposible for ensuring correct type
         unreachable!()
@@ -65,198 +129,170 @@ pub fn insert_global_scope(mut_item: &mut ast::Item, inst_point: InstPoint) {
 }
 
 /// Inserts thread-local scope initialization and finalization into the AST of the original program.
-pub fn insert_local_scope(mut_expr: &mut ast::Expr, inst_point: InstPoint) {
+/// The parent thread is taken before the call and moved into the spawned closures
+pub fn insert_local_scope(mut_expr: &mut ast::Expr, inst_point: InstPoint, sites: &mut SiteTable) {
     dbg!("insert_local_scope");
     dbg!(inst_point.static_data.lines_begin);
-    if let ast::ExprKind::Call(_expr_path, arg_exprs) = &mut mut_expr.node {
-        for arg_expr in arg_exprs {
-            if let ast::ExprKind::Closure(_, _, _, _, closure_expr, _) = &mut arg_expr.node {
-                *closure_expr = ptr::P(ast::Expr {
-                    id: DUMMY_NODE_ID,
-                    node: ast::ExprKind::Block(
-                        ptr::P(ast::Block {
-                            stmts: vec![
-                                // Insert thread local init
-                                create_local_init(),
-                                // Insert thread local starting instrumentation call
-                                create_instrumentation_call(
-                                    &inst_point.static_data,
-                                    DESCRIPTION_LOCAL_BEGIN,
-                                ),
-                                // Capture the entire closure body in a block and saved to a return var
-                                ast::Stmt {
-                                    id: DUMMY_NODE_ID,
-                                    node: ast::StmtKind::Local(ptr::P(build_std_ast_local_ident(
-                                        NAME_OF_RETURN_VAR,
-                                        "",
-                                        Some(closure_expr.clone()),
-                                    ))),
-                                    span: DUMMY_SP,
-                                },
-                                // Insert thread local ending instrumentation call
-                                create_instrumentation_call(
-                                    &inst_point.static_data,
-                                    DESCRIPTION_LOCAL_END,
+    let arg_exprs = match &mut mut_expr.node {
+        ast::ExprKind::Call(_expr_path, arg_exprs) => arg_exprs,
+        ast::ExprKind::MethodCall(_expr_path, arg_exprs) => arg_exprs,
+        // Should never be reached, InstFinder is resposible for ensuring correct type
+        _ => unreachable!(),
+    };
+    for arg_expr in arg_exprs {
+        insert_spawned_closure(arg_expr, &inst_point.static_data, sites);
+    }
+    *mut_expr = ast::Expr {
+        id: DUMMY_NODE_ID,
+        node: ast::ExprKind::Block(
+            ptr::P(ast::Block {
+                stmts: vec![
+                    // Instrumentation call
+                    create_instrumentation_call(
+                        sites,
+                        &inst_point.static_data,
+                        DESCRIPTION_INST_CALL_BEGIN,
+                    ),
+                    // Parent of the spawned closures
+                    // "let instrumentation_parent_thread = instrument::current_thread();"
+                    ast::Stmt {
+                        id: DUMMY_NODE_ID,
+                        node: ast::StmtKind::Local(ptr::P(build_std_ast_local_ident(
+                            NAME_OF_PARENT_VAR,
+                            "",
+                            Some(build_call_expr(
+                                build_2_ast_path(
+                                    NAME_OF_INST_CRATE,
+                                    "",
+                                    NAME_OF_CURRENT_THREAD_FN,
+                                    "",
                                 ),
-                                // Insert thread local clean up
-                                // No clean_up needed, helper ends because channel sender ends
-                                // create_local_clean_up(),
-                                // Insert return of the original closure return value
-                                create_return_value(),
-                            ],
-                            id: DUMMY_NODE_ID,
-                            rules: ast::BlockCheckMode::Default,
-                            span: DUMMY_SP,
-                        }),
-                        None,
+                                Vec::new(),
+                            )),
+                        ))),
+                        span: DUMMY_SP,
+                    },
+                    // Store result of original expression for later return
+                    // "let value_2_return = original_expression;"
+                    ast::Stmt {
+                        id: DUMMY_NODE_ID,
+                        node: ast::StmtKind::Local(ptr::P(build_std_ast_local_ident(
+                            NAME_OF_RETURN_VAR,
+                            "",
+                            Some(ptr::P(mut_expr.clone())),
+                        ))),
+                        span: DUMMY_SP,
+                    },
+                    // Instrumentation call
+                    create_instrumentation_call(
+                        sites,
+                        &inst_point.static_data,
+                        DESCRIPTION_INST_CALL_END,
                     ),
-                    span: DUMMY_SP,
-                    attrs: ThinVec::new(),
-                });
-            }
+                    // Return result of original expression
+                    // "NAME_OF_RETURN_VAR"
+                    create_return_value(),
+                ],
+                id: DUMMY_NODE_ID,
+                // Safe block
+                rules: ast::BlockCheckMode::Default,
+                span: source_map::DUMMY_SP,
+            }),
+            None,
+        ),
+        span: DUMMY_SP,
+        attrs: ThinVec::new(),
+    };
+}
+
+/// Instruments a closure passed to a call starting thread-local scopes.
+/// Closures without parameters are wrapped, so their captures stay unchanged:
+/// `{ let mut instrumentation_spawned_fn = <closure>; move || { .. } }`, mutable for FnMut closures.
+/// The body of closures with parameters is instrumented in place, wrapping them would lose
+/// the parameter types inferred from the call
+fn insert_spawned_closure(
+    arg_expr: &mut ast::Expr,
+    static_data: &StaticData,
+    sites: &mut SiteTable,
+) {
+    let wrapper = match &arg_expr.node {
+        ast::ExprKind::Closure(_, ast::IsAsync::NotAsync, movability, decl, _, _)
+            if decl.inputs.is_empty() =>
+        {
+            Some((*movability, decl.clone()))
         }
-        *mut_expr = ast::Expr {
+        _ => None,
+    };
+    if let Some((movability, decl)) = wrapper {
+        let spawned_fn =
+            build_call_expr(build_std_ast_path(NAME_OF_SPAWNED_FN_VAR, ""), Vec::new());
+        let body = create_spawned_body(sites, static_data, spawned_fn);
+        *arg_expr = ast::Expr {
             id: DUMMY_NODE_ID,
             node: ast::ExprKind::Block(
                 ptr::P(ast::Block {
                     stmts: vec![
-                        // Instrumentation call
-                        create_instrumentation_call(
-                            &inst_point.static_data,
-                            DESCRIPTION_INST_CALL_BEGIN,
-                        ),
-                        // Store result of original expression for later return
-                        // "let value_2_return = original_expression;"
+                        // Mutable, the original closure may be FnMut
+                        // "let mut instrumentation_spawned_fn = original_closure;"
                         ast::Stmt {
                             id: DUMMY_NODE_ID,
-                            node: ast::StmtKind::Local(ptr::P(build_std_ast_local_ident(
-                                NAME_OF_RETURN_VAR,
+                            node: ast::StmtKind::Local(ptr::P(build_mut_ast_local_ident(
+                                NAME_OF_SPAWNED_FN_VAR,
                                 "",
-                                Some(ptr::P(mut_expr.clone())),
+                                Some(ptr::P(arg_expr.clone())),
                             ))),
                             span: DUMMY_SP,
                         },
-                        // Instrumentation call
-                        create_instrumentation_call(
-                            &inst_point.static_data,
-                            DESCRIPTION_INST_CALL_END,
-                        ),
-                        // Return result of original expression
-                        // "NAME_OF_RETURN_VAR"
-                        create_return_value(),
-                    ],
-                    id: DUMMY_NODE_ID,
-                    // Safe block
-                    rules: ast::BlockCheckMode::Default,
-                    span: source_map::DUMMY_SP,
-                }),
-                None,
-            ),
-            span: DUMMY_SP,
-            attrs: ThinVec::new(),
-        };
-    } else if let ast::ExprKind::MethodCall(_expr_path, arg_exprs) = &mut mut_expr.node {
-        for arg_expr in arg_exprs {
-            if let ast::ExprKind::Closure(_, _, _, _, closure_expr, _) = &mut arg_expr.node {
-                *closure_expr = ptr::P(ast::Expr {
-                    id: DUMMY_NODE_ID,
-                    node: ast::ExprKind::Block(
-                        ptr::P(ast::Block {
-                            stmts: vec![
-                                // Insert thread local init
-                                create_local_init(),
-                                // Insert thread local starting instrumentation call
-                                create_instrumentation_call(
-                                    &inst_point.static_data,
-                                    DESCRIPTION_LOCAL_BEGIN,
-                                ),
-                                // Capture the entire closure body in a block and saved to a return var
-                                ast::Stmt {
-                                    id: DUMMY_NODE_ID,
-                                    node: ast::StmtKind::Local(ptr::P(build_std_ast_local_ident(
-                                        NAME_OF_RETURN_VAR,
-                                        "",
-                                        Some(closure_expr.clone()),
-                                    ))),
-                                    span: DUMMY_SP,
-                                },
-                                // Insert thread local ending instrumentation call
-                                create_instrumentation_call(
-                                    &inst_point.static_data,
-                                    DESCRIPTION_LOCAL_END,
-                                ),
-                                // Insert thread local clean up
-                                // No clean_up needed, helper ends because channel sender ends
-                                // create_local_clean_up(),
-                                // Insert return of the original closure return value
-                                create_return_value(),
-                            ],
-                            id: DUMMY_NODE_ID,
-                            rules: ast::BlockCheckMode::Default,
-                            span: DUMMY_SP,
-                        }),
-                        None,
-                    ),
-                    span: DUMMY_SP,
-                    attrs: ThinVec::new(),
-                });
-            }
-        }
-        *mut_expr = ast::Expr {
-            id: DUMMY_NODE_ID,
-            node: ast::ExprKind::Block(
-                ptr::P(ast::Block {
-                    stmts: vec![
-                        // Instrumentation call
-                        create_instrumentation_call(
-                            &inst_point.static_data,
-                            DESCRIPTION_INST_CALL_BEGIN,
-                        ),
-                        // Store result of original expression for later return
-                        // "let value_2_return = original_expression;"
+                        // Closure moving the original closure and the parent thread
                         ast::Stmt {
                             id: DUMMY_NODE_ID,
-                            node: ast::StmtKind::Local(ptr::P(build_std_ast_local_ident(
-                                NAME_OF_RETURN_VAR,
-                                "",
-                                Some(ptr::P(mut_expr.clone())),
-                            ))),
+                            node: ast::StmtKind::Expr(ptr::P(ast::Expr {
+                                id: DUMMY_NODE_ID,
+                                node: ast::ExprKind::Closure(
+                                    ast::CaptureBy::Value,
+                                    ast::IsAsync::NotAsync,
+                                    movability,
+                                    decl,
+                                    body,
+                                    DUMMY_SP,
+                                ),
+                                span: DUMMY_SP,
+                                attrs: ThinVec::new(),
+                            })),
                             span: DUMMY_SP,
                         },
-                        // Instrumentation call
-                        create_instrumentation_call(
-                            &inst_point.static_data,
-                            DESCRIPTION_INST_CALL_END,
-                        ),
-                        // Return result of original expression
-                        // "NAME_OF_RETURN_VAR"
-                        create_return_value(),
                     ],
                     id: DUMMY_NODE_ID,
-                    // Safe block
                     rules: ast::BlockCheckMode::Default,
-                    span: source_map::DUMMY_SP,
+                    span: DUMMY_SP,
                 }),
                 None,
             ),
             span: DUMMY_SP,
             attrs: ThinVec::new(),
         };
-    } else {
-        // Should never be reached, InstFinder is resposible for ensuring correct type
-        unreachable!()
+    } else if let ast::ExprKind::Closure(_, _, _, _, closure_expr, _) = &mut arg_expr.node {
+        *closure_expr = create_spawned_body(sites, static_data, closure_expr.clone());
     }
 }
 
 /// Inserts instrumentation arround function calls.
-pub fn insert_inst_call_function(mut_expr: &mut ast::Expr, inst_point: InstPoint) {
+pub fn insert_inst_call_function(
+    mut_expr: &mut ast::Expr,
+    inst_point: InstPoint,
+    sites: &mut SiteTable,
+) {
     dbg!("insert_inst_call_function");
     if let ast::ExprKind::Call(func, args_vec) = &mut mut_expr.node {
         // Extract arguments of interesting method call
         let (mut block, args_vars) = extract_arguments(args_vec.clone());
         let mut stmts_vec = vec![
             // Instrumentation call
-            create_instrumentation_call(&inst_point.static_data, DESCRIPTION_INST_CALL_BEGIN),
+            create_instrumentation_call(
+                sites,
+                &inst_point.static_data,
+                DESCRIPTION_INST_CALL_BEGIN,
+            ),
             // Store result of original expression for later return
             // "let value_2_return = original_expression;"
             ast::Stmt {
@@ -274,7 +310,7 @@ pub fn insert_inst_call_function(mut_expr: &mut ast::Expr, inst_point: InstPoint
                 span: DUMMY_SP,
             },
             // Instrumentation call
-            create_instrumentation_call(&inst_point.static_data, DESCRIPTION_INST_CALL_END),
+            create_instrumentation_call(sites, &inst_point.static_data, DESCRIPTION_INST_CALL_END),
             // Return result of original expression
             // "NAME_OF_RETURN_VAR"
             create_return_value(),
@@ -304,7 +340,11 @@ pub fn insert_inst_call_function(mut_expr: &mut ast::Expr, inst_point: InstPoint
 }
 
 /// Inserts instrumentation arround method calls.
-pub fn insert_inst_call_method(mut_expr: &mut ast::Expr, inst_point: InstPoint) {
+pub fn insert_inst_call_method(
+    mut_expr: &mut ast::Expr,
+    inst_point: InstPoint,
+    sites: &mut SiteTable,
+) {
     dbg!("insert_inst_call_method");
     if let ast::ExprKind::MethodCall(path_s, args) = &mut mut_expr.node {
         // Unwind Method chain
@@ -322,11 +362,36 @@ pub fn insert_inst_call_method(mut_expr: &mut ast::Expr, inst_point: InstPoint)
         let (mut extracted_args, mut args_vars) = extract_arguments(args.clone());
         // Insert extracted arguments to block
         block.append(&mut extracted_args);
+        // Sends and receives number their message before the call. Only handles of wrapped
+        // channels have one, handles which are not a plain place would be evaluated twice
+        let channel_fn = channel_instrumentation_fn(&path_s.ident.as_str())
+            .filter(|_| is_method_chain || is_plain_place(&called_on));
         // Instrumentation call
-        block.push(create_instrumentation_call(
-            &inst_point.static_data,
-            DESCRIPTION_INST_CALL_BEGIN,
-        ));
+        block.push(match channel_fn {
+            Some(channel_fn) => create_channel_instrumentation_call(
+                sites,
+                &inst_point.static_data,
+                channel_fn,
+                if is_method_chain {
+                    ptr::P(ast::Expr {
+                        id: DUMMY_NODE_ID,
+                        node: ast::ExprKind::Path(
+                            None,
+                            build_std_ast_path(NAME_OF_INTERMEDIATE_VAR, "0"),
+                        ),
+                        span: DUMMY_SP,
+                        attrs: ThinVec::new(),
+                    })
+                } else {
+                    ptr::P(called_on.clone())
+                },
+            ),
+            None => create_instrumentation_call(
+                sites,
+                &inst_point.static_data,
+                DESCRIPTION_INST_CALL_BEGIN,
+            ),
+        });
         // MethodCall of interest gets binded to return value
         block.push(ast::Stmt {
             id: DUMMY_NODE_ID,
@@ -363,6 +428,7 @@ pub fn insert_inst_call_method(mut_expr: &mut ast::Expr, inst_point: InstPoint)
         });
         // Instrumentation call
         block.push(create_instrumentation_call(
+            sites,
             &inst_point.static_data,
             DESCRIPTION_INST_CALL_END,
         ));
@@ -392,11 +458,221 @@ pub fn insert_inst_call_method(mut_expr: &mut ast::Expr, inst_point: InstPoint)
     }
 }
 
+/// Wraps the handles returned by a channel constructor: `instrument::channel(original_call)`.
+/// The wrapped handles carry the identity and message counters of their channel
+pub fn insert_channel_constructor(mut_expr: &mut ast::Expr, wrapper: &str) {
+    *mut_expr = ast::Expr {
+        id: DUMMY_NODE_ID,
+        node: ast::ExprKind::Call(
+            ptr::P(ast::Expr {
+                id: DUMMY_NODE_ID,
+                node: ast::ExprKind::Path(
+                    None,
+                    build_2_ast_path(NAME_OF_INST_CRATE, "", wrapper, ""),
+                ),
+                span: DUMMY_SP,
+                attrs: ThinVec::new(),
+            }),
+            vec![ptr::P(mut_expr.clone())],
+        ),
+        span: mut_expr.span,
+        attrs: ThinVec::new(),
+    };
+}
+
+/// Replaces a channel handle type by the wrapping type: `instrument::Sender<original_type>`
+pub fn insert_channel_type(mut_ty: &mut ast::Ty, wrapper: &str) {
+    let generic_args = ast::GenericArgs::AngleBracketed(ast::AngleBracketedArgs {
+        span: DUMMY_SP,
+        args: vec![ast::GenericArg::Type(ptr::P(mut_ty.clone()))],
+        bindings: Vec::new(),
+    });
+    *mut_ty = ast::Ty {
+        id: DUMMY_NODE_ID,
+        node: ast::TyKind::Path(
+            None,
+            ast::Path {
+                span: DUMMY_SP,
+                segments: vec![
+                    build_ast_pathsegment(NAME_OF_INST_CRATE, "", None),
+                    build_ast_pathsegment(wrapper, "", Some(ptr::P(generic_args))),
+                ],
+            },
+        ),
+        span: mut_ty.span,
+    };
+}
+
 // Create functions
 // ------------------------------------------------------------------------------------------------
 
-/// Creates global init AST structure.
+/// Creates global init AST structure: `instrument::global_init(INSTRUMENTATION_EMBEDDED_CONFIG);`
 fn create_global_init() -> ast::Stmt {
+    create_probe_call(
+        NAME_OF_GLOBAL_INIT_FN,
+        vec![ptr::P(ast::Expr {
+            id: DUMMY_NODE_ID,
+            node: ast::ExprKind::Path(None, build_std_ast_path(NAME_OF_EMBEDDED_CONFIG, "")),
+            span: DUMMY_SP,
+            attrs: ThinVec::new(),
+        })],
+    )
+}
+
+/// Creates the embedded config AST structure: `static INSTRUMENTATION_EMBEDDED_CONFIG: &str = "..";`
+fn create_embedded_config(config: &str) -> ast::Stmt {
+    let item = ast::Item {
+        ident: build_ast_ident(NAME_OF_EMBEDDED_CONFIG, ""),
+        attrs: Vec::new(),
+        id: DUMMY_NODE_ID,
+        node: ast::ItemKind::Static(
+            // Type: &str
+            ptr::P(ast::Ty {
+                id: DUMMY_NODE_ID,
+                node: ast::TyKind::Rptr(
+                    None,
+                    ast::MutTy {
+                        ty: ptr::P(ast::Ty {
+                            id: DUMMY_NODE_ID,
+                            node: ast::TyKind::Path(None, build_std_ast_path("str", "")),
+                            span: DUMMY_SP,
+                        }),
+                        mutbl: ast::Mutability::Immutable,
+                    },
+                ),
+                span: DUMMY_SP,
+            }),
+            ast::Mutability::Immutable,
+            build_str_lit_expr(config),
+        ),
+        vis: source_map::Spanned {
+            node: ast::VisibilityKind::Inherited,
+            span: DUMMY_SP,
+        },
+        span: DUMMY_SP,
+        tokens: None,
+    };
+    ast::Stmt {
+        id: DUMMY_NODE_ID,
+        node: ast::StmtKind::Item(ptr::P(item)),
+        span: DUMMY_SP,
+    }
+}
+
+/// Creates local init AST structure.
+fn create_local_init() -> ast::Stmt {
+    create_probe_call(NAME_OF_LOCAL_INIT_FN, Vec::new())
+}
+
+/// Creates clean up AST structure.
+fn create_local_clean_up() -> ast::Stmt {
+    create_probe_call(NAME_OF_LOCAL_CLEAN_UP_FN, Vec::new())
+}
+
+/// Creates global clean up AST structure, sends the events of all threads.
+fn create_global_clean_up() -> ast::Stmt {
+    create_probe_call(NAME_OF_GLOBAL_CLEAN_UP_FN, Vec::new())
+}
+
+/// Creates instrumentation call AST structure.
+/// The call refers to its entry in the site table of the crate (`crate::INSTRUMENTATION_SITES`)
+fn create_instrumentation_call(
+    sites: &mut SiteTable,
+    static_data: &StaticData,
+    description: &str,
+) -> ast::Stmt {
+    create_probe_call(
+        NAME_OF_INSTRUMENT_CALL_FN,
+        vec![create_site_ref(sites, static_data, description)],
+    )
+}
+
+/// Creates instrumentation call AST structure starting a channel operation, passes the channel
+/// of the handle: `instrument::instrument_send(&.., instrument::ChannelProbe(&handle).channel());`
+fn create_channel_instrumentation_call(
+    sites: &mut SiteTable,
+    static_data: &StaticData,
+    channel_fn: &str,
+    handle: ptr::P<ast::Expr>,
+) -> ast::Stmt {
+    create_probe_call(
+        channel_fn,
+        vec![
+            create_site_ref(sites, static_data, DESCRIPTION_INST_CALL_BEGIN),
+            create_channel_probe(handle),
+        ],
+    )
+}
+
+/// Creates the body of a spawned closure around the original body
+fn create_spawned_body(
+    sites: &mut SiteTable,
+    static_data: &StaticData,
+    body: ptr::P<ast::Expr>,
+) -> ptr::P<ast::Expr> {
+    ptr::P(ast::Expr {
+        id: DUMMY_NODE_ID,
+        node: ast::ExprKind::Block(
+            ptr::P(ast::Block {
+                stmts: vec![
+                    // Insert thread local init
+                    create_local_init(),
+                    // Insert thread local starting instrumentation call,
+                    // refers to the spawning call and the parent thread
+                    create_spawned_instrumentation_call(sites, static_data),
+                    // Capture the entire closure body in a block and saved to a return var
+                    ast::Stmt {
+                        id: DUMMY_NODE_ID,
+                        node: ast::StmtKind::Local(ptr::P(build_std_ast_local_ident(
+                            NAME_OF_RETURN_VAR,
+                            "",
+                            Some(body),
+                        ))),
+                        span: DUMMY_SP,
+                    },
+                    // Insert thread local ending instrumentation call
+                    create_instrumentation_call(sites, static_data, DESCRIPTION_LOCAL_END),
+                    // Insert thread local clean up
+                    // No clean_up needed, the stream ends when the thread exits
+                    // create_local_clean_up(),
+                    // Insert return of the original closure return value
+                    create_return_value(),
+                ],
+                id: DUMMY_NODE_ID,
+                rules: ast::BlockCheckMode::Default,
+                span: DUMMY_SP,
+            }),
+            None,
+        ),
+        span: DUMMY_SP,
+        attrs: ThinVec::new(),
+    })
+}
+
+/// Creates instrumentation call AST structure starting a spawned closure, refers to the
+/// site of the spawning call and the parent thread:
+/// `instrument::instrument_spawned(&.., &.., instrumentation_parent_thread);`
+fn create_spawned_instrumentation_call(
+    sites: &mut SiteTable,
+    static_data: &StaticData,
+) -> ast::Stmt {
+    create_probe_call(
+        NAME_OF_INSTRUMENT_SPAWNED_FN,
+        vec![
+            create_site_ref(sites, static_data, DESCRIPTION_LOCAL_BEGIN),
+            create_site_ref(sites, static_data, DESCRIPTION_INST_CALL_BEGIN),
+            ptr::P(ast::Expr {
+                id: DUMMY_NODE_ID,
+                node: ast::ExprKind::Path(None, build_std_ast_path(NAME_OF_PARENT_VAR, "")),
+                span: DUMMY_SP,
+                attrs: ThinVec::new(),
+            }),
+        ],
+    )
+}
+
+/// Creates the statement calling a function of the instrument crate: `instrument::name(args);`
+fn create_probe_call(fn_name: &str, args: Vec<ptr::P<ast::Expr>>) -> ast::Stmt {
     ast::Stmt {
         id: DUMMY_NODE_ID,
         node: ast::StmtKind::Semi(ptr::P(ast::Expr {
@@ -406,12 +682,12 @@ fn create_global_init() -> ast::Stmt {
                     id: DUMMY_NODE_ID,
                     node: ast::ExprKind::Path(
                         None,
-                        build_2_ast_path(NAME_OF_INST_CRATE, "", NAME_OF_GLOBAL_INIT_FN, ""),
+                        build_2_ast_path(NAME_OF_INST_CRATE, "", fn_name, ""),
                     ),
                     span: DUMMY_SP,
                     attrs: ThinVec::new(),
                 }),
-                Vec::new(),
+                args,
             ),
             span: DUMMY_SP,
             attrs: ThinVec::new(),
@@ -420,186 +696,182 @@ fn create_global_init() -> ast::Stmt {
     }
 }
 
-/// Creates local init AST structure.
-fn create_local_init() -> ast::Stmt {
-    ast::Stmt {
+/// Creates the reference to the site of a probe: `&crate::INSTRUMENTATION_SITES[index]`
+fn create_site_ref(
+    sites: &mut SiteTable,
+    static_data: &StaticData,
+    description: &str,
+) -> ptr::P<ast::Expr> {
+    let site_index = sites.add(static_data, description);
+    ptr::P(ast::Expr {
         id: DUMMY_NODE_ID,
-        node: ast::StmtKind::Local(ptr::P(build_std_ast_local_ident(
-            NAME_OF_LOCAL_THREAD_HANDLE_VAR,
-            "",
-            Some(ptr::P(ast::Expr {
+        node: ast::ExprKind::AddrOf(
+            ast::Mutability::Immutable,
+            ptr::P(ast::Expr {
                 id: DUMMY_NODE_ID,
-                node: ast::ExprKind::Call(
+                node: ast::ExprKind::Index(
                     ptr::P(ast::Expr {
                         id: DUMMY_NODE_ID,
                         node: ast::ExprKind::Path(
                             None,
-                            build_2_ast_path(NAME_OF_INST_CRATE, "", NAME_OF_LOCAL_INIT_FN, ""),
+                            build_2_ast_path(NAME_OF_CRATE_ROOT, "", NAME_OF_SITE_TABLE, ""),
                         ),
                         span: DUMMY_SP,
                         attrs: ThinVec::new(),
                     }),
-                    Vec::new(),
+                    build_int_lit_expr(
+                        site_index as u128,
+                        ast::LitIntType::Unsigned(ast::UintTy::Usize),
+                    ),
                 ),
                 span: DUMMY_SP,
                 attrs: ThinVec::new(),
-            })),
-        ))),
+            }),
+        ),
         span: DUMMY_SP,
-    }
+        attrs: ThinVec::new(),
+    })
 }
 
-/// Creates clean up AST structure.
-fn create_local_clean_up() -> ast::Stmt {
-    ast::Stmt {
+/// Creates channel AST structure: `instrument::ChannelProbe(&handle).channel()`
+fn create_channel_probe(handle: ptr::P<ast::Expr>) -> ptr::P<ast::Expr> {
+    ptr::P(ast::Expr {
         id: DUMMY_NODE_ID,
-        node: ast::StmtKind::Semi(ptr::P(ast::Expr {
-            id: DUMMY_NODE_ID,
-            node: ast::ExprKind::Call(
-                ptr::P(ast::Expr {
-                    id: DUMMY_NODE_ID,
-                    node: ast::ExprKind::Path(
-                        None,
-                        build_2_ast_path(NAME_OF_INST_CRATE, "", NAME_OF_LOCAL_CLEAN_UP_FN, ""),
-                    ),
-                    span: DUMMY_SP,
-                    attrs: ThinVec::new(),
-                }),
-                vec![ptr::P(ast::Expr {
-                    id: DUMMY_NODE_ID,
-                    node: ast::ExprKind::Path(
-                        None,
-                        build_std_ast_path(NAME_OF_LOCAL_THREAD_HANDLE_VAR, ""),
-                    ),
-                    span: DUMMY_SP,
-                    attrs: ThinVec::new(),
-                })],
-            ),
-            span: DUMMY_SP,
-            attrs: ThinVec::new(),
-        })),
+        node: ast::ExprKind::MethodCall(
+            build_ast_pathsegment(NAME_OF_CHANNEL_PROBE_METHOD, "", None),
+            vec![ptr::P(ast::Expr {
+                id: DUMMY_NODE_ID,
+                node: ast::ExprKind::Call(
+                    ptr::P(ast::Expr {
+                        id: DUMMY_NODE_ID,
+                        node: ast::ExprKind::Path(
+                            None,
+                            build_2_ast_path(
+                                NAME_OF_INST_CRATE,
+                                "",
+                                NAME_OF_CHANNEL_PROBE_STRUCT,
+                                "",
+                            ),
+                        ),
+                        span: DUMMY_SP,
+                        attrs: ThinVec::new(),
+                    }),
+                    vec![ptr::P(ast::Expr {
+                        id: DUMMY_NODE_ID,
+                        node: ast::ExprKind::AddrOf(ast::Mutability::Immutable, handle),
+                        span: DUMMY_SP,
+                        attrs: ThinVec::new(),
+                    })],
+                ),
+                span: DUMMY_SP,
+                attrs: ThinVec::new(),
+            })],
+        ),
         span: DUMMY_SP,
-    }
+        attrs: ThinVec::new(),
+    })
 }
 
-/// Creates instrumentation call AST structure.
-fn create_instrumentation_call(static_data: &StaticData, description: &str) -> ast::Stmt {
-    ast::Stmt {
+/// Creates the site table AST structure: `static SITES: &[instrument::Site] = &[...];`
+fn create_site_table(sites: SiteTable) -> ast::Item {
+    let site_path = build_2_ast_path(NAME_OF_INST_CRATE, "", NAME_OF_SITE_STRUCT, "");
+    let site_exprs = sites
+        .sites
+        .into_iter()
+        .map(|site| {
+            ptr::P(ast::Expr {
+                id: DUMMY_NODE_ID,
+                node: ast::ExprKind::Struct(
+                    site_path.clone(),
+                    vec![
+                        build_ast_field(
+                            "id",
+                            build_int_lit_expr(
+                                u128::from(site.site_id),
+                                ast::LitIntType::Unsigned(ast::UintTy::U64),
+                            ),
+                        ),
+                        build_ast_field("absolute_path", build_str_lit_expr(&site.absolute_path)),
+                        build_ast_field("description", build_str_lit_expr(&site.description)),
+                        build_ast_field(
+                            "ast_depth",
+                            build_int_lit_expr(
+                                site.ast_depth,
+                                ast::LitIntType::Unsigned(ast::UintTy::U128),
+                            ),
+                        ),
+                        build_ast_field("source_file", build_str_lit_expr(&site.source_file)),
+                        build_ast_field(
+                            "lines_begin",
+                            build_int_lit_expr(
+                                site.lines_begin,
+                                ast::LitIntType::Unsigned(ast::UintTy::U128),
+                            ),
+                        ),
+                        build_ast_field(
+                            "lines_end",
+                            build_int_lit_expr(
+                                site.lines_end,
+                                ast::LitIntType::Unsigned(ast::UintTy::U128),
+                            ),
+                        ),
+                    ],
+                    None,
+                ),
+                span: DUMMY_SP,
+                attrs: ThinVec::new(),
+            })
+        })
+        .collect();
+
+    ast::Item {
+        ident: build_ast_ident(NAME_OF_SITE_TABLE, ""),
+        attrs: Vec::new(),
         id: DUMMY_NODE_ID,
-        node: ast::StmtKind::Semi(ptr::P(ast::Expr {
-            id: DUMMY_NODE_ID,
-            node: ast::ExprKind::Call(
-                ptr::P(ast::Expr {
-                    id: DUMMY_NODE_ID,
-                    node: ast::ExprKind::Path(
-                        None,
-                        build_2_ast_path(NAME_OF_INST_CRATE, "", NAME_OF_INSTRUMENT_CALL_FN, ""),
-                    ),
-                    span: DUMMY_SP,
-                    attrs: ThinVec::new(),
-                }),
-                vec![ptr::P(ast::Expr {
-                    id: DUMMY_NODE_ID,
-                    node: ast::ExprKind::Call(
-                        ptr::P(ast::Expr {
+        node: ast::ItemKind::Static(
+            // Type: &[instrument::Site]
+            ptr::P(ast::Ty {
+                id: DUMMY_NODE_ID,
+                node: ast::TyKind::Rptr(
+                    None,
+                    ast::MutTy {
+                        ty: ptr::P(ast::Ty {
                             id: DUMMY_NODE_ID,
-                            node: ast::ExprKind::Path(
-                                None,
-                                ast::Path {
-                                    span: DUMMY_SP,
-                                    segments: vec![
-                                        build_ast_pathsegment(NAME_OF_INST_CRATE, "", None),
-                                        build_ast_pathsegment(NAME_OF_STATICDATA_STRUCT, "", None),
-                                        build_ast_pathsegment(NAME_OF_STATICDATA_NEW_FN, "", None),
-                                    ],
-                                },
-                            ),
-                            span: DUMMY_SP,
-                            attrs: ThinVec::new(),
-                        }),
-                        vec![
-                            ptr::P(ast::Expr {
-                                id: DUMMY_NODE_ID,
-                                node: ast::ExprKind::Lit(source_map::Spanned {
-                                    node: ast::LitKind::Str(
-                                        Symbol::intern(static_data.absolute_path.as_str()),
-                                        ast::StrStyle::Cooked,
-                                    ),
-                                    span: DUMMY_SP,
-                                }),
-                                span: DUMMY_SP,
-                                attrs: ThinVec::new(),
-                            }),
-                            ptr::P(ast::Expr {
-                                id: DUMMY_NODE_ID,
-                                node: ast::ExprKind::Lit(source_map::Spanned {
-                                    node: ast::LitKind::Str(
-                                        Symbol::intern(description),
-                                        ast::StrStyle::Cooked,
-                                    ),
-                                    span: DUMMY_SP,
-                                }),
-                                span: DUMMY_SP,
-                                attrs: ThinVec::new(),
-                            }),
-                            ptr::P(ast::Expr {
+                            node: ast::TyKind::Slice(ptr::P(ast::Ty {
                                 id: DUMMY_NODE_ID,
-                                node: ast::ExprKind::Lit(source_map::Spanned {
-                                    node: ast::LitKind::Int(
-                                        static_data.ast_depth as u128,
-                                        ast::LitIntType::Unsigned(ast::UintTy::U128),
-                                    ),
-                                    span: DUMMY_SP,
-                                }),
-                                span: DUMMY_SP,
-                                attrs: ThinVec::new(),
-                            }),
-                            ptr::P(ast::Expr {
-                                id: DUMMY_NODE_ID,
-                                node: ast::ExprKind::Lit(source_map::Spanned {
-                                    node: ast::LitKind::Str(
-                                        Symbol::intern(static_data.source_file.as_str()),
-                                        ast::StrStyle::Cooked,
-                                    ),
-                                    span: DUMMY_SP,
-                                }),
-                                span: DUMMY_SP,
-                                attrs: ThinVec::new(),
-                            }),
-                            ptr::P(ast::Expr {
-                                id: DUMMY_NODE_ID,
-                                node: ast::ExprKind::Lit(source_map::Spanned {
-                                    node: ast::LitKind::Int(
-                                        static_data.lines_begin as u128,
-                                        ast::LitIntType::Unsigned(ast::UintTy::U128),
-                                    ),
-                                    span: DUMMY_SP,
-                                }),
-                                span: DUMMY_SP,
-                                attrs: ThinVec::new(),
-                            }),
-                            ptr::P(ast::Expr {
-                                id: DUMMY_NODE_ID,
-                                node: ast::ExprKind::Lit(source_map::Spanned {
-                                    node: ast::LitKind::Int(
-                                        static_data.lines_end as u128,
-                                        ast::LitIntType::Unsigned(ast::UintTy::U128),
-                                    ),
-                                    span: DUMMY_SP,
-                                }),
+                                node: ast::TyKind::Path(None, site_path),
                                 span: DUMMY_SP,
-                                attrs: ThinVec::new(),
-                            }),
-                        ],
-                    ),
-                    span: DUMMY_SP,
-                    attrs: ThinVec::new(),
-                })],
-            ),
+                            })),
+                            span: DUMMY_SP,
+                        }),
+                        mutbl: ast::Mutability::Immutable,
+                    },
+                ),
+                span: DUMMY_SP,
+            }),
+            ast::Mutability::Immutable,
+            // Value: &[instrument::Site { .. }, ..]
+            ptr::P(ast::Expr {
+                id: DUMMY_NODE_ID,
+                node: ast::ExprKind::AddrOf(
+                    ast::Mutability::Immutable,
+                    ptr::P(ast::Expr {
+                        id: DUMMY_NODE_ID,
+                        node: ast::ExprKind::Array(site_exprs),
+                        span: DUMMY_SP,
+                        attrs: ThinVec::new(),
+                    }),
+                ),
+                span: DUMMY_SP,
+                attrs: ThinVec::new(),
+            }),
+        ),
+        vis: source_map::Spanned {
+            node: ast::VisibilityKind::Inherited,
             span: DUMMY_SP,
-            attrs: ThinVec::new(),
-        })),
+        },
         span: DUMMY_SP,
+        tokens: None,
     }
 }
 
@@ -668,6 +940,24 @@ fn extract_arguments(
     (extracted_args, args_vars)
 }
 
+/// Instrumentation function starting a channel operation, if the method is one
+fn channel_instrumentation_fn(method_name: &str) -> Option<&'static str> {
+    match method_name {
+        "send" => Some(NAME_OF_INSTRUMENT_SEND_FN),
+        "recv" => Some(NAME_OF_INSTRUMENT_RECV_FN),
+        _ => None,
+    }
+}
+
+/// Checks if an expression is a variable or a field of one, which can be evaluated twice
+fn is_plain_place(expr: &ast::Expr) -> bool {
+    match &expr.node {
+        ast::ExprKind::Path(..) => true,
+        ast::ExprKind::Field(base, _) | ast::ExprKind::Paren(base) => is_plain_place(base),
+        _ => false,
+    }
+}
+
 /// Unwinds a chained methods.
 fn unwind_method_chain(expr: ast::Expr) -> Vec<ast::Stmt> {
     fn unwind_result_chain(
@@ -803,6 +1093,90 @@ fn build_ast_ident(var_name: &str, var_counter: &str) -> ast::Ident {
     }
 }
 
+/// Convenience function builds a call expression of the function at the path
+fn build_call_expr(path: ast::Path, args: Vec<ptr::P<ast::Expr>>) -> ptr::P<ast::Expr> {
+    ptr::P(ast::Expr {
+        id: DUMMY_NODE_ID,
+        node: ast::ExprKind::Call(
+            ptr::P(ast::Expr {
+                id: DUMMY_NODE_ID,
+                node: ast::ExprKind::Path(None, path),
+                span: DUMMY_SP,
+                attrs: ThinVec::new(),
+            }),
+            args,
+        ),
+        span: DUMMY_SP,
+        attrs: ThinVec::new(),
+    })
+}
+
+/// Convenience function builds a string literal expression
+fn build_str_lit_expr(value: &str) -> ptr::P<ast::Expr> {
+    ptr::P(ast::Expr {
+        id: DUMMY_NODE_ID,
+        node: ast::ExprKind::Lit(source_map::Spanned {
+            node: ast::LitKind::Str(Symbol::intern(value), ast::StrStyle::Cooked),
+            span: DUMMY_SP,
+        }),
+        span: DUMMY_SP,
+        attrs: ThinVec::new(),
+    })
+}
+
+/// Convenience function builds an integer literal expression
+fn build_int_lit_expr(value: u128, int_type: ast::LitIntType) -> ptr::P<ast::Expr> {
+    ptr::P(ast::Expr {
+        id: DUMMY_NODE_ID,
+        node: ast::ExprKind::Lit(source_map::Spanned {
+            node: ast::LitKind::Int(value, int_type),
+            span: DUMMY_SP,
+        }),
+        span: DUMMY_SP,
+        attrs: ThinVec::new(),
+    })
+}
+
+/// Convenience function builds a Field struct of a struct expression
+fn build_ast_field(field_name: &str, expr: ptr::P<ast::Expr>) -> ast::Field {
+    ast::Field {
+        ident: build_ast_ident(field_name, ""),
+        expr,
+        span: DUMMY_SP,
+        is_shorthand: false,
+        attrs: ThinVec::new(),
+    }
+}
+
+/// Convenience function builds a mutable Local struct. Allows `unused_mut`,
+/// the binding is only mutated if the bound value needs it
+fn build_mut_ast_local_ident(
+    var_name: &str,
+    var_counter: &str,
+    init_expr: Option<ptr::P<ast::Expr>>,
+) -> ast::Local {
+    let mut local = build_std_ast_local_ident(var_name, var_counter, init_expr);
+    local.pat = ptr::P(ast::Pat {
+        id: DUMMY_NODE_ID,
+        node: ast::PatKind::Ident(
+            ast::BindingMode::ByValue(ast::Mutability::Mutable),
+            build_ast_ident(var_name, var_counter),
+            None,
+        ),
+        span: DUMMY_SP,
+    });
+    local.attrs = ThinVec::from(vec![attr::mk_attr_outer(
+        DUMMY_SP,
+        attr::mk_attr_id(),
+        attr::mk_list_item(
+            DUMMY_SP,
+            build_ast_ident("allow", ""),
+            vec![attr::mk_nested_word_item(build_ast_ident("unused_mut", ""))],
+        ),
+    )]);
+    local
+}
+
 /// Convenience function builds a Local struct
 fn build_std_ast_local_ident(
     var_name: &str,
@@ -827,3 +1201,44 @@ fn build_std_ast_local_ident(
         attrs: ThinVec::new(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instfinder::InstKind;
+    use syntax::parse::{self, ParseSess};
+    use syntax::print::pprust;
+    use syntax::source_map::{FileName, FilePathMapping};
+
+    #[test]
+    fn wraps_fn_mut_closures_in_mutable_bindings() {
+        syntax::with_globals(|| {
+            let source = "rayon::join(|| left.push(1), || right.push(2))";
+            let sess = ParseSess::new(FilePathMapping::empty());
+            let mut expr = parse::new_parser_from_source_str(
+                &sess,
+                FileName::Custom(String::from("test")),
+                String::from(source),
+            )
+            .parse_expr()
+            .unwrap_or_else(|_| panic!("Unable to parse {}", source))
+            .into_inner();
+            let original = expr.clone();
+            let inst_point = InstPoint {
+                point: InstKind::LocalScope(&original),
+                absolute_path: String::from("rayon::join"),
+                ast_pos: (0, 0, 0),
+                static_data: StaticData::new("rayon::join", "", 1, "main.rs", 1, 1),
+            };
+            let mut sites = SiteTable::new();
+            insert_local_scope(&mut expr, inst_point, &mut sites);
+
+            // Both closures are moved into mutable bindings and called through them
+            let instrumented = pprust::expr_to_string(&expr);
+            let count = |text: &str| instrumented.matches(text).count();
+            assert_eq!(count("let mut instrumentation_spawned_fn ="), 2);
+            assert_eq!(count("allow(unused_mut)"), 2);
+            assert_eq!(count("instrumentation_spawned_fn()"), 2);
+        });
+    }
+}
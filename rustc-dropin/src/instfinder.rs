@@ -1,9 +1,16 @@
 use std::cmp::Ordering;
-use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use syntax::{ast, source_map, visit};
 
-use crate::insertfuncs;
-use instrument::StaticData;
+use crate::insertfuncs::{self, SiteTable};
+use crate::pattern::Pattern;
+use instrument::{Behaviour, StaticData};
+
+/// Modules whose channel constructors and handle types are wrapped by the instrument crate,
+/// so the messages of their channels can be matched
+const CHANNEL_MODULES: [&str; 3] = ["std::sync::mpsc", "crossbeam_channel", "crossbeam::channel"];
+/// Channel operations which number their messages
+const CHANNEL_METHODS: [&str; 2] = ["send", "recv"];
 
 /// Stores the reference to AST node and associated data for instrumentation.
 #[derive(Debug)]
@@ -31,22 +38,45 @@ impl<'p> InstPoint<'p> {
     }
 
     /// Calls the appropriate insert function.
-    fn insert_inst(self) {
+    fn insert_inst(self, sites: &mut SiteTable, embedded_config: &str) {
         match self.point {
             InstKind::ExternCrateItem(ast_ref) => unsafe {
-                insertfuncs::insert_extern_crate_item(cast_point_2_mut(ast_ref).unwrap(), self)
+                insertfuncs::insert_extern_crate_item(
+                    cast_point_2_mut(ast_ref).unwrap(),
+                    self,
+                    sites,
+                )
             },
             InstKind::GlobalScope(ast_ref) => unsafe {
-                insertfuncs::insert_global_scope(cast_point_2_mut(ast_ref).unwrap(), self)
+                insertfuncs::insert_global_scope(
+                    cast_point_2_mut(ast_ref).unwrap(),
+                    self,
+                    sites,
+                    embedded_config,
+                )
             },
             InstKind::LocalScope(ast_ref) => unsafe {
-                insertfuncs::insert_local_scope(cast_point_2_mut(ast_ref).unwrap(), self)
+                insertfuncs::insert_local_scope(cast_point_2_mut(ast_ref).unwrap(), self, sites)
             },
             InstKind::InstCallForFunction(ast_ref) => unsafe {
-                insertfuncs::insert_inst_call_function(cast_point_2_mut(ast_ref).unwrap(), self)
+                insertfuncs::insert_inst_call_function(
+                    cast_point_2_mut(ast_ref).unwrap(),
+                    self,
+                    sites,
+                )
             },
             InstKind::InstCallForMethod(ast_ref) => unsafe {
-                insertfuncs::insert_inst_call_method(cast_point_2_mut(ast_ref).unwrap(), self)
+                insertfuncs::insert_inst_call_method(
+                    cast_point_2_mut(ast_ref).unwrap(),
+                    self,
+                    sites,
+                )
+            },
+            InstKind::ChannelConstructor(ast_ref, wrapper) => unsafe {
+                insertfuncs::insert_channel_constructor(cast_point_2_mut(ast_ref).unwrap(), wrapper)
+            },
+            InstKind::ChannelType(ast_ref, wrapper) => unsafe {
+                insertfuncs::insert_channel_type(cast_point_2_mut(ast_ref).unwrap(), wrapper)
             },
         }
     }
@@ -101,6 +131,10 @@ pub enum InstKind<'p> {
     GlobalScope(&'p ast::Item),
     LocalScope(&'p ast::Expr),
     ExternCrateItem(&'p ast::Mod),
+    /// Call of a channel constructor and the wrapping function of the instrument crate
+    ChannelConstructor(&'p ast::Expr, &'static str),
+    /// Channel handle type and the wrapping type of the instrument crate
+    ChannelType(&'p ast::Ty, &'static str),
 }
 
 impl<'p> InstKind<'p> {
@@ -112,6 +146,8 @@ impl<'p> InstKind<'p> {
             InstKind::GlobalScope(_) => String::from("GlobalScope"),
             InstKind::LocalScope(_) => String::from("LocalScope"),
             InstKind::ExternCrateItem(_) => String::from("ExternCrateItem"),
+            InstKind::ChannelConstructor(..) => String::from("ChannelConstructor"),
+            InstKind::ChannelType(..) => String::from("ChannelType"),
         }
     }
 }
@@ -128,40 +164,59 @@ struct PositionInfo {
 /// Inserts the instrumentation call at the specified positions.
 pub struct InstFinder<'p> {
     resolv_paths: HashMap<String, String>,
-    code_2_monitor: Vec<(String, String)>,
-    code_2_monitor_names: HashSet<String>,
+    code_2_monitor: Vec<(Pattern, String)>,
+    special_behaviour: HashMap<String, Vec<Behaviour>>,
+    /// Text of the config file, embedded into the crate with main
+    embedded_config: String,
     inst_points: BTreeSet<InstPoint<'p>>,
     source_map: &'p source_map::SourceMap,
     ast_node_stack: VecDeque<PositionInfo>,
+    krate: Option<&'p ast::Crate>,
+    /// Whether channel constructors and handle types are wrapped
+    wrap_channels: bool,
 }
 
 impl<'p> InstFinder<'p> {
     /// Constructor for the InstFinder struct.
     pub fn new(
         r_paths: HashMap<String, String>,
-        c_2_m: Vec<(String, String)>,
+        c_2_m: Vec<(Pattern, String)>,
+        s_behaviour: HashMap<String, Vec<Behaviour>>,
+        e_config: String,
         s_map: &'p source_map::SourceMap,
     ) -> InstFinder<'p> {
-        let only_names: HashSet<String> = c_2_m.iter().map(|x| x.0.clone()).collect();
-        InstFinder {
+        let mut inst_finder = InstFinder {
             resolv_paths: r_paths,
             code_2_monitor: c_2_m,
-            code_2_monitor_names: only_names,
+            special_behaviour: s_behaviour,
+            embedded_config: e_config,
             inst_points: BTreeSet::new(),
             source_map: s_map,
             ast_node_stack: VecDeque::new(),
-        }
+            krate: None,
+            wrap_channels: false,
+        };
+        inst_finder.wrap_channels = inst_finder.monitors_channels();
+        inst_finder
     }
 
     /// Collects the InstPoints by walking the AST.
     pub fn find_inst_points(&mut self, krate: &'p ast::Crate) {
+        self.krate = Some(krate);
         visit::walk_crate(self, krate)
     }
 
-    /// Inserts instrumentation for all collected InstPoints.
+    /// Inserts instrumentation for all collected InstPoints
+    /// and the table of their sites into the crate root.
     pub fn insert_instrumentations(self) {
+        let mut sites = SiteTable::new();
         for inst_point in self.inst_points.into_iter().rev() {
-            inst_point.insert_inst();
+            inst_point.insert_inst(&mut sites, &self.embedded_config);
+        }
+        if let Some(krate) = self.krate {
+            unsafe {
+                insertfuncs::insert_site_table(&mut cast_point_2_mut(krate).unwrap().module, sites)
+            }
         }
     }
 
@@ -173,6 +228,12 @@ impl<'p> InstFinder<'p> {
     /// Adds InstPoint to list if specified in config file
     fn add_point_if_needed(&mut self, path: String, point: InstKind<'p>, pos_info: PositionInfo) {
         if let Some((absolute_path, point_kinds)) = self.needs_inst(path, point.get_string_rep()) {
+            // Disabled probes are left out, scopes are still needed for thread-local init
+            if self.is_disabled(&absolute_path)
+                && !point_kinds.iter().any(|kind| kind.ends_with("Scope"))
+            {
+                return;
+            }
             // compute ast_depth here
             let ast_depth: u128 = self.ast_node_stack.len() as u128;
 
@@ -248,18 +309,84 @@ impl<'p> InstFinder<'p> {
         }
     }
 
+    /// Whether send or recv calls are instrumented. Only then channels are wrapped,
+    /// so other projects keep the original handle types
+    fn monitors_channels(&self) -> bool {
+        CHANNEL_METHODS.iter().any(|method| {
+            !self.is_disabled(method)
+                && self
+                    .get_from_code_2_moditor(method)
+                    .iter()
+                    .any(|kind| kind == "InstCallForMethod")
+        })
+    }
+
+    /// Resolves a path and returns the wrapper of the instrument crate if it names a channel
+    /// constructor or handle type. Generic arguments are left out
+    fn channel_wrapper(&self, path: &ast::Path) -> Option<(String, &'static str)> {
+        if !self.wrap_channels {
+            return None;
+        }
+        let path = path
+            .segments
+            .iter()
+            .map(|segment| segment.ident.to_string())
+            .collect::<Vec<_>>()
+            .join("::");
+        let absolute_path = self.determine_abs_path(path, String::from("ChannelWrapper"));
+        channel_wrapper(&absolute_path).map(|wrapper| (absolute_path, wrapper))
+    }
+
+    /// Adds InstPoint wrapping a channel constructor or handle type. Constructors and types are
+    /// wrapped regardless of their own code_2_monitor entries, all handles of a channel have to
+    /// agree on the type
+    fn add_channel_point(
+        &mut self,
+        absolute_path: String,
+        point: InstKind<'p>,
+        pos_info: PositionInfo,
+    ) {
+        let ast_depth: u128 = self.ast_node_stack.len() as u128;
+        self.inst_points.insert(InstPoint::new(
+            point,
+            absolute_path.clone(),
+            (
+                ast_depth,
+                pos_info.begin_line_col.0,
+                pos_info.begin_line_col.1,
+            ),
+            StaticData::new(
+                absolute_path.as_str(),
+                "",
+                ast_depth,
+                pos_info.filename.as_str(),
+                pos_info.begin_line_col.0,
+                pos_info.end_line_col.0,
+            ),
+        ));
+    }
+
     /// Checks if an expression needs instrumentation.
     fn needs_inst(&mut self, path: String, str_inst_kind: String) -> Option<(String, Vec<String>)> {
         let absolute_path = self.determine_abs_path(path, str_inst_kind);
+        let point_kinds = self.get_from_code_2_moditor(&absolute_path);
 
-        if self.code_2_monitor_names.contains(&absolute_path) {
-            let point_kinds = self.get_from_code_2_moditor(&absolute_path);
-            Some((absolute_path, point_kinds))
-        } else {
+        if point_kinds.is_empty() {
             None
+        } else {
+            Some((absolute_path, point_kinds))
         }
     }
 
+    /// Checks if the special behaviour rules disable a path entirely.
+    fn is_disabled(&self, absolute_path: &str) -> bool {
+        self.special_behaviour
+            .get(absolute_path)
+            .map_or(false, |behaviours| {
+                behaviours.contains(&Behaviour::Disabled)
+            })
+    }
+
     /// Resolves the absolute path of a name.
     fn determine_abs_path(&self, path: String, str_inst_kind: String) -> String {
         if str_inst_kind == "InstCallForMethod" {
@@ -332,18 +459,21 @@ impl<'p> InstFinder<'p> {
         self.ast_node_stack.push_back(pos_info);
     }
 
-    /// Filters code_2_monitor list
+    /// Filters code_2_monitor list. Kinds of matching exclusion patterns are left out
     fn get_from_code_2_moditor(&self, absolute_path: &str) -> Vec<String> {
-        self.code_2_monitor
+        let (exclusions, inclusions): (Vec<_>, Vec<_>) = self
+            .code_2_monitor
             .iter()
-            .filter_map(|x| {
-                if x.0 == absolute_path {
-                    Some(x.1.clone())
-                } else {
-                    None
-                }
-            })
-            .collect()
+            .filter(|x| x.0.matches(absolute_path))
+            .partition(|x| x.0.is_exclusion());
+        let mut point_kinds: Vec<String> = Vec::new();
+        for (_, kind) in inclusions {
+            let excluded = exclusions.iter().any(|x| &x.1 == kind);
+            if !excluded && !point_kinds.contains(kind) {
+                point_kinds.push(kind.clone());
+            }
+        }
+        point_kinds
     }
 }
 
@@ -390,6 +520,13 @@ impl<'p> visit::Visitor<'p> for InstFinder<'p> {
             // Function call (func_call(...) or path::func_call(...) or etc.)
             ast::ExprKind::Call(expr_path, _args) => {
                 if let ast::ExprKind::Path(_qualified, path) = &expr_path.node {
+                    if let Some((absolute_path, wrapper)) = self.channel_wrapper(path) {
+                        self.add_channel_point(
+                            absolute_path,
+                            InstKind::ChannelConstructor(expr, wrapper),
+                            pos_info.clone(),
+                        );
+                    }
                     self.add_point_if_needed(
                         path.to_string(),
                         InstKind::InstCallForFunction(expr),
@@ -436,6 +573,17 @@ impl<'p> visit::Visitor<'p> for InstFinder<'p> {
         visit::walk_impl_item(self, ii)
     }
 
+    fn visit_ty(&mut self, t: &'p ast::Ty) {
+        let pos_info = self.get_file_lines(String::from("type"), t.span);
+        self.set_ast_stack(pos_info.clone());
+        if let ast::TyKind::Path(None, path) = &t.node {
+            if let Some((absolute_path, wrapper)) = self.channel_wrapper(path) {
+                self.add_channel_point(absolute_path, InstKind::ChannelType(t, wrapper), pos_info);
+            }
+        }
+        visit::walk_ty(self, t)
+    }
+
     fn visit_local(&mut self, l: &'p ast::Local) {
         let pos_info = self.get_file_lines(String::from("local"), l.span);
         self.set_ast_stack(pos_info);
@@ -454,6 +602,21 @@ impl<'p> visit::Visitor<'p> for InstFinder<'p> {
     }
 }
 
+/// Wrapper of the instrument crate for a channel constructor or handle type
+fn channel_wrapper(absolute_path: &str) -> Option<&'static str> {
+    let split = absolute_path.rfind("::")?;
+    if !CHANNEL_MODULES.contains(&&absolute_path[..split]) {
+        return None;
+    }
+    match &absolute_path[split + 2..] {
+        "channel" | "sync_channel" | "bounded" | "unbounded" => Some("channel"),
+        "after" | "tick" | "never" => Some("receiver"),
+        "Sender" | "SyncSender" => Some("Sender"),
+        "Receiver" => Some("Receiver"),
+        _ => None,
+    }
+}
+
 /// Converts a immutable reference to a mutable reference
 unsafe fn cast_point_2_mut<A>(item_ref: &A) -> Option<&mut A> {
     ((item_ref as *const A) as *mut A).as_mut()
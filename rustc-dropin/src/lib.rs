@@ -11,12 +11,12 @@ extern crate rustc_metadata;
 extern crate rustc_plugin;
 extern crate syntax;
 
-use instrument::read_conf_file;
+use instrument::{deserialize_config, locate_conf_file, read_conf_text};
 mod insertfuncs;
 mod instfinder;
 use instfinder::InstFinder;
 mod pathresolver;
-use dirs;
+mod pattern;
 use pathresolver::PathResolver;
 use rustc::session::config::{self, ErrorOutputType, Input};
 use rustc::session::Session;
@@ -27,8 +27,6 @@ use rustc_metadata::cstore::CStore;
 use std::path::PathBuf;
 use syntax::{ast, errors};
 
-const CONFIG_FILE: &str = ".rust_inst/instconfig.toml";
-
 /// Struct implements CompilerCalls Trait
 pub struct Instrumentator {
     default_calls: RustcDefaultCalls,
@@ -119,12 +117,11 @@ impl<'a> CompilerCalls<'a> for Instrumentator {
             if state.krate.is_some() {
 
                 // Read config file
-                let config = if let Some(mut config_path) = dirs::home_dir() {
-                    config_path.push(CONFIG_FILE);
-                    read_conf_file(config_path)
-                } else {
-                    panic!("Unable to locate home directory!")
-                };
+                let config_text = locate_conf_file()
+                    .and_then(read_conf_text)
+                    .unwrap_or_else(|err| panic!("{}", err));
+                let config =
+                    deserialize_config(&config_text).unwrap_or_else(|err| panic!("{}", err));
 
                 // Prepare name resolution
                 let resolv_paths = {
@@ -135,7 +132,10 @@ impl<'a> CompilerCalls<'a> for Instrumentator {
                 // Insert instrumentation calls at relevant positions
                 let mut inst_finder = InstFinder::new(
                     resolv_paths,
-                    config.code_2_monitor,
+                    pattern::parse_code_2_monitor(config.code_2_monitor)
+                        .unwrap_or_else(|err| panic!("{}", err)),
+                    config.behaviours,
+                    config_text,
                     &state.session.source_map(),
                 );
                 // Construct list of InstPoints
@@ -0,0 +1,127 @@
+use regex::Regex;
+
+/// Prefix of patterns which are regular expressions
+const REGEX_PREFIX: &str = "re:";
+/// Prefix of patterns which exclude paths
+const EXCLUSION_PREFIX: &str = "!";
+
+/// Absolute path pattern of a code_2_monitor entry.
+/// - `name::path` matches the path exactly
+/// - `*` matches any part of a single segment, `**::` any number of segments:
+///   `crossbeam_channel::*`, `rayon::iter::**::par_*`
+/// - `re:<regex>` matches the paths the regular expression matches entirely
+///
+/// A leading `!` turns the pattern into an exclusion, paths it matches do not receive
+/// the instrumentation kind of the entry
+#[derive(Debug)]
+pub struct Pattern {
+    matcher: Matcher,
+    exclusion: bool,
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Exact(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// Parses a pattern, returns an error for invalid regular expressions
+    pub fn parse(pattern: &str) -> Result<Pattern, String> {
+        let (exclusion, pattern) = if pattern.starts_with(EXCLUSION_PREFIX) {
+            (true, &pattern[EXCLUSION_PREFIX.len()..])
+        } else {
+            (false, pattern)
+        };
+        let matcher = if pattern.starts_with(REGEX_PREFIX) {
+            Matcher::Regex(anchored(&pattern[REGEX_PREFIX.len()..])?)
+        } else if pattern.contains('*') {
+            Matcher::Regex(anchored(&glob_to_regex(pattern))?)
+        } else {
+            Matcher::Exact(String::from(pattern))
+        };
+        Ok(Pattern { matcher, exclusion })
+    }
+
+    /// Whether the pattern matches the absolute path
+    pub fn matches(&self, absolute_path: &str) -> bool {
+        match &self.matcher {
+            Matcher::Exact(path) => path == absolute_path,
+            Matcher::Regex(regex) => regex.is_match(absolute_path),
+        }
+    }
+
+    /// Whether the pattern excludes the paths it matches
+    pub fn is_exclusion(&self) -> bool {
+        self.exclusion
+    }
+}
+
+/// Parses the patterns of the code_2_monitor list
+pub fn parse_code_2_monitor(
+    code_2_monitor: Vec<(String, String)>,
+) -> Result<Vec<(Pattern, String)>, String> {
+    code_2_monitor
+        .into_iter()
+        .map(|(pattern, kind)| {
+            Pattern::parse(&pattern)
+                .map(|parsed| (parsed, kind))
+                .map_err(|err| format!("invalid pattern '{}' in code_2_monitor: {}", pattern, err))
+        })
+        .collect()
+}
+
+/// Compiles the regular expression so it has to match the entire path
+fn anchored(regex: &str) -> Result<Regex, String> {
+    Regex::new(&format!("^(?:{})$", regex)).map_err(|err| err.to_string())
+}
+
+/// Translates a glob to a regular expression, the rest of the glob is matched literally
+fn glob_to_regex(glob: &str) -> String {
+    glob.split("**::")
+        .map(|segments| {
+            segments
+                .split("**")
+                .map(|segment| {
+                    segment
+                        .split('*')
+                        .map(regex::escape)
+                        .collect::<Vec<_>>()
+                        .join("[^:]*")
+                })
+                .collect::<Vec<_>>()
+                .join(".*")
+        })
+        .collect::<Vec<_>>()
+        .join("(?:.*::)?")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_globs_and_regexes() {
+        let pattern = Pattern::parse("crossbeam_channel::*").unwrap();
+        assert!(pattern.matches("crossbeam_channel::bounded"));
+        assert!(!pattern.matches("crossbeam_channel::Sender::send"));
+        assert!(!pattern.matches("crossbeam_channel"));
+
+        let pattern = Pattern::parse("rayon::iter::**::par_*").unwrap();
+        assert!(pattern.matches("rayon::iter::plumbing::par_bridge"));
+        assert!(pattern.matches("rayon::iter::a::b::par_iter"));
+        assert!(pattern.matches("rayon::iter::par_bridge"));
+        assert!(!pattern.matches("rayon::iter::par_iter::len"));
+
+        let pattern = Pattern::parse("re:(send|recv|receive)").unwrap();
+        assert!(pattern.matches("recv"));
+        assert!(!pattern.matches("try_recv"));
+
+        let pattern = Pattern::parse("!rayon::join").unwrap();
+        assert!(pattern.is_exclusion());
+        assert!(pattern.matches("rayon::join"));
+        assert!(!pattern.matches("rayon::join_context"));
+
+        assert!(Pattern::parse("re:(").is_err());
+    }
+}